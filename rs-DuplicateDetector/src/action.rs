@@ -0,0 +1,465 @@
+//! Items to resolve duplicates, by replacing all but one copy of a file.
+//!
+//! Every file is hashed again right before it is touched,
+//! so files modified since they were indexed are left alone.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::path::absolute;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use strum::Display;
+
+use crate::archive::is_member;
+use crate::core::ansi::Bold;
+use crate::core::fs::replace_file;
+use crate::core::fs::symlink_file;
+use crate::core::units::Bytes;
+use crate::fingerprint::Fingerprint;
+use crate::hash::FileHash;
+use crate::hash::FileHasher;
+use crate::report::DuplicateGroup;
+
+/////////////
+// Options //
+/////////////
+
+#[derive(Debug, Clone, Copy, ValueEnum, Display)]
+#[clap(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+/// What to do with duplicates.
+pub enum ActionKind {
+    /// Delete the duplicates.
+    Delete,
+    /// Replace the duplicates with hard links to the kept file.
+    HardLink,
+    /// Replace the duplicates with symbolic links to the kept file.
+    Symlink,
+    /// Replace the duplicates with copy-on-write clones of the kept file.
+    /// Only supported by some file systems, such as Btrfs, XFS and APFS.
+    Reflink,
+}
+
+#[derive(Debug, Default, Clone, Copy, ValueEnum, Display)]
+#[clap(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+/// Which file of a set of duplicates to keep.
+pub enum KeepPolicy {
+    #[default]
+    /// Keep the file modified the longest ago.
+    Oldest,
+    /// Keep the file modified most recently.
+    Newest,
+    /// Keep the file with the shortest path.
+    ShortestPath,
+    /// Keep the oldest file inside a preferred directory,
+    /// or the oldest file if none are inside one.
+    Preferred,
+}
+
+#[derive(Debug, Clone)]
+/// Options for resolving duplicates.
+pub struct ActionOptions {
+    /// What to do with the duplicates.
+    pub kind: ActionKind,
+    /// Which file to keep.
+    pub keep: KeepPolicy,
+    /// The directories used by [`KeepPolicy::Preferred`].
+    pub preferred: Vec<PathBuf>,
+    /// Only print what would be done.
+    pub dry_run: bool,
+    /// File to append a record of every change to.
+    pub log: Option<PathBuf>,
+}
+
+/////////////////
+// Keep policy //
+/////////////////
+
+#[derive(Debug, Clone, Copy)]
+/// A file in a set of duplicates.
+pub struct Member<'a> {
+    /// The path to the file.
+    pub path: &'a Path,
+    /// The fingerprint of the file.
+    pub fingerprint: Fingerprint,
+}
+
+/// Sort key to prefer older files.
+fn by_age<'a>((_, member): &(usize, &Member<'a>)) -> (SystemTime, &'a Path) {
+    (member.fingerprint.modified, member.path)
+}
+
+impl KeepPolicy {
+    /// Returns the index of the file to keep.
+    /// Ties are broken by path, so the choice is deterministic.
+    ///
+    /// # Panics
+    /// If there are no members.
+    pub fn choose(self, members: &[Member], preferred: &[PathBuf]) -> usize {
+        let candidates = members.iter().enumerate();
+        let chosen = match self {
+            Self::Oldest => candidates.min_by_key(by_age),
+            Self::Newest => candidates
+                .min_by_key(|(_, m)| (Reverse(m.fingerprint.modified), m.path)),
+            Self::ShortestPath => candidates
+                .min_by_key(|(_, m)| (m.path.as_os_str().len(), m.path)),
+            Self::Preferred => {
+                let is_preferred = |path: &Path| {
+                    let path = absolute(path).unwrap_or(path.to_path_buf());
+                    preferred.iter().any(|dir| {
+                        absolute(dir).is_ok_and(|dir| path.starts_with(dir))
+                    })
+                };
+                candidates
+                    .clone()
+                    .filter(|(_, m)| is_preferred(m.path))
+                    .min_by_key(by_age)
+                    .or_else(|| candidates.min_by_key(by_age))
+            },
+        };
+        chosen.expect("set of duplicates was empty").0
+    }
+}
+
+/////////////
+// Actions //
+/////////////
+
+impl ActionKind {
+    /// Replaces the target with (a link to) the file to keep.
+    fn apply(self, keep: &Path, target: &Path) -> io::Result<()> {
+        match self {
+            Self::Delete => fs::remove_file(target),
+            Self::HardLink => {
+                replace_file(target, |temp| fs::hard_link(keep, temp))
+            },
+            Self::Symlink => {
+                // Relative links would be relative to the link's directory
+                let keep = fs::canonicalize(keep)?;
+                replace_file(target, |temp| symlink_file(&keep, temp))
+            },
+            Self::Reflink => {
+                replace_file(target, |temp| reflink_copy::reflink(keep, temp))
+            },
+        }
+    }
+}
+
+/// Whether the file at the path still has the expected hash.
+fn verify(hasher: &mut FileHasher, path: &Path, expected: &FileHash) -> bool {
+    hasher.from_contents(path).is_ok_and(|actual| actual == *expected)
+}
+
+/// Applies an action to the target,
+/// after verifying both files still have the expected hash.
+pub fn apply_verified(
+    kind: ActionKind,
+    keep: &Path,
+    target: &Path,
+    hash: &FileHash,
+) -> crate::Result {
+    if let Some(path) = [keep, target].into_iter().find(|p| is_member(p)) {
+        anyhow::bail!("'{}' is inside an archive", path.display());
+    }
+    let mut hasher = FileHasher::new(hash.algorithm());
+    for path in [keep, target] {
+        if !verify(&mut hasher, path, hash) {
+            anyhow::bail!("'{}' changed since it was hashed", path.display());
+        }
+    }
+    kind.apply(keep, target)
+        .with_context(|| format!("failed to {} '{}'", kind, target.display()))
+}
+
+/// Appends records of changes to a file.
+struct ActionLog {
+    file: Option<fs::File>,
+}
+
+impl ActionLog {
+    fn open(path: Option<&Path>) -> crate::Result<Self> {
+        let file = match path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| {
+                        format!("failed to open '{}'", path.display())
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(ActionLog { file })
+    }
+
+    fn record(
+        &mut self,
+        kind: ActionKind,
+        keep: &Path,
+        target: &Path,
+        hash: &FileHash,
+    ) -> io::Result<()> {
+        let Some(file) = &mut self.file else { return Ok(()) };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}",
+            time,
+            kind,
+            target.display(),
+            keep.display(),
+            hash,
+        )
+    }
+}
+
+/// Resolves all duplicates using the given options.
+///
+/// Files without a known fingerprint are left alone.
+pub fn resolve_duplicates(
+    duplicates: &[DuplicateGroup],
+    fingerprints: &HashMap<PathBuf, Fingerprint>,
+    ActionOptions { kind, keep, preferred, dry_run, log }: &ActionOptions,
+    mut out: impl Write,
+) -> crate::Result {
+    let mut log = match dry_run {
+        true => ActionLog::open(None)?,
+        false => ActionLog::open(log.as_deref())?,
+    };
+    let prefix = match dry_run {
+        true => "would ",
+        false => "",
+    };
+
+    let mut changed_count = 0;
+    let mut skipped_count = 0;
+    let mut freed_bytes = 0;
+    for DuplicateGroup { hash, paths, .. } in duplicates {
+        let mut hasher = FileHasher::new(hash.algorithm());
+        // Files inside archives cannot be changed, nor linked to
+        let members: Vec<Member> = paths
+            .iter()
+            .filter(|path| !is_member(path))
+            .filter_map(|path| {
+                let fingerprint = *fingerprints.get(path)?;
+                Some(Member { path, fingerprint })
+            })
+            .collect();
+        if members.len() < 2 {
+            continue;
+        }
+        let keep_index = keep.choose(&members, preferred);
+        let kept = members[keep_index];
+
+        if !verify(&mut hasher, kept.path, hash) {
+            eprintln!(
+                "skipping {} file(s): '{}' changed since it was hashed",
+                members.len() - 1,
+                kept.path.display(),
+            );
+            skipped_count += members.len() - 1;
+            continue;
+        }
+
+        for (index, member) in members.iter().enumerate() {
+            if index == keep_index {
+                continue;
+            }
+            let target = member.path;
+            if !verify(&mut hasher, target, hash) {
+                eprintln!(
+                    "skipping '{}': changed since it was hashed",
+                    target.display(),
+                );
+                skipped_count += 1;
+                continue;
+            }
+            if !dry_run {
+                if let Err(error) = kind.apply(kept.path, target) {
+                    eprintln!(
+                        "failed to {} '{}': {}",
+                        kind,
+                        target.display(),
+                        error,
+                    );
+                    skipped_count += 1;
+                    continue;
+                }
+                log.record(*kind, kept.path, target, hash)?;
+            }
+            writeln!(
+                out,
+                "{}{} '{}' (keeping '{}')",
+                prefix,
+                kind,
+                target.display(),
+                kept.path.display(),
+            )?;
+            changed_count += 1;
+            freed_bytes += member.fingerprint.size;
+        }
+    }
+
+    let summary = format!(
+        "{}{} {} file(s), freeing {}",
+        prefix,
+        kind,
+        changed_count,
+        Bytes(freed_bytes),
+    );
+    writeln!(out, "{}", Bold(&summary))?;
+    if skipped_count > 0 {
+        writeln!(out, "skipped {} file(s)", skipped_count)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::hash::HashAlgorithm;
+
+    #[test]
+    fn policies_break_ties_by_path() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let member = |path, secs| Member {
+            path: Path::new(path),
+            fingerprint: Fingerprint { size: 1, modified: at(secs), id: None },
+        };
+        let members = [
+            member("/b/new", 3),
+            member("/a/old/z", 1),
+            member("/a/old/y", 1),
+            member("/b/newer", 3),
+        ];
+        let chosen = |policy: KeepPolicy, preferred: &[&str]| {
+            let preferred: Vec<PathBuf> =
+                preferred.iter().map(PathBuf::from).collect();
+            members[policy.choose(&members, &preferred)].path
+        };
+        assert_eq!(chosen(KeepPolicy::Oldest, &[]), Path::new("/a/old/y"));
+        assert_eq!(chosen(KeepPolicy::Newest, &[]), Path::new("/b/new"));
+        assert_eq!(chosen(KeepPolicy::ShortestPath, &[]), Path::new("/b/new"));
+        assert_eq!(chosen(KeepPolicy::Preferred, &["/b"]), Path::new("/b/new"));
+        // Without a file in a preferred directory, the oldest is kept
+        assert_eq!(
+            chosen(KeepPolicy::Preferred, &["/c"]),
+            Path::new("/a/old/y")
+        );
+    }
+
+    /// Creates files with the given contents in a new directory,
+    /// returning the directory and the paths, oldest first.
+    fn files(name: &str, contents: &[&str]) -> (PathBuf, Vec<PathBuf>) {
+        let dir = std::env::temp_dir().join(format!(
+            "duplicate-detector-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<PathBuf> = (0..contents.len())
+            .map(|index| dir.join(index.to_string()))
+            .collect();
+        for (secs, (path, contents)) in paths.iter().zip(contents).enumerate() {
+            fs::write(path, contents).unwrap();
+            let modified = UNIX_EPOCH + Duration::from_secs(secs as u64 + 1);
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        (dir, paths)
+    }
+
+    /// Groups the paths as duplicates of the given contents.
+    fn group(
+        paths: &[PathBuf],
+        contents: &str,
+    ) -> (DuplicateGroup, HashMap<PathBuf, Fingerprint>) {
+        let mut hasher = FileHasher::new(HashAlgorithm::Xxh3);
+        let hash = hasher.from_reader(&mut contents.as_bytes()).unwrap();
+        let group = DuplicateGroup {
+            hash,
+            size: contents.len() as u64,
+            paths: paths.to_vec(),
+            covered: false,
+        };
+        let fingerprints = paths
+            .iter()
+            .map(|path| (path.clone(), Fingerprint::from_path(path).unwrap()))
+            .collect();
+        (group, fingerprints)
+    }
+
+    fn options(dry_run: bool) -> ActionOptions {
+        ActionOptions {
+            kind: ActionKind::Delete,
+            keep: KeepPolicy::Oldest,
+            preferred: vec![],
+            dry_run,
+            log: None,
+        }
+    }
+
+    #[test]
+    fn skips_files_that_changed() {
+        let (dir, paths) = files("changed", &["same", "same", "same"]);
+        let (group, mut fingerprints) = group(&paths, "same");
+        fs::write(&paths[2], "diff").unwrap();
+        // Files without a fingerprint are left alone, too
+        let unknown = dir.join("unknown");
+        fs::write(&unknown, "same").unwrap();
+        let group = DuplicateGroup {
+            paths: [paths.clone(), vec![unknown.clone()]].concat(),
+            ..group
+        };
+        fingerprints.remove(&unknown);
+
+        let mut out = Vec::new();
+        resolve_duplicates(&[group], &fingerprints, &options(false), &mut out)
+            .unwrap();
+        let exists = [&paths[0], &paths[1], &paths[2], &unknown]
+            .map(|path| path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(exists, [true, false, true, true]);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("skipped 1 file(s)"), "{}", out);
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let (dir, paths) = files("dry-run", &["same", "same"]);
+        let (group, fingerprints) = group(&paths, "same");
+        let log = dir.join("log");
+        let options = ActionOptions { log: Some(log.clone()), ..options(true) };
+
+        let mut out = Vec::new();
+        resolve_duplicates(&[group], &fingerprints, &options, &mut out)
+            .unwrap();
+        let exists = [&paths[0], &paths[1], &log].map(|path| path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(exists, [true, true, false]);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("would delete"), "{}", out);
+    }
+}
@@ -0,0 +1,447 @@
+//! Items to look inside zip and tar archives.
+//!
+//! Files inside an archive (members) get virtual paths like
+//! `backup.zip!/dir/file`, so they are indexed and reported like any other.
+//! Members are read in a streaming fashion, as archives are rarely seekable.
+//! They cannot be changed in place, so actions leave them alone.
+//!
+//! Listing the members of a compressed archive means decompressing all of it,
+//! so listings are cached, by the fingerprint of the archive.
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::ops::ControlFlow;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::Context;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use serde::Serialize;
+use zip::ZipArchive;
+
+use crate::fingerprint::Fingerprint;
+use crate::hash::FileHash;
+use crate::hash::FileHasher;
+use crate::hash::RateLimit;
+use crate::hash_concurrent::HashFilesOptions;
+use crate::hash_concurrent::WorkQueue;
+use crate::progress::ProgressSink;
+use crate::progress::Stage;
+use crate::progress::Tracker;
+use crate::progress::track;
+use crate::skip::Skipped;
+
+/// Separates the path of an archive from the name of a member.
+pub const SEPARATOR: &str = "!/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The supported archive formats.
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// File name suffixes of the supported archive formats.
+const SUFFIXES: &[(&str, ArchiveKind)] = &[
+    (".zip", ArchiveKind::Zip),
+    (".tar", ArchiveKind::Tar),
+    (".tar.gz", ArchiveKind::TarGz),
+    (".tgz", ArchiveKind::TarGz),
+];
+
+impl ArchiveKind {
+    fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        let mut kinds = SUFFIXES.iter();
+        kinds.find(|(suffix, _)| name.ends_with(suffix)).map(|(_, kind)| *kind)
+    }
+}
+
+/// Whether the file at the path looks like an archive, judging by its name.
+pub fn is_archive(path: &Path) -> bool {
+    ArchiveKind::from_path(path).is_some()
+}
+
+///////////
+// Paths //
+///////////
+
+/// Returns the virtual path of a member of an archive.
+pub fn member_path(archive: &Path, name: &str) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(SEPARATOR);
+    path.push(name);
+    path.into()
+}
+
+/// Splits a virtual path into the path of the archive and the member name.
+/// Returns [`None`] for the paths of regular files.
+pub fn split_member(path: &Path) -> Option<(&Path, &str)> {
+    let path = path.to_str()?;
+    path.match_indices(SEPARATOR)
+        .map(|(index, _)| {
+            let archive = Path::new(&path[..index]);
+            (archive, &path[index + SEPARATOR.len()..])
+        })
+        .find(|(archive, _)| is_archive(archive))
+}
+
+/// Whether the path is the virtual path of a member of an archive.
+pub fn is_member(path: &Path) -> bool { split_member(path).is_some() }
+
+/// Whether the file, or the archive containing it, lies at or under the path.
+pub fn lies_in(file: &Path, path: &Path) -> bool {
+    let file = split_member(file).map_or(file, |(archive, _)| archive);
+    file.starts_with(path)
+}
+
+/// Like [`fs::canonicalize`], but also accepts virtual paths.
+pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    match split_member(path) {
+        Some((archive, name)) => {
+            Ok(member_path(&fs::canonicalize(archive)?, name))
+        },
+        None => fs::canonicalize(path),
+    }
+}
+
+/// Normalizes the name of a member, e.g. `./dir/file` to `dir/file`.
+/// Returns [`None`] for names that point outside the archive,
+/// or that are not valid UTF-8.
+fn normalize(name: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {},
+            _ => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/////////////
+// Reading //
+/////////////
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A file inside an archive.
+pub struct Member {
+    /// The normalized name of the file, relative to the archive.
+    pub name: String,
+    /// The uncompressed size of the file in bytes.
+    pub size: u64,
+}
+
+impl Member {
+    /// The fingerprint of this member, given the fingerprint of the archive.
+    /// Whenever the archive changes, the member is considered changed too.
+    pub fn fingerprint(&self, archive: &Fingerprint) -> Fingerprint {
+        Fingerprint { size: self.size, ..*archive }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// The files inside an archive, when it had the given fingerprint.
+pub struct Listing {
+    /// The fingerprint of the archive at the moment it was listed.
+    pub fingerprint: Fingerprint,
+    /// The files inside.
+    pub members: Vec<Member>,
+}
+
+type Visit<'a> =
+    dyn FnMut(Member, &mut dyn Read) -> crate::Result<ControlFlow<()>> + 'a;
+
+/// Visits the members of an archive in the order they are stored,
+/// together with a reader of their contents.
+///
+/// Members that cannot be read (e.g. encrypted ones) are left out.
+/// If the archive is damaged, the members before the damage are visited,
+/// and then the error is returned.
+fn for_each_member(archive: &Path, visit: &mut Visit) -> crate::Result {
+    let kind = ArchiveKind::from_path(archive).context("not an archive")?;
+    let file = BufReader::new(File::open(archive)?);
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = ZipArchive::new(file)?;
+            for index in 0..zip.len() {
+                let Ok(mut entry) = zip.by_index(index) else { continue };
+                let name = normalize(Path::new(entry.name()));
+                let Some(name) = name.filter(|_| entry.is_file()) else {
+                    continue;
+                };
+                let member = Member { name, size: entry.size() };
+                if visit(member, &mut entry)?.is_break() {
+                    break;
+                }
+            }
+        },
+        ArchiveKind::Tar => for_each_tar_member(file, visit)?,
+        ArchiveKind::TarGz => for_each_tar_member(GzDecoder::new(file), visit)?,
+    }
+    Ok(())
+}
+
+fn for_each_tar_member(reader: impl Read, visit: &mut Visit) -> crate::Result {
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        // NB: Entries are not indexed, so nothing after a damaged one is found
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let Ok(path) = entry.path() else { continue };
+        let Some(name) = normalize(&path) else { continue };
+        let member = Member { name, size: entry.size() };
+        if visit(member, &mut entry)?.is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the files inside an archive.
+///
+/// If the archive is damaged, the files before the damage are listed,
+/// together with the error.
+pub fn list_members(archive: &Path) -> (Vec<Member>, Option<crate::Error>) {
+    let mut members = Vec::new();
+    let result = for_each_member(archive, &mut |member, _| {
+        members.push(member);
+        Ok(ControlFlow::Continue(()))
+    });
+    (members, result.err())
+}
+
+/// Reads the member at the given virtual path,
+/// passing a reader of its contents and its size.
+pub fn read_member<T>(
+    path: &Path,
+    read: impl FnOnce(&mut dyn Read, u64) -> crate::Result<T>,
+) -> crate::Result<T> {
+    let (archive, name) = split_member(path).context("not in an archive")?;
+    let mut read = Some(read);
+    let mut result = None;
+    for_each_member(archive, &mut |member, reader| {
+        if member.name != name {
+            return Ok(ControlFlow::Continue(()));
+        }
+        let read = read.take().expect("stops at the first match");
+        result = Some(read(reader, member.size)?);
+        Ok(ControlFlow::Break(()))
+    })?;
+    result.context("no such file in the archive")
+}
+
+/// Fully hashes the given members of each archive in parallel,
+/// reading each archive only once.
+///
+/// Members are given by name, with their size, to report progress in bytes.
+/// Members that cannot be read are skipped, as are (the rest of) archives.
+pub fn hash_members(
+    members: &HashMap<&Path, HashMap<&str, u64>>,
+    HashFilesOptions { threads, algorithm, buffer_size, rate_limit }:
+        HashFilesOptions,
+    progress: &mut dyn ProgressSink,
+) -> (Vec<(PathBuf, FileHash)>, Vec<Skipped>) {
+    if members.is_empty() {
+        return (vec![], vec![]);
+    }
+    // Largest first, as with files, so no thread is left with a huge archive
+    let queue = WorkQueue::new(
+        members.iter().map(|(archive, names)| (*archive, names)).collect(),
+        |(_, names)| names.values().sum(),
+    );
+    let bytes = members.values().flat_map(|names| names.values()).sum();
+    let worker_count = threads.get().min(members.len());
+    let rate_limit = rate_limit.map(|limit| Arc::new(RateLimit::new(limit)));
+    let results: Vec<(Vec<_>, Vec<_>)> =
+        track(Stage::Archives, members.len(), bytes, progress, |tracker| {
+            thread::scope(|scope| {
+                let workers: Vec<_> = (0..worker_count)
+                    .map(|_| {
+                        let hasher = FileHasher::new(algorithm)
+                            .with_buffer_size(buffer_size)
+                            .with_progress(tracker.bytes_read())
+                            .with_rate_limit(rate_limit.clone());
+                        let queue = &queue;
+                        scope.spawn(move || hash_queued(queue, hasher, tracker))
+                    })
+                    .collect();
+                workers.into_iter().map(|w| w.join().unwrap()).collect()
+            })
+        });
+
+    let mut hashes = Vec::new();
+    let mut skipped = Vec::new();
+    for (found, failed) in results {
+        hashes.extend(found);
+        skipped.extend(failed);
+    }
+    (hashes, skipped)
+}
+
+/// Hashes the given members of archives taken from the queue,
+/// until it is empty.
+fn hash_queued<'a>(
+    queue: &WorkQueue<(&'a Path, &HashMap<&str, u64>)>,
+    mut hasher: FileHasher,
+    tracker: &Tracker<'a>,
+) -> (Vec<(PathBuf, FileHash)>, Vec<Skipped>) {
+    let mut hashes = Vec::new();
+    let mut skipped = Vec::new();
+    while let Some(&(archive, names)) = queue.next() {
+        tracker.start(archive);
+        let mut remaining = names.len();
+        let result = for_each_member(archive, &mut |member, reader| {
+            if !names.contains_key(member.name.as_str()) {
+                return Ok(ControlFlow::Continue(()));
+            }
+            let path = member_path(archive, &member.name);
+            match hasher.from_reader(reader) {
+                Ok(hash) => hashes.push((path, hash)),
+                Err(error) => skipped.push(Skipped::new(path, error)),
+            }
+            remaining -= 1;
+            Ok(match remaining {
+                0 => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            })
+        });
+        if let Err(error) = result {
+            skipped.push(Skipped::new(archive, error));
+        }
+        tracker.finish();
+    }
+    (hashes, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::num::NonZero;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    use super::*;
+    use crate::progress::NoProgress;
+
+    #[test]
+    fn virtual_paths_round_trip() {
+        let path = member_path(Path::new("/a/b!/backup.tar.gz"), "dir/file");
+        assert_eq!(path, Path::new("/a/b!/backup.tar.gz!/dir/file"));
+        assert_eq!(
+            split_member(&path),
+            Some((Path::new("/a/b!/backup.tar.gz"), "dir/file")),
+        );
+        assert!(!is_member(Path::new("/a/b!/backup.tar.gz")));
+        assert!(!is_member(Path::new("/a/notes.txt!/file")));
+
+        assert_eq!(normalize(Path::new("./dir/file")).unwrap(), "dir/file");
+        assert_eq!(normalize(Path::new("../file")), None);
+        assert_eq!(normalize(Path::new("/etc/passwd")), None);
+    }
+
+    /// Writes the files to a tar archive.
+    fn tar(out: impl Write, files: &[(&str, &[u8])]) -> io::Result<()> {
+        let mut builder = tar::Builder::new(out);
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *contents)?;
+        }
+        builder.into_inner()?.flush()
+    }
+
+    #[test]
+    fn archives_round_trip() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-archive", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let large = [7; 10_000];
+        let files: [(&str, &[u8]); 2] = [("x", b"hello"), ("sub/y", &large)];
+
+        let zip = dir.join("a.zip");
+        let mut writer = ZipWriter::new(File::create(&zip).unwrap());
+        for (name, contents) in files {
+            writer.start_file(name, SimpleFileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap();
+        let tar_gz = dir.join("a.tar.gz");
+        let encoder =
+            GzEncoder::new(File::create(&tar_gz).unwrap(), Compression::fast());
+        tar(encoder, &files).unwrap();
+
+        let options = HashFilesOptions {
+            threads: NonZero::new(2).unwrap(),
+            ..HashFilesOptions::for_tests()
+        };
+        let mut hasher = FileHasher::new(options.algorithm);
+        let names: HashMap<&str, u64> = files
+            .iter()
+            .map(|(name, contents)| (*name, contents.len() as u64))
+            .collect();
+        let archives: HashMap<&Path, HashMap<&str, u64>> =
+            [(zip.as_path(), names.clone()), (tar_gz.as_path(), names)].into();
+        let (mut hashes, skipped) =
+            hash_members(&archives, options, &mut NoProgress);
+        hashes.sort();
+        assert!(skipped.is_empty());
+
+        for archive in [&zip, &tar_gz] {
+            let (members, error) = list_members(archive);
+            assert!(error.is_none());
+            let listed: Vec<(&str, u64)> =
+                members.iter().map(|m| (m.name.as_str(), m.size)).collect();
+            assert_eq!(listed, [("x", 5), ("sub/y", 10_000)]);
+
+            for (name, contents) in files {
+                let path = member_path(archive, name);
+                let read = read_member(&path, |reader, _| {
+                    let mut buffer = Vec::new();
+                    reader.read_to_end(&mut buffer)?;
+                    Ok(buffer)
+                });
+                assert_eq!(read.unwrap(), contents);
+                let hash = hasher.from_reader(&mut &contents[..]).unwrap();
+                assert!(hashes.contains(&(path, hash)));
+            }
+        }
+        assert_eq!(hashes.len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_archives_keep_what_comes_before() {
+        let path = std::env::temp_dir().join(format!(
+            "duplicate-detector-{}-damaged.tar",
+            std::process::id()
+        ));
+        let mut bytes = Vec::new();
+        tar(&mut bytes, &[("x", b"hello"), ("y", b"world")]).unwrap();
+        // Cut off halfway through the header of the second file
+        fs::write(&path, &bytes[..512 + 512 + 100]).unwrap();
+        let (members, error) = list_members(&path);
+        fs::remove_file(&path).unwrap();
+
+        let names: Vec<&str> =
+            members.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["x"]);
+        assert!(error.is_some());
+    }
+}
@@ -0,0 +1,248 @@
+//! Items to inspect and maintain the cache.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::connection::Connection;
+use crate::connection::ConnectionKind;
+use crate::core::ansi::Bold;
+use crate::core::time::Timestamp;
+use crate::core::units::Bytes;
+use crate::db::Change;
+use crate::db::Database;
+
+/// Name of the cache file inside the cache directory.
+const CACHE_FILE_NAME: &str = "index.dat";
+
+/// Returns the default location of the cache, if there is one.
+///
+/// This is inside the per-user cache directory of the platform,
+/// e.g. `$XDG_CACHE_HOME` on Linux or `%LOCALAPPDATA%` on Windows.
+pub fn default_cache_path() -> Option<PathBuf> {
+    let dir = dirs::cache_dir()?;
+    Some(dir.join(env!("CARGO_PKG_NAME")).join(CACHE_FILE_NAME))
+}
+
+//////////////
+// Commands //
+//////////////
+
+#[derive(Debug, Clone, Copy)]
+/// A maintenance task.
+pub enum CacheCommand {
+    /// Prints statistics on the cache.
+    Stats,
+    /// Forgets directories that no longer exist, and the files inside.
+    Prune,
+    /// Forgets directories not searched for the given duration,
+    /// and files not inside a directory searched since.
+    ///
+    /// Files are not aged one by one, but by the directories containing them.
+    Vacuum {
+        /// How long ago a directory must have been searched to be kept.
+        max_age: Duration,
+    },
+}
+
+/// Returns the changes that forget the given roots,
+/// and every file not inside a root that remains.
+fn forget_roots(db: &Database, roots: &[&Path]) -> Vec<Change> {
+    let remaining: Vec<&Path> = db
+        .roots()
+        .map(|(path, _)| path)
+        .filter(|path| !roots.contains(path))
+        .collect();
+    let files: BTreeSet<&Path> = db
+        .paths()
+        .chain(db.partial_paths())
+        .chain(db.image_paths())
+        .chain(db.listing_paths())
+        .filter(|file| !remaining.iter().any(|root| file.starts_with(root)))
+        .collect();
+    let files =
+        files.into_iter().map(|file| Change::Remove(file.to_path_buf()));
+    roots
+        .iter()
+        .map(|root| Change::ForgetRoot(root.to_path_buf()))
+        .chain(files)
+        .collect()
+}
+
+fn print_stats(db: &Database, mut out: impl Write) -> crate::Result {
+    let mut roots: BTreeMap<&Path, (SystemTime, usize, u64)> = db
+        .roots()
+        .map(|(path, root)| (path, (root.last_scanned, 0, 0)))
+        .collect();
+    let mut total_bytes = 0;
+    let mut file_count = 0;
+    for (path, record) in db.records() {
+        file_count += 1;
+        total_bytes += record.fingerprint.size;
+        // Attribute each file to the innermost root containing it
+        let root = roots
+            .iter_mut()
+            .filter(|(root, _)| path.starts_with(root))
+            .max_by_key(|(root, _)| root.as_os_str().len());
+        if let Some((_, (_, count, bytes))) = root {
+            *count += 1;
+            *bytes += record.fingerprint.size;
+        }
+    }
+
+    writeln!(
+        out,
+        "{}",
+        Bold(format!("{} file(s), {}", file_count, Bytes(total_bytes))),
+    )?;
+    for (path, (last_scanned, count, bytes)) in roots {
+        let missing = match path.is_dir() {
+            true => "",
+            false => " (missing)",
+        };
+        writeln!(
+            out,
+            "{}{}: {} file(s), {}, last scanned {}",
+            path.display(),
+            missing,
+            count,
+            Bytes(bytes),
+            Timestamp(last_scanned),
+        )?;
+    }
+    Ok(())
+}
+
+/// Runs a maintenance task on the cache.
+pub fn maintain(
+    kind: ConnectionKind,
+    command: CacheCommand,
+    mut out: impl Write,
+) -> crate::Result {
+    let mut index = Connection::<Database>::open(kind)
+        .context("failed to open cache; use --clean-cache to rebuild it")?;
+    let changes = match command {
+        CacheCommand::Stats => return print_stats(&index, out),
+        CacheCommand::Prune => {
+            let missing: Vec<&Path> = index
+                .roots()
+                .map(|(path, _)| path)
+                .filter(|path| !path.is_dir())
+                .collect();
+            forget_roots(&index, &missing)
+        },
+        CacheCommand::Vacuum { max_age } => {
+            let cutoff = SystemTime::now()
+                .checked_sub(max_age)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let old: Vec<&Path> = index
+                .roots()
+                .filter(|(_, root)| root.last_scanned < cutoff)
+                .map(|(path, _)| path)
+                .collect();
+            forget_roots(&index, &old)
+        },
+    };
+
+    let roots =
+        changes.iter().filter(|c| matches!(c, Change::ForgetRoot(_))).count();
+    let files = changes.len() - roots;
+    for change in changes {
+        index.apply(change)?;
+    }
+    index.save()?;
+    writeln!(out, "forgot {} director(ies) and {} file(s)", roots, files)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::db::Record;
+    use crate::fingerprint::Fingerprint;
+    use crate::hash::FileHash;
+    use crate::hash::HashAlgorithm;
+
+    /// Creates a cache with an existing directory searched long ago,
+    /// and a missing directory searched just now.
+    fn fixture(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "duplicate-detector-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let old = dir.join("old");
+        fs::create_dir_all(&old).unwrap();
+        let cache = dir.join("index.dat");
+        let mut index = Connection::<Database>::open_empty(
+            ConnectionKind::Disk(cache.clone()),
+        )
+        .unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(10 * 86400);
+        let record = Record {
+            fingerprint: Fingerprint {
+                size: 10,
+                modified: SystemTime::UNIX_EPOCH,
+                id: None,
+            },
+            hash: FileHash::new(HashAlgorithm::Xxh3, &[0; 16]).unwrap(),
+        };
+        let changes = [
+            Change::ScanRoot(old.clone(), long_ago),
+            Change::ScanRoot(dir.join("missing"), SystemTime::now()),
+            Change::Add(old.join("a"), record),
+            Change::Add(dir.join("missing/b"), record),
+            Change::Add(dir.join("missing/c"), record),
+        ];
+        for change in changes {
+            index.apply(change).unwrap();
+        }
+        index.save().unwrap();
+        (dir, cache)
+    }
+
+    fn run(cache: &Path, command: CacheCommand) -> String {
+        let mut out = Vec::new();
+        maintain(ConnectionKind::Disk(cache.to_path_buf()), command, &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn prune_forgets_missing_directories() {
+        let (dir, cache) = fixture("prune");
+        let before = run(&cache, CacheCommand::Stats);
+        let pruned = run(&cache, CacheCommand::Prune);
+        let after = run(&cache, CacheCommand::Stats);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(before.contains("3 file(s), 30 B"));
+        assert!(before.contains("missing (missing): 2 file(s), 20 B"));
+        assert_eq!(pruned, "forgot 1 director(ies) and 2 file(s)\n");
+        assert!(after.contains("1 file(s), 10 B"));
+        assert!(!after.contains("missing"));
+    }
+
+    #[test]
+    fn vacuum_forgets_directories_not_searched_recently() {
+        let (dir, cache) = fixture("vacuum");
+        let day = Duration::from_secs(86400);
+        let kept = run(&cache, CacheCommand::Vacuum { max_age: 30 * day });
+        let vacuumed = run(&cache, CacheCommand::Vacuum { max_age: day });
+        let after = run(&cache, CacheCommand::Stats);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(kept, "forgot 0 director(ies) and 0 file(s)\n");
+        assert_eq!(vacuumed, "forgot 1 director(ies) and 1 file(s)\n");
+        assert!(after.contains("2 file(s), 20 B"));
+        assert!(!after.contains("old"));
+    }
+}
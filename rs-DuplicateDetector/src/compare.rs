@@ -0,0 +1,144 @@
+//! Items to compare two directories by contents,
+//! e.g. to check that a backup holds everything in the original.
+//!
+//! Contents are compared by hash, so files only need to be read
+//! if a file of the same size exists; the rest have unique contents.
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::iter::once;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::hash::FileHash;
+use crate::scan::Scan;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// How the contents of two directories differ.
+pub struct Comparison {
+    /// Files in the first directory with contents nowhere in the second.
+    pub only_in_first: Vec<PathBuf>,
+    /// Files in the second directory with contents nowhere in the first.
+    pub only_in_second: Vec<PathBuf>,
+    /// Files in the first directory whose namesake in the second
+    /// has different contents. These are not listed as only in either.
+    pub different: Vec<PathBuf>,
+}
+
+impl Comparison {
+    /// Whether the directories hold the same contents.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Number of files that differ, counting each pair of namesakes once.
+    pub fn len(&self) -> usize {
+        self.only_in_first.len() +
+            self.only_in_second.len() +
+            self.different.len()
+    }
+}
+
+/// The contents of each file in a directory, by path relative to it.
+/// Files without a hash have a size no other file has.
+type Contents<'a> = BTreeMap<&'a Path, (&'a Path, Option<&'a FileHash>)>;
+
+impl Scan {
+    /// Lists the contents of every readable file in the directory,
+    /// including every name of a file with hard links.
+    fn contents(&self, dir: &Path) -> Contents<'_> {
+        let skipped: HashSet<&Path> =
+            self.skipped.iter().map(|skipped| skipped.path.as_path()).collect();
+        let mut contents = Contents::new();
+        for path in self.files.keys() {
+            if skipped.contains(path.as_path()) {
+                continue;
+            }
+            let hash = self.index.get(path).map(|record| &record.hash);
+            let links = self.hard_links.get(path).into_iter().flatten();
+            for name in once(path).chain(links) {
+                if let Ok(relative) = name.strip_prefix(dir) {
+                    contents.insert(relative, (name, hash));
+                }
+            }
+        }
+        contents
+    }
+
+    /// Compares the contents of two of the directories scanned.
+    ///
+    /// Directories must be given as scanned, and not contain one another.
+    pub fn compare(&self, first: &Path, second: &Path) -> Comparison {
+        let (first, second) = (self.contents(first), self.contents(second));
+        let hashes = |contents: &Contents| -> HashSet<FileHash> {
+            contents.values().filter_map(|(_, hash)| hash.copied()).collect()
+        };
+        let (first_hashes, second_hashes) = (hashes(&first), hashes(&second));
+
+        let mut comparison = Comparison::default();
+        let mut different: HashSet<&Path> = HashSet::new();
+        for (relative, (path, hash)) in &first {
+            if let Some((_, other)) = second.get(relative) &&
+                (hash.is_none() || hash != other)
+            {
+                different.insert(relative);
+                comparison.different.push(path.to_path_buf());
+            }
+        }
+        let only_in = |contents: &Contents, others: &HashSet<FileHash>| {
+            contents
+                .iter()
+                .filter(|(relative, _)| !different.contains(*relative))
+                .filter(|(_, (_, hash))| {
+                    !hash.is_some_and(|h| others.contains(h))
+                })
+                .map(|(_, (path, _))| path.to_path_buf())
+                .collect()
+        };
+        comparison.only_in_first = only_in(&first, &second_hashes);
+        comparison.only_in_second = only_in(&second, &first_hashes);
+        comparison
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::connection::ConnectionKind;
+    use crate::progress::NoProgress;
+    use crate::scan::ScanOptions;
+    use crate::scan::scan;
+
+    #[test]
+    fn finds_what_a_backup_is_missing() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-compare", std::process::id()));
+        let (original, backup) = (dir.join("original"), dir.join("backup"));
+        for (root, files) in [
+            (&original, [("kept", "1"), ("moved", "22"), ("edited", "333")]),
+            (&backup, [("kept", "1"), ("moved/x", "22"), ("edited", "444")]),
+        ] {
+            for (name, contents) in files {
+                let path = root.join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+        }
+        fs::write(original.join("lost"), "55555").unwrap();
+        fs::write(backup.join("new"), "666666").unwrap();
+
+        let options = ScanOptions::for_tests(
+            vec![original.clone(), backup.clone()],
+            ConnectionKind::Memory,
+        );
+        let scan = scan(options, &mut NoProgress).unwrap();
+        let comparison = scan.compare(&original, &backup);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(comparison, Comparison {
+            only_in_first: vec![original.join("lost")],
+            only_in_second: vec![backup.join("new")],
+            different: vec![original.join("edited")],
+        });
+    }
+}
@@ -1,54 +1,305 @@
 //! Abstracts over storing a file on disk and in memory.
+//!
+//! Disk-backed stores can also keep a journal:
+//! changes are appended to a log next to the file,
+//! and the file itself is only rewritten once the log grows too large.
+//! The log is also written to disk every so often,
+//! so a crash only loses the most recent changes.
 
 use std::fs;
+use std::fs::File;
 use std::fs::OpenOptions;
+use std::fs::TryLockError;
 use std::fs::create_dir_all;
-use std::io::Read;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
 use std::ops::Deref;
-use std::ops::DerefMut;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Context;
+use anyhow::bail;
 use serde::Deserialize;
 use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::core::fs::replace_file;
 
 //////////////////
 // Cache Format //
 //////////////////
 
+/// Identifies a cache file; precedes the version.
+const MAGIC: &[u8; 4] = b"DDC\0";
+
+/// Version of the cache format.
+///
+/// New fields must be `#[serde(default)]`, so older caches stay readable
+/// without a new version. Increment only for changes older caches cannot
+/// be read with, e.g. removing, renaming or retyping a field.
+pub const FORMAT_VERSION: u32 = 1;
+
 /// Recover an instance of the underlying type from a slice of bytes.
 fn from_bytes<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> crate::Result<T> {
-    Ok(rmp_serde::from_slice(bytes)?)
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        bail!("cache has no header, so it predates versioning");
+    };
+    let Some((version, payload)) = rest.split_first_chunk() else {
+        bail!("cache header is truncated");
+    };
+    match u32::from_le_bytes(*version) {
+        FORMAT_VERSION => {
+            Ok(rmp_serde::from_slice(payload).context("cache is corrupt")?)
+        },
+        version if version > FORMAT_VERSION => bail!(
+            "cache has format version {}, \
+             but this program only understands up to version {}",
+            version,
+            FORMAT_VERSION,
+        ),
+        version => bail!(
+            "cache has outdated format version {} (current is {})",
+            version,
+            FORMAT_VERSION,
+        ),
+    }
 }
 
 /// Convert an instance of the underlying type into bytes.
 fn to_bytes<T: Serialize + ?Sized>(value: &T) -> crate::Result<Vec<u8>> {
-    Ok(rmp_serde::to_vec(value)?)
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    rmp_serde::encode::write(&mut bytes, value)?;
+    Ok(bytes)
 }
 
 /// Saves a [`Serialize`]able value to a file at the given path.
 /// Returns the number of bytes written.
+///
+/// The file is replaced atomically, so a crash leaves either
+/// the old or the new contents, but never a mix.
 fn save_to_file<T: Serialize>(path: &Path, value: &T) -> crate::Result<usize> {
     let contents = to_bytes(value)?;
     let bytes = contents.len();
-    fs::write(path, contents)?;
+    replace_file(path, |temp| {
+        let mut file = File::create(temp)?;
+        file.write_all(&contents)?;
+        file.sync_all()
+    })?;
     Ok(bytes)
 }
 
 /// Loads a [`Deserialize`]able value from a file at the given path.
-/// - The outer result contains IO errors, if any.
-/// - The inner option contains parsing errors, if any.
+/// Returns [`None`] if the file does not exist.
 fn load_from_file<T: for<'a> Deserialize<'a>>(
     path: &Path,
 ) -> crate::Result<Option<T>> {
-    let parent = path.parent().context("path has no parent")?;
-    create_dir_all(parent)?;
-    let mut file =
-        OpenOptions::new().read(true).write(true).create(true).open(path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    Ok(from_bytes(&buffer).ok())
+    let buffer = match fs::read(path) {
+        Ok(buffer) => buffer,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Ok(None);
+        },
+        Err(error) => return Err(error.into()),
+    };
+    Ok(Some(from_bytes(&buffer)?))
+}
+
+/// Returns the path of a file next to the given file,
+/// named after it plus the suffix.
+fn sibling(path: &Path, suffix: &str) -> crate::Result<PathBuf> {
+    let mut name = path.file_name().context("path has no name")?.to_owned();
+    name.push(suffix);
+    Ok(path.with_file_name(name))
+}
+
+/// Takes an exclusive advisory lock for the file at the given path.
+/// The lock is held until the returned file is dropped.
+///
+/// The lock lives in a separate file,
+/// since saving replaces the file itself.
+fn lock_file(path: &Path) -> crate::Result<File> {
+    let lock = File::create(sibling(path, ".lock")?)?;
+    match lock.try_lock() {
+        Ok(()) => {},
+        Err(TryLockError::WouldBlock) => {
+            eprintln!(
+                "waiting for another process using '{}'...",
+                path.display(),
+            );
+            lock.lock()?;
+        },
+        Err(TryLockError::Error(error)) => return Err(error.into()),
+    }
+    Ok(lock)
+}
+
+/////////////
+// Journal //
+/////////////
+
+/// A type whose changes can be recorded in a journal.
+///
+/// Replaying changes must be idempotent in aggregate:
+/// applying changes already reflected in the value must not alter it.
+/// This holds for changes that overwrite state, such as inserts and removals.
+pub trait Journal {
+    /// A single change to the value.
+    type Change: Serialize + DeserializeOwned;
+
+    /// Applies a change to the value.
+    fn apply(&mut self, change: Self::Change);
+}
+
+/// Identifies a journal file; precedes the version.
+const JOURNAL_MAGIC: &[u8; 4] = b"DDJ\0";
+
+/// Length of the header of a journal file.
+const JOURNAL_HEADER_LEN: u64 = 8;
+
+/// The journal is compacted once it exceeds this size,
+/// and the size of the snapshot.
+const MIN_COMPACT_BYTES: u64 = 1024 * 1024;
+
+/// Records are written to disk once this many are buffered,
+/// or once the oldest has been buffered this long.
+const FLUSH_RECORDS: usize = 1024;
+const FLUSH_PERIOD: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+/// An append-only log of changes.
+///
+/// Each record is a little-endian `u32` length, followed by the change.
+struct JournalLog {
+    writer: BufWriter<File>,
+    /// Size of the log, including unflushed records.
+    bytes: u64,
+    /// Size of the snapshot the log applies to.
+    snapshot_bytes: u64,
+    /// Whether the snapshot must be rewritten, regardless of sizes.
+    is_snapshot_stale: bool,
+    /// Number of records not yet written to disk.
+    unflushed: usize,
+    /// When the records were last written to disk.
+    flushed_at: Instant,
+}
+
+impl JournalLog {
+    /// Applies all complete records in the log to the value,
+    /// then opens the log for appending.
+    /// A torn record at the end, left by a crash, is discarded.
+    fn replay<T: Journal>(path: &Path, value: &mut T) -> crate::Result<Self> {
+        let buffer = match fs::read(path) {
+            Ok(buffer) => buffer,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => vec![],
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut valid = 0;
+        if !buffer.is_empty() {
+            let Some(rest) = buffer.strip_prefix(JOURNAL_MAGIC) else {
+                bail!("journal has no header");
+            };
+            let Some((version, mut records)) = rest.split_first_chunk() else {
+                bail!("journal header is truncated");
+            };
+            let version = u32::from_le_bytes(*version);
+            if version != FORMAT_VERSION {
+                bail!(
+                    "journal has format version {}, expected {}",
+                    version,
+                    FORMAT_VERSION,
+                );
+            }
+            valid = buffer.len() - records.len();
+            while let Some((len, rest)) = records.split_first_chunk() {
+                let len = u32::from_le_bytes(*len) as usize;
+                let Some((record, rest)) = rest.split_at_checked(len) else {
+                    break;
+                };
+                let Ok(change) = rmp_serde::from_slice(record) else { break };
+                value.apply(change);
+                records = rest;
+                valid = buffer.len() - records.len();
+            }
+        }
+
+        let mut log = Self::open(path)?;
+        match valid {
+            0 => log.truncate()?,
+            valid => {
+                log.writer.get_ref().set_len(valid as u64)?;
+                log.bytes = valid as u64;
+            },
+        }
+        Ok(log)
+    }
+
+    /// Creates an empty log, discarding any existing records.
+    fn create(path: &Path) -> crate::Result<Self> {
+        let mut log = Self::open(path)?;
+        log.truncate()?;
+        Ok(log)
+    }
+
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JournalLog {
+            writer: BufWriter::new(file),
+            bytes: 0,
+            snapshot_bytes: 0,
+            is_snapshot_stale: false,
+            unflushed: 0,
+            flushed_at: Instant::now(),
+        })
+    }
+
+    /// Appends a change to the log,
+    /// writing buffered records to disk if enough have piled up.
+    fn append<C: Serialize>(&mut self, change: &C) -> crate::Result {
+        let record = rmp_serde::to_vec(change)?;
+        let len = u32::try_from(record.len())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(&record)?;
+        self.bytes += 4 + record.len() as u64;
+        self.unflushed += 1;
+        if self.unflushed >= FLUSH_RECORDS ||
+            self.flushed_at.elapsed() >= FLUSH_PERIOD
+        {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Whether the log has grown large enough to fold into the snapshot.
+    fn should_compact(&self) -> bool {
+        self.is_snapshot_stale ||
+            self.bytes > MIN_COMPACT_BYTES.max(self.snapshot_bytes)
+    }
+
+    /// Discards all records, leaving only the header.
+    fn truncate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_mut();
+        file.set_len(0)?;
+        file.write_all(JOURNAL_MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        file.sync_data()?;
+        self.bytes = JOURNAL_HEADER_LEN;
+        Ok(())
+    }
+
+    /// Writes buffered records to disk.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unflushed = 0;
+        self.flushed_at = Instant::now();
+        Ok(())
+    }
 }
 
 ////////////////
@@ -61,7 +312,11 @@ pub enum ConnectionKind {
     /// Store it in memory.
     Memory,
     /// Store it on disk.
+    /// A journal left next to the file is folded into it, then removed.
     Disk(PathBuf),
+    /// Store it on disk, with changes appended to a journal.
+    /// The journal is stored next to the file, with a `.journal` suffix.
+    Journal(PathBuf),
 }
 
 #[derive(Debug)]
@@ -69,6 +324,10 @@ pub enum ConnectionKind {
 pub struct Connection<T> {
     kind: ConnectionKind,
     inner: T,
+    /// Held while connected to a disk-backed store.
+    _lock: Option<File>,
+    /// Present when connected to a journaled store.
+    journal: Option<JournalLog>,
 }
 
 impl<T> Connection<T> {
@@ -78,11 +337,37 @@ impl<T> Connection<T> {
     }
 }
 
-impl<T: Serialize> Connection<T> {
+impl<T: Journal + Serialize> Connection<T> {
+    /// Applies a change, recording it in the journal if there is one.
+    pub fn apply(&mut self, change: T::Change) -> crate::Result {
+        if let Some(journal) = &mut self.journal {
+            journal.append(&change)?;
+        }
+        self.inner.apply(change);
+        Ok(())
+    }
+
     /// Writes changes to the backing store.
-    pub fn save(&self) -> crate::Result {
-        if let ConnectionKind::Disk(file) = &self.kind {
-            save_to_file(file, &self.inner)?;
+    pub fn save(&mut self) -> crate::Result {
+        match (&self.kind, &mut self.journal) {
+            (ConnectionKind::Memory, _) => {},
+            (ConnectionKind::Disk(file), _) => {
+                save_to_file(file, &self.inner)?;
+            },
+            (ConnectionKind::Journal(file), Some(journal)) => {
+                journal.flush()?;
+                if journal.should_compact() {
+                    // NB: replaying the old journal on top of the new snapshot
+                    // is harmless, so a crash in between loses nothing
+                    let bytes = save_to_file(file, &self.inner)?;
+                    journal.snapshot_bytes = bytes as u64;
+                    journal.is_snapshot_stale = false;
+                    journal.truncate()?;
+                }
+            },
+            (ConnectionKind::Journal(_), None) => {
+                unreachable!("journaled connection without a journal")
+            },
         }
         Ok(())
     }
@@ -93,26 +378,83 @@ impl<T: Default> Connection<T> {
     pub fn open_in_memory() -> crate::Result<Self> {
         let inner = T::default();
         let kind = ConnectionKind::Memory;
-        Ok(Connection { kind, inner })
+        Ok(Connection { kind, inner, _lock: None, journal: None })
     }
 }
 
-impl<T: for<'a> Deserialize<'a> + Default> Connection<T> {
+impl<T: Journal + DeserializeOwned + Serialize + Default> Connection<T> {
     /// Establises a connection to a disk-backed store.
-    fn open_from_disk(file: PathBuf) -> crate::Result<Self> {
-        let inner = load_from_file(&file)
-            .context("failed to open connection")?
-            .unwrap_or_else(T::default);
-        let kind = ConnectionKind::Disk(file);
-        Ok(Connection { kind, inner })
+    fn open_from_disk(kind: ConnectionKind, load: bool) -> crate::Result<Self> {
+        let (ConnectionKind::Disk(file) | ConnectionKind::Journal(file)) =
+            &kind
+        else {
+            return Self::open_in_memory();
+        };
+        let parent = file.parent().context("path has no parent")?;
+        create_dir_all(parent)?;
+        let lock = lock_file(file).context("failed to lock cache")?;
+        let mut inner = match load {
+            true => load_from_file(file)
+                .with_context(|| {
+                    format!("failed to read '{}'", file.display())
+                })?
+                .unwrap_or_default(),
+            false => T::default(),
+        };
+
+        let journal = match (&kind, load) {
+            (ConnectionKind::Journal(_), true) => {
+                let path = sibling(file, ".journal")?;
+                let mut journal = JournalLog::replay(&path, &mut inner)
+                    .with_context(|| {
+                        format!("failed to replay '{}'", path.display())
+                    })?;
+                journal.snapshot_bytes =
+                    fs::metadata(file).map_or(0, |meta| meta.len());
+                Some(journal)
+            },
+            (ConnectionKind::Journal(_), false) => {
+                let path = sibling(file, ".journal")?;
+                let mut journal = JournalLog::create(&path)?;
+                journal.is_snapshot_stale = true;
+                Some(journal)
+            },
+            (ConnectionKind::Disk(_), load) => {
+                // Changes journaled by an earlier run belong in the snapshot,
+                // or a later journaled run would replay them on a newer one
+                let path = sibling(file, ".journal")?;
+                if load && path.try_exists()? {
+                    JournalLog::replay(&path, &mut inner).with_context(
+                        || format!("failed to replay '{}'", path.display()),
+                    )?;
+                    save_to_file(file, &inner)?;
+                }
+                match fs::remove_file(&path) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                        return Err(error.into());
+                    },
+                    _ => None,
+                }
+            },
+            (ConnectionKind::Memory, _) => None,
+        };
+
+        Ok(Connection { kind, inner, _lock: Some(lock), journal })
     }
 
     /// Establishes a connection of the given kind.
+    ///
+    /// Fails if the store exists but cannot be read,
+    /// for example because it was written in a different format.
     pub fn open(kind: ConnectionKind) -> crate::Result<Self> {
-        match kind {
-            ConnectionKind::Disk(file) => Self::open_from_disk(file),
-            ConnectionKind::Memory => Self::open_in_memory(),
-        }
+        Self::open_from_disk(kind, true)
+    }
+
+    /// Establishes a connection of the given kind,
+    /// ignoring the current contents of the store.
+    /// These are overwritten on the next [`Connection::save`].
+    pub fn open_empty(kind: ConnectionKind) -> crate::Result<Self> {
+        Self::open_from_disk(kind, false)
     }
 }
 
@@ -121,6 +463,114 @@ impl<T> Deref for Connection<T> {
     fn deref(&self) -> &Self::Target { &self.inner }
 }
 
-impl<T> DerefMut for Connection<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.inner }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes = to_bytes(&vec![1, 2, 3]).unwrap();
+        assert_eq!(from_bytes::<Vec<u32>>(&bytes).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = to_bytes(&vec![1, 2, 3]).unwrap();
+        assert!(from_bytes::<Vec<u32>>(&bytes[MAGIC.len()..]).is_err());
+        bytes[MAGIC.len()..][..4].copy_from_slice(&2u32.to_le_bytes());
+        assert!(from_bytes::<Vec<u32>>(&bytes).is_err());
+    }
+
+    impl Journal for Vec<u32> {
+        type Change = u32;
+        fn apply(&mut self, change: u32) { self.push(change) }
+    }
+
+    #[test]
+    fn replay_discards_torn_record() {
+        let path = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}.journal", std::process::id()));
+        let mut log = JournalLog::create(&path).unwrap();
+        log.append(&1).unwrap();
+        log.append(&2).unwrap();
+        log.flush().unwrap();
+        drop(log);
+
+        // Simulate a crash halfway through the third record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(&[0x03]).unwrap();
+        drop(file);
+
+        let mut value = vec![];
+        let mut log = JournalLog::replay(&path, &mut value).unwrap();
+        assert_eq!(value, vec![1, 2]);
+        log.append(&3).unwrap();
+        log.flush().unwrap();
+        drop(log);
+
+        let mut value = vec![];
+        JournalLog::replay(&path, &mut value).unwrap();
+        assert_eq!(value, vec![1, 2, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn appends_reach_the_disk_without_saving() {
+        let path = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-flush", std::process::id()));
+        let mut connection = Connection::<Vec<u32>>::open_empty(
+            ConnectionKind::Journal(path.clone()),
+        )
+        .unwrap();
+        for change in 0..FLUSH_RECORDS as u32 + 1 {
+            connection.apply(change).unwrap();
+        }
+
+        // Read while the connection is still open, as after a crash
+        let journal = sibling(&path, ".journal").unwrap();
+        let mut value = vec![];
+        JournalLog::replay(&journal, &mut value).unwrap();
+        assert_eq!(value.len(), FLUSH_RECORDS);
+        drop(connection);
+        for suffix in [".journal", ".lock"] {
+            fs::remove_file(sibling(&path, suffix).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn disk_connections_fold_in_the_journal() {
+        let path = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-fold", std::process::id()));
+        let journal = sibling(&path, ".journal").unwrap();
+        let mut connection = Connection::<Vec<u32>>::open_empty(
+            ConnectionKind::Journal(path.clone()),
+        )
+        .unwrap();
+        connection.save().unwrap();
+        connection.apply(1).unwrap();
+        connection.apply(2).unwrap();
+        // Too little to compact, so the changes stay in the journal
+        connection.save().unwrap();
+        drop(connection);
+
+        let connection =
+            Connection::<Vec<u32>>::open(ConnectionKind::Disk(path.clone()))
+                .unwrap();
+        let from_disk = connection.to_vec();
+        let is_journal_left = journal.exists();
+        drop(connection);
+        let connection =
+            Connection::<Vec<u32>>::open(ConnectionKind::Journal(path.clone()))
+                .unwrap();
+        let from_journal = connection.to_vec();
+        drop(connection);
+        for suffix in ["", ".journal", ".lock"] {
+            fs::remove_file(sibling(&path, suffix).unwrap()).unwrap();
+        }
+
+        assert_eq!(from_disk, [1, 2]);
+        assert!(!is_journal_left);
+        assert_eq!(from_journal, [1, 2]);
+    }
 }
@@ -30,6 +30,14 @@ pub const CLEAR_LINE: &str = "\x1B[2K";
 pub const HIDE_CURSOR: &str = "\x1B[?25l";
 /// ANSI sequence to show the cursor.
 pub const SHOW_CURSOR: &str = "\x1B[?25h";
+/// ANSI sequence to move the cursor to the top left corner.
+pub const CURSOR_HOME: &str = "\x1B[H";
+/// ANSI sequence to clear the entire screen.
+pub const CLEAR_SCREEN: &str = "\x1B[2J";
+/// ANSI sequence to switch to the alternate screen buffer.
+pub const ENTER_ALT_SCREEN: &str = "\x1B[?1049h";
+/// ANSI sequence to switch back to the main screen buffer.
+pub const LEAVE_ALT_SCREEN: &str = "\x1B[?1049l";
 
 /////////////////////
 // Simple wrappers //
@@ -0,0 +1,119 @@
+//! A BK-tree, which finds items within some distance of a query.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+
+/// A distance function which satisfies the triangle inequality.
+pub trait Metric {
+    /// Returns the distance between two values.
+    fn distance(&self, other: &Self) -> u32;
+}
+
+impl Metric for u64 {
+    /// The Hamming distance, i.e. the number of bits that differ.
+    fn distance(&self, other: &Self) -> u32 { (self ^ other).count_ones() }
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    value: T,
+    /// Children, keyed by their distance to this node.
+    children: BTreeMap<u32, Node<T>>,
+}
+
+/// Indexes values by a [`Metric`],
+/// so values close to a query can be found without comparing every value.
+///
+/// See <https://en.wikipedia.org/wiki/BK-tree>.
+#[derive(Debug, Clone)]
+pub struct BkTree<T> {
+    root: Option<Node<T>>,
+    len: usize,
+}
+
+impl<T> Default for BkTree<T> {
+    fn default() -> Self { BkTree { root: None, len: 0 } }
+}
+
+impl<T: Metric> BkTree<T> {
+    /// Creates an empty tree.
+    pub fn new() -> Self { Self::default() }
+
+    /// Returns the number of values in this tree.
+    pub fn len(&self) -> usize { self.len }
+
+    /// Returns `true` if this tree contains no values.
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Adds a value.
+    pub fn insert(&mut self, value: T) {
+        self.len += 1;
+        let Some(mut node) = self.root.as_mut() else {
+            self.root = Some(Node { value, children: BTreeMap::new() });
+            return;
+        };
+        loop {
+            let distance = node.value.distance(&value);
+            match node.children.entry(distance) {
+                Entry::Vacant(entry) => {
+                    entry.insert(Node { value, children: BTreeMap::new() });
+                    return;
+                },
+                Entry::Occupied(entry) => node = entry.into_mut(),
+            }
+        }
+    }
+
+    /// Returns all values within the given distance of the query,
+    /// together with their distance.
+    pub fn find(&self, query: &T, max_distance: u32) -> Vec<(&T, u32)> {
+        let mut found = Vec::new();
+        let mut stack: Vec<&Node<T>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            let distance = node.value.distance(query);
+            if distance <= max_distance {
+                found.push((&node.value, distance));
+            }
+            // By the triangle inequality, only these children can match
+            let low = distance.saturating_sub(max_distance);
+            let high = distance.saturating_add(max_distance);
+            stack.extend(node.children.range(low..=high).map(|(_, n)| n));
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_matches_linear_scan() {
+        let values: Vec<u64> = (0..200u64)
+            .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (i % 7))
+            .collect();
+        let mut tree = BkTree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        assert_eq!(tree.len(), values.len());
+
+        for query in [values[3], values[150], 0, u64::MAX] {
+            for max_distance in [0, 4, 16, 32] {
+                let mut expected: Vec<u64> = values
+                    .iter()
+                    .copied()
+                    .filter(|v| v.distance(&query) <= max_distance)
+                    .collect();
+                let mut actual: Vec<u64> = tree
+                    .find(&query, max_distance)
+                    .into_iter()
+                    .map(|(v, _)| *v)
+                    .collect();
+                expected.sort();
+                actual.sort();
+                assert_eq!(actual, expected);
+            }
+        }
+    }
+}
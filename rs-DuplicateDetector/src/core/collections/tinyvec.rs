@@ -37,6 +37,9 @@ impl<T> TinyVec<T> {
         }
     }
 
+    /// Returns `true` if this vector contains no items.
+    pub fn is_empty(&self) -> bool { matches!(self, Empty) }
+
     /// Adds a new value.
     pub fn push(&mut self, new: T) {
         // ...is there ::core::mem method that does in one operation?
@@ -48,11 +48,7 @@ pub struct AggregateError {
 impl AggregateError {
     /// Creates a new aggregate error.
     pub fn new(errors: Vec<crate::Error>) -> Option<Self> {
-        if errors.len() != 0 {
-            Some(AggregateError { errors })
-        } else {
-            return None
-        }
+        if !errors.is_empty() { Some(AggregateError { errors }) } else { None }
     }
 }
 
@@ -1,43 +1,76 @@
 //! Contains additional file system routines.
 
-use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
 use std::path::absolute;
 use std::process::Command;
-use std::thread::sleep;
-use std::time::Duration;
-
-/// Recursively reads a directory and returns a list of all files.
-/// Returned paths are relative to the given directory.
-pub fn read_dir_all<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
-    // FIXME: One circular symbolic link and this blows up
-    // TODO: Maybe turns this into an iterator?
-    let mut frontier = VecDeque::new();
-    let mut visited = Vec::new();
-    frontier.push_back(dir.as_ref().to_path_buf());
-    while let Some(dir) = frontier.pop_front() {
-        for item in fs::read_dir(dir)? {
-            let path = item?.path();
-            let stat = fs::metadata(&path)?;
-            if stat.is_dir() {
-                frontier.push_back(path);
-            } else if stat.is_file() {
-                visited.push(path);
-            } else {
-                // only visited if we symlink_metadata
-            }
+use std::process::Stdio;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// Creates a symbolic link to a file.
+pub fn symlink_file(
+    original: impl AsRef<Path>,
+    link: impl AsRef<Path>,
+) -> io::Result<()> {
+    #[cfg(unix)]
+    use std::os::unix::fs::symlink;
+    #[cfg(windows)]
+    use std::os::windows::fs::symlink_file as symlink;
+    symlink(original, link)
+}
+
+/// Creates a new directory next to the target, for temporary files.
+fn create_temp_dir(target: &Path, name: &OsStr) -> io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".{}.{}.tmp", std::process::id(), count));
+        let dir = target.with_file_name(temp_name);
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
         }
     }
-    Ok(visited)
+}
+
+/// Replaces a file with a file created by the callback.
+///
+/// The new file is created in a new directory next to the target,
+/// then moved over the target. As a result, the target is never missing,
+/// even if the callback fails, and no other file is ever touched.
+pub fn replace_file(
+    target: &Path,
+    create: impl FnOnce(&Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let Some(name) = target.file_name() else {
+        return Err(io::ErrorKind::InvalidInput.into());
+    };
+    let dir = create_temp_dir(target, name)?;
+    let temp = dir.join(name);
+
+    let result = create(&temp).and_then(|()| fs::rename(&temp, target));
+    if result.is_err() {
+        // The callback may have failed halfway through writing
+        let _ = fs::remove_file(&temp);
+    }
+    let _ = fs::remove_dir(&dir);
+    result
 }
 
 #[cfg(target_os = "windows")]
 /// Opens Windows Explorer and highlights the given file
 pub fn open_explorer(path: impl AsRef<Path>) -> io::Result<()> {
     use std::os::windows::process::CommandExt;
+    use std::thread::sleep;
+    use std::time::Duration;
     let path = absolute(path)?;
     let arg = format!("/select,\"{}\"", path.display());
     // raw_arg() and double quotes so spaces can appear anywhere in the path
@@ -45,3 +78,97 @@ pub fn open_explorer(path: impl AsRef<Path>) -> io::Result<()> {
     sleep(Duration::from_millis(50)); // Delay to allow the window to open
     Ok(())
 }
+
+#[cfg(target_os = "macos")]
+/// Opens Finder and highlights the given file
+pub fn open_explorer(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = absolute(path)?;
+    Command::new("open").arg("-R").arg(path).spawn()?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+/// Opens the default file manager in the directory of the given file
+pub fn open_explorer(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = absolute(path)?;
+    let dir = path.parent().unwrap_or(&path);
+    Command::new("xdg-open").arg(dir).spawn()?;
+    Ok(())
+}
+
+/// Runs a command to show the given file to the user.
+///
+/// The command is split on whitespace,
+/// then `{path}` and `{dir}` are replaced by the path to the file
+/// and the path to the directory containing the file respectively.
+pub fn reveal_with(command: &str, path: impl AsRef<Path>) -> io::Result<()> {
+    let path = absolute(path)?;
+    let dir = path.parent().unwrap_or(&path);
+    let replace = |arg: &str| {
+        arg.replace("{path}", &path.to_string_lossy())
+            .replace("{dir}", &dir.to_string_lossy())
+    };
+    let mut args = command.split_whitespace().map(replace);
+    let Some(program) = args.next() else {
+        return Err(io::Error::other("reveal command is empty"));
+    };
+    Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_replacements_leave_the_target_alone() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-replace", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target");
+        fs::write(&target, "original").unwrap();
+
+        let failed = replace_file(&target, |temp| {
+            fs::write(temp, "half")?;
+            Err(io::ErrorKind::StorageFull.into())
+        });
+        let after_failure = fs::read_to_string(&target).unwrap();
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        replace_file(&target, |temp| fs::write(temp, "replaced")).unwrap();
+        let after_success = fs::read_to_string(&target).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(failed.unwrap_err().kind(), io::ErrorKind::StorageFull);
+        assert_eq!(after_failure, "original");
+        assert_eq!(leftovers, 1);
+        assert_eq!(after_success, "replaced");
+    }
+
+    #[test]
+    fn replacements_leave_other_files_alone() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-others", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (keep, target) = (dir.join("keep"), dir.join("target"));
+        fs::write(&keep, "kept").unwrap();
+        fs::write(&target, "original").unwrap();
+        // Named as temporary files used to be
+        let other = dir.join(".target.tmp");
+        fs::write(&other, "mine").unwrap();
+
+        replace_file(&target, |temp| fs::hard_link(&keep, temp)).unwrap();
+        let replaced = fs::read_to_string(&target).unwrap();
+        let untouched = fs::read_to_string(&other).unwrap();
+        let files = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(replaced, "kept");
+        assert_eq!(untouched, "mine");
+        assert_eq!(files, 3);
+    }
+}
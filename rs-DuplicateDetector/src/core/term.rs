@@ -0,0 +1,292 @@
+//! Items to take over the terminal for a full-screen interface.
+//!
+//! Raw mode is set using `stty`, since calling `tcsetattr` requires `unsafe`.
+//! Where `stty` is unavailable (i.e. Windows), input falls back to lines:
+//! every key has to be followed by enter.
+//!
+//! In raw mode, reads time out after a tenth of a second,
+//! so a lone escape can be told apart from the start of an escape sequence,
+//! and a resized terminal is noticed while waiting for a key.
+
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::io::stdin;
+use std::io::stdout;
+use std::panic;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::PoisonError;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use crate::core::ansi::ENTER_ALT_SCREEN;
+use crate::core::ansi::HIDE_CURSOR;
+use crate::core::ansi::LEAVE_ALT_SCREEN;
+use crate::core::ansi::SHOW_CURSOR;
+
+/////////
+// Key //
+/////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A key pressed by the user.
+pub enum Key {
+    /// Arrow up.
+    Up,
+    /// Arrow down.
+    Down,
+    /// Arrow left.
+    Left,
+    /// Arrow right.
+    Right,
+    /// Page up.
+    PageUp,
+    /// Page down.
+    PageDown,
+    /// Tab.
+    Tab,
+    /// Enter or return.
+    Enter,
+    /// Escape.
+    Escape,
+    /// Any other (printable) character.
+    Char(char),
+    /// Not a key: the terminal was resized, so the screen must be redrawn.
+    Resize,
+}
+
+//////////
+// stty //
+//////////
+
+/// Runs `stty` on the controlling terminal; returns its output.
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+//////////////
+// Terminal //
+//////////////
+
+/// The `stty` settings to restore if the program panics,
+/// while a [`Terminal`] is alive.
+/// With `panic = "abort"`, the guard is never dropped.
+static RESTORE_ON_PANIC: Mutex<Option<Option<String>>> = Mutex::new(None);
+
+/// Leaves the alternate screen, and restores the `stty` settings, if any.
+fn restore(saved: Option<&str>) {
+    let mut out = stdout().lock();
+    let _ = write!(out, "{}{}", SHOW_CURSOR, LEAVE_ALT_SCREEN);
+    let _ = out.flush();
+    if let Some(saved) = saved {
+        let _ = stty(&[saved]);
+    }
+}
+
+/// Restores the terminal before a panic message is printed.
+fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let mut owned =
+                RESTORE_ON_PANIC.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(saved) = owned.take() {
+                restore(saved.as_deref());
+            }
+            drop(owned);
+            previous(info);
+        }));
+    });
+}
+
+/// Returns the number of rows and columns of the terminal.
+fn query_size() -> (usize, usize) {
+    const FALLBACK: (usize, usize) = (24, 80);
+    let Ok(size) = stty(&["size"]) else { return FALLBACK };
+    let mut parts = size.split_whitespace().map(str::parse);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(rows)), Some(Ok(cols))) if rows > 0 && cols > 0 => {
+            (rows, cols)
+        },
+        _ => FALLBACK,
+    }
+}
+
+#[derive(Debug)]
+/// Guard that owns the terminal, restoring it when dropped.
+pub struct Terminal {
+    /// The `stty` settings before entering raw mode, if it was entered.
+    saved: Option<String>,
+    /// The number of rows and columns, as of the last resize.
+    size: (usize, usize),
+    /// Set whenever the terminal is resized.
+    resized: Arc<AtomicBool>,
+    #[cfg(unix)]
+    /// Sets `resized` on `SIGWINCH`.
+    on_resize: Option<signal_hook::SigId>,
+}
+
+impl Terminal {
+    /// Switches to the alternate screen and enters raw mode, if possible.
+    pub fn enter() -> io::Result<Self> {
+        install_panic_hook();
+        let saved = stty(&["-g"]).ok();
+        if saved.is_some() {
+            // Reads wait for at most 1/10 s, rather than for a byte
+            stty(&["raw", "-echo", "min", "0", "time", "1"])?;
+        }
+        *RESTORE_ON_PANIC.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(saved.clone());
+        let mut out = stdout().lock();
+        write!(out, "{}{}", ENTER_ALT_SCREEN, HIDE_CURSOR)?;
+        out.flush()?;
+
+        let resized = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        let on_resize = signal_hook::flag::register(
+            signal_hook::consts::SIGWINCH,
+            resized.clone(),
+        )
+        .ok();
+        Ok(Terminal {
+            saved,
+            size: query_size(),
+            resized,
+            #[cfg(unix)]
+            on_resize,
+        })
+    }
+
+    /// Whether keys are read one at a time.
+    /// If not, every key has to be followed by enter.
+    pub fn is_raw(&self) -> bool { self.saved.is_some() }
+
+    /// Returns the number of rows and columns of the terminal.
+    pub fn size(&mut self) -> (usize, usize) {
+        if self.resized.swap(false, Ordering::Relaxed) {
+            self.size = query_size();
+        }
+        self.size
+    }
+
+    /// Blocks until the user presses a key, or the terminal is resized.
+    pub fn read_key(&mut self) -> io::Result<Key> {
+        if !self.is_raw() {
+            return read_line_key();
+        }
+        let mut input = stdin().lock();
+        loop {
+            if let Some(key) = read_raw_key(&mut input)? {
+                return Ok(key);
+            }
+            if self.resized.load(Ordering::Relaxed) {
+                return Ok(Key::Resize);
+            }
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(id) = self.on_resize {
+            signal_hook::low_level::unregister(id);
+        }
+        RESTORE_ON_PANIC.lock().unwrap_or_else(PoisonError::into_inner).take();
+        restore(self.saved.as_deref());
+    }
+}
+
+//////////////
+// Decoding //
+//////////////
+
+/// Reads a byte, or returns [`None`] if none arrives in time.
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut buffer = [0];
+    loop {
+        return match input.read(&mut buffer) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(buffer[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+    }
+}
+
+/// Decodes a key, or returns [`None`] if no key was pressed in time.
+fn read_raw_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let Some(byte) = read_byte(input)? else { return Ok(None) };
+    Ok(Some(match byte {
+        b'\t' => Key::Tab,
+        b'\r' | b'\n' => Key::Enter,
+        0x03 => Key::Char('q'), // Ctrl+C doesn't signal in raw mode
+        // A lone escape is not followed by anything, in time
+        0x1B => match read_byte(input)? {
+            Some(b'[') => match read_byte(input)? {
+                Some(b'A') => Key::Up,
+                Some(b'B') => Key::Down,
+                Some(b'C') => Key::Right,
+                Some(b'D') => Key::Left,
+                Some(digit @ (b'5' | b'6')) => {
+                    let _tilde = read_byte(input)?;
+                    match digit {
+                        b'5' => Key::PageUp,
+                        _ => Key::PageDown,
+                    }
+                },
+                _ => Key::Escape,
+            },
+            _ => Key::Escape,
+        },
+        byte => Key::Char(byte as char),
+    }))
+}
+
+fn read_line_key() -> io::Result<Key> {
+    let mut line = String::new();
+    if stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(Key::Char('q')); // end of input
+    }
+    Ok(match line.trim_end_matches(['\r', '\n']).chars().next() {
+        Some(char) => Key::Char(char),
+        None => Key::Enter,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lone_escapes_do_not_swallow_keys() {
+        // An empty read means no byte arrived in time
+        let keys = |mut input: &[u8]| {
+            let mut keys = Vec::new();
+            while let Some(key) = read_raw_key(&mut input).unwrap() {
+                keys.push(key);
+            }
+            keys
+        };
+        assert_eq!(keys(b"\x1B[A\x1B[6~q"), [
+            Key::Up,
+            Key::PageDown,
+            Key::Char('q')
+        ]);
+        assert_eq!(keys(b"\x1B"), [Key::Escape]);
+    }
+}
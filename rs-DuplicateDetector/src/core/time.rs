@@ -0,0 +1,79 @@
+//! Items to display points in time, without pulling in a date library.
+
+use std::fmt;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Clone, Copy)]
+/// Displays a point in time as an ISO 8601 date and time in UTC.
+pub struct Timestamp(pub SystemTime);
+
+/// Converts days since the Unix epoch to a (year, month, day) triple.
+///
+/// Based on <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Timestamp(time) = *self;
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(error) => -(error.duration().as_secs() as i64),
+        };
+        let (days, seconds) =
+            (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+        let (hour, minute, second) =
+            (seconds / 3600, seconds / 60 % 60, seconds % 60);
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}Z",
+            year, month, day, hour, minute, second,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Displays a duration like a clock, e.g. `1:02:03` or `2:03`.
+pub struct Clock(pub Duration);
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        let (hours, minutes, seconds) =
+            (seconds / 3600, seconds / 60 % 60, seconds % 60);
+        match hours {
+            0 => write!(f, "{}:{:02}", minutes, seconds),
+            _ => write!(f, "{}:{:02}:{:02}", hours, minutes, seconds),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let at = |secs| Timestamp(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0).to_string(), "1970-01-01 00:00:00Z");
+        assert_eq!(at(951_782_400).to_string(), "2000-02-29 00:00:00Z");
+        assert_eq!(at(1_737_553_445).to_string(), "2025-01-22 13:44:05Z");
+
+        let clock = |secs| Clock(Duration::from_secs(secs)).to_string();
+        assert_eq!(clock(5), "0:05");
+        assert_eq!(clock(3723), "1:02:03");
+    }
+}
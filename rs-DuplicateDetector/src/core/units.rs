@@ -0,0 +1,87 @@
+//! Items to parse and display quantities of bytes, like `64K` or `1.5GiB`.
+
+use std::fmt;
+
+/////////////
+// Parsing //
+/////////////
+
+/// Parses a byte size with an optional binary suffix.
+///
+/// Accepts `B`, `K`, `M`, `G` and `T`, optionally followed by `iB` or `B`.
+/// All suffixes are powers of 1024.
+pub fn parse_bytes(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, suffix) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("'{}' does not start with a number", text))?;
+    let exponent = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        other => return Err(format!("unknown unit '{}'", other)),
+    };
+    Ok((number * 1024f64.powi(exponent)) as u64)
+}
+
+////////////////
+// Formatting //
+////////////////
+
+#[derive(Debug, Clone, Copy)]
+/// Displays a number of bytes using the largest fitting binary unit.
+pub struct Bytes(pub u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+        let Bytes(bytes) = *self;
+        if bytes < 1024 {
+            return write!(f, "{} B", bytes);
+        }
+        let mut value = bytes as f64;
+        let mut unit = "B";
+        for next in UNITS {
+            if value < 1024.0 {
+                break;
+            }
+            value /= 1024.0;
+            unit = next;
+        }
+        write!(f, "{:.1} {}", value, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_with_suffix() {
+        assert_eq!(parse_bytes("0"), Ok(0));
+        assert_eq!(parse_bytes("512"), Ok(512));
+        assert_eq!(parse_bytes("512B"), Ok(512));
+        assert_eq!(parse_bytes("4K"), Ok(4 * 1024));
+        assert_eq!(parse_bytes("4kib"), Ok(4 * 1024));
+        assert_eq!(parse_bytes("1.5M"), Ok(3 * 512 * 1024));
+        assert_eq!(parse_bytes("2 GiB"), Ok(2 * 1024 * 1024 * 1024));
+
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("K").is_err());
+        assert!(parse_bytes("12 parsecs").is_err());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Bytes(0).to_string(), "0 B");
+        assert_eq!(Bytes(1023).to_string(), "1023 B");
+        assert_eq!(Bytes(1024).to_string(), "1.0 KiB");
+        assert_eq!(Bytes(3 * 512 * 1024).to_string(), "1.5 MiB");
+    }
+}
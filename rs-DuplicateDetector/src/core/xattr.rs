@@ -1,5 +1,8 @@
 //! Provides cross platform support for eXtended Attributes
 //!
+//! On Linux (and other Unixes), attributes live in the `user.` namespace.
+//! Callers pass the bare name; the namespace is prepended for them.
+//!
 //! Note that on Windows, EA have very poor documentation;
 //! NTFS ADS is used instead.
 
@@ -7,23 +10,93 @@ use std::ffi::OsStr;
 use std::io;
 use std::path::Path;
 
-#[cfg(not(windows))]
-compile_error!("this program requires NTFS ADS to store metadata");
+#[cfg(not(any(unix, windows)))]
+compile_error!("this program requires xattrs or NTFS ADS to store metadata");
 
 // Note: Ideally these use Reader/Writer,
 // but that requires more Rust-fu than I have
 
 // Listing xattrs is also not really nice
 // Most xattr libraries don't have a fallback for windows
-// Will probably just be a shim for a proper xattr library...eventually.
+// Hence this shim over the `xattr` crate.
+
+/////////////
+// Backend //
+/////////////
+
+#[cfg(unix)]
+mod backend {
+    use std::ffi::OsStr;
+    use std::ffi::OsString;
+    use std::io;
+    use std::path::Path;
+
+    /// Namespace which unprivileged processes are allowed to write to.
+    const NAMESPACE: &str = "user.";
+
+    fn qualify(name: &OsStr) -> OsString {
+        let mut result = OsString::from(NAMESPACE);
+        result.push(name);
+        result
+    }
+
+    pub fn get(path: &Path, name: &OsStr) -> io::Result<Vec<u8>> {
+        match xattr::get(path, qualify(name))? {
+            Some(value) => Ok(value),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    pub fn set(path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        xattr::set(path, qualify(name), value)
+    }
+
+    pub fn delete(path: &Path, name: &OsStr) -> io::Result<()> {
+        xattr::remove(path, qualify(name))
+    }
+}
+
+#[cfg(windows)]
+mod backend {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    /// Path of the alternate data stream, i.e. `file.txt:name`.
+    fn stream(path: &Path, name: &OsStr) -> PathBuf {
+        let mut result = path.as_os_str().to_owned();
+        result.push(":");
+        result.push(name);
+        result.into()
+    }
+
+    pub fn get(path: &Path, name: &OsStr) -> io::Result<Vec<u8>> {
+        fs::read(stream(path, name))
+    }
+
+    pub fn set(path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        fs::write(stream(path, name), value)
+    }
+
+    pub fn delete(path: &Path, name: &OsStr) -> io::Result<()> {
+        fs::remove_file(stream(path, name))
+    }
+}
+
+////////////
+// Public //
+////////////
 
 /// Retrieves an extended attribute from a file.
+///
+/// Fails with [`io::ErrorKind::NotFound`] if the attribute is not set.
 pub fn get_xattr(
     path: impl AsRef<Path>,
     name: impl AsRef<OsStr>,
 ) -> io::Result<Vec<u8>> {
-    fn inner(_path: &Path, _name: &OsStr) -> io::Result<Vec<u8>> { todo!() }
-    inner(path.as_ref(), name.as_ref())
+    backend::get(path.as_ref(), name.as_ref())
 }
 
 /// Sets an extended attribute on a file.
@@ -32,10 +105,7 @@ pub fn set_xattr(
     name: impl AsRef<OsStr>,
     value: &[u8],
 ) -> io::Result<()> {
-    fn inner(_path: &Path, _name: &OsStr, _value: &[u8]) -> io::Result<()> {
-        todo!()
-    }
-    inner(path.as_ref(), name.as_ref(), value)
+    backend::set(path.as_ref(), name.as_ref(), value)
 }
 
 /// Removes an extended attribute from a file.
@@ -43,6 +113,33 @@ pub fn delete_xattr(
     path: impl AsRef<Path>,
     name: impl AsRef<OsStr>,
 ) -> io::Result<()> {
-    fn inner(_path: &Path, _name: &OsStr) -> io::Result<()> { todo!() }
-    inner(path.as_ref(), name.as_ref())
+    backend::delete(path.as_ref(), name.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-xattr", std::process::id()));
+        fs::write(&path, "contents").unwrap();
+        let set = set_xattr(&path, "test", b"value");
+        let got = get_xattr(&path, "test");
+        let deleted = delete_xattr(&path, "test");
+        let missing = get_xattr(&path, "test");
+        fs::remove_file(&path).unwrap();
+
+        // Not every file system (e.g. tmpfs before Linux 6.6) supports these
+        if set.as_ref().is_err_and(|e| e.kind() == io::ErrorKind::Unsupported) {
+            return;
+        }
+        set.unwrap();
+        assert_eq!(got.unwrap(), b"value");
+        deleted.unwrap();
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
 }
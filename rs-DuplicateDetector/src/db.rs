@@ -4,11 +4,73 @@ use std::collections::HashMap;
 use std::ops::Deref;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::archive::Listing;
+use crate::connection::Journal;
+use crate::fingerprint::Fingerprint;
 use crate::hash::FileHash;
+use crate::hash::HashAlgorithm;
+use crate::similar::ImageHash;
+use crate::similar::ImageHashKind;
+
+////////////
+// Record //
+////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Everything known about a single file.
+pub struct Record {
+    /// The fingerprint of the file at the moment it was hashed.
+    pub fingerprint: Fingerprint,
+    /// The hash of the file.
+    pub hash: FileHash,
+}
+
+impl Record {
+    /// Whether this record still describes a file with the given fingerprint,
+    /// using a hash created by the given algorithm.
+    pub fn is_fresh(
+        &self,
+        current: &Fingerprint,
+        algorithm: HashAlgorithm,
+    ) -> bool {
+        self.fingerprint == *current && self.hash.algorithm() == algorithm
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// What an image looks like, so it need not be decoded again.
+pub struct ImageRecord {
+    /// The fingerprint of the file at the moment it was hashed.
+    pub fingerprint: Fingerprint,
+    /// How the image was hashed.
+    pub kind: ImageHashKind,
+    /// The perceptual hash of the image.
+    pub hash: ImageHash,
+}
+
+impl ImageRecord {
+    /// Whether this record still describes a file with the given fingerprint,
+    /// using a hash of the given kind.
+    pub fn is_fresh(&self, current: &Fingerprint, kind: ImageHashKind) -> bool {
+        self.fingerprint == *current && self.kind == kind
+    }
+}
+
+//////////
+// Root //
+//////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Everything known about a directory that was searched.
+pub struct RootRecord {
+    /// When the directory was last searched.
+    pub last_scanned: SystemTime,
+}
 
 //////////////
 // Database //
@@ -16,30 +78,185 @@ use crate::hash::FileHash;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 /// Stores the mapping between path to files and those files' hashes.
+///
+/// Paths are absolute, so the database can be shared between directories.
 pub struct Database {
-    files: HashMap<PathBuf, FileHash>,
+    files: HashMap<PathBuf, Record>,
+    /// The directories searched, so records can be aged.
+    // NB: Older caches lack this field
+    #[serde(default)]
+    roots: HashMap<PathBuf, RootRecord>,
+    /// When the hash of each file was last found to still match.
+    // NB: Older caches lack this field
+    #[serde(default)]
+    verified: HashMap<PathBuf, SystemTime>,
+    /// The partial hash of files, so files ruled out are not read again.
+    // NB: Older caches lack this field
+    #[serde(default)]
+    partial: HashMap<PathBuf, Record>,
+    /// The perceptual hash of images, so they are not decoded again.
+    // NB: Older caches lack this field
+    #[serde(default)]
+    images: HashMap<PathBuf, ImageRecord>,
+    /// The members of archives, so archives are not listed again.
+    // NB: Older caches lack this field
+    #[serde(default)]
+    listings: HashMap<PathBuf, Listing>,
 }
 
 impl Database {
-    /// Adds a record to this database.
-    pub fn add(&mut self, path: PathBuf, hash: FileHash) {
+    /// Adds a record to this database, replacing any previous record.
+    pub fn add(&mut self, path: PathBuf, record: Record) {
         // TODO: Check for absolute path
-        self.files.insert(path, hash);
+        self.verified.remove(&path);
+        self.files.insert(path, record);
+    }
+
+    /// Adds the partial hash of a file, replacing any previous one.
+    pub fn add_partial(&mut self, path: PathBuf, record: Record) {
+        self.partial.insert(path, record);
+    }
+
+    /// Adds the perceptual hash of an image, replacing any previous one.
+    pub fn add_image(&mut self, path: PathBuf, record: ImageRecord) {
+        self.images.insert(path, record);
+    }
+
+    /// Adds the members of an archive, replacing any previous listing.
+    pub fn add_listing(&mut self, path: PathBuf, listing: Listing) {
+        self.listings.insert(path, listing);
     }
 
-    /// Removes a record from this database.
-    pub fn remove(&mut self, path: &Path) { self.files.remove(path); }
+    /// Removes everything known about a path from this database.
+    pub fn remove(&mut self, path: &Path) {
+        self.files.remove(path);
+        self.verified.remove(path);
+        self.partial.remove(path);
+        self.images.remove(path);
+        self.listings.remove(path);
+    }
 
     /// Clears the entire database.
-    pub fn clear(&mut self) { self.files.clear() }
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.roots.clear();
+        self.verified.clear();
+        self.partial.clear();
+        self.images.clear();
+        self.listings.clear();
+    }
+
+    /// Retrieves the record for the given path, if any.
+    pub fn get(&self, path: &Path) -> Option<&Record> { self.files.get(path) }
+
+    /// Retrieves the partial hash of the given path, if any.
+    pub fn get_partial(&self, path: &Path) -> Option<&Record> {
+        self.partial.get(path)
+    }
+
+    /// Retrieves the perceptual hash of the given path, if any.
+    pub fn get_image(&self, path: &Path) -> Option<&ImageRecord> {
+        self.images.get(path)
+    }
+
+    /// Retrieves the members of the archive at the given path, if listed.
+    pub fn get_listing(&self, path: &Path) -> Option<&Listing> {
+        self.listings.get(path)
+    }
 
     /// Returns all paths in this database.
     pub fn paths(&self) -> impl Iterator<Item = &Path> {
-        self.files.iter().map(|(path, _)| path.deref())
+        self.files.keys().map(|path| path.deref())
+    }
+
+    /// Returns all paths with a partial hash in this database.
+    pub fn partial_paths(&self) -> impl Iterator<Item = &Path> {
+        self.partial.keys().map(|path| path.deref())
+    }
+
+    /// Returns all paths with a perceptual hash in this database.
+    pub fn image_paths(&self) -> impl Iterator<Item = &Path> {
+        self.images.keys().map(|path| path.deref())
+    }
+
+    /// Returns all archives listed in this database.
+    pub fn listing_paths(&self) -> impl Iterator<Item = &Path> {
+        self.listings.keys().map(|path| path.deref())
     }
 
     /// Returns all records in this database.
+    pub fn records(&self) -> impl Iterator<Item = (&Path, &Record)> {
+        self.files.iter().map(|(path, record)| (path.deref(), record))
+    }
+
+    /// Returns the hash of every path in this database.
     pub fn entries(&self) -> impl Iterator<Item = (&Path, &FileHash)> {
-        self.files.iter().map(|(path, hash)| (path.deref(), hash))
+        self.records().map(|(path, record)| (path, &record.hash))
+    }
+
+    /// Returns all directories searched.
+    pub fn roots(&self) -> impl Iterator<Item = (&Path, &RootRecord)> {
+        self.roots.iter().map(|(path, root)| (path.deref(), root))
+    }
+
+    /// Returns when the given path was last seen,
+    /// i.e. the last time a directory containing it was searched.
+    pub fn last_seen(&self, path: &Path) -> Option<SystemTime> {
+        self.roots()
+            .filter(|(root, _)| path.starts_with(root))
+            .map(|(_, record)| record.last_scanned)
+            .max()
+    }
+
+    /// Returns when the hash of the file was last verified, if ever.
+    pub fn last_verified(&self, path: &Path) -> Option<SystemTime> {
+        self.verified.get(path).copied()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A change to a [`Database`].
+pub enum Change {
+    /// Adds a record, replacing any previous record.
+    Add(PathBuf, Record),
+    /// Adds the partial hash of a file, replacing any previous one.
+    AddPartial(PathBuf, Record),
+    /// Removes everything known about a path.
+    Remove(PathBuf),
+    /// Notes a directory was searched at the given time.
+    ScanRoot(PathBuf, SystemTime),
+    /// Forgets a directory was ever searched.
+    /// Does not remove the records inside it.
+    ForgetRoot(PathBuf),
+    /// Notes the hash of a file still matched its contents at the given time.
+    Verify(PathBuf, SystemTime),
+    /// Adds the perceptual hash of an image, replacing any previous one.
+    AddImage(PathBuf, ImageRecord),
+    /// Adds the members of an archive, replacing any previous listing.
+    AddListing(PathBuf, Listing),
+}
+
+impl Journal for Database {
+    type Change = Change;
+
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Add(path, record) => self.add(path, record),
+            Change::AddPartial(path, record) => self.add_partial(path, record),
+            Change::Remove(path) => self.remove(&path),
+            Change::ScanRoot(path, last_scanned) => {
+                self.roots.insert(path, RootRecord { last_scanned });
+            },
+            Change::ForgetRoot(path) => _ = self.roots.remove(&path),
+            Change::Verify(path, at) => {
+                if self.files.contains_key(&path) {
+                    self.verified.insert(path, at);
+                }
+            },
+            Change::AddImage(path, record) => self.add_image(path, record),
+            Change::AddListing(path, listing) => {
+                self.add_listing(path, listing)
+            },
+        }
     }
 }
@@ -0,0 +1,271 @@
+//! Items to select which files to search for duplicates.
+//!
+//! Files can be selected by:
+//! - glob patterns passed as options;
+//! - their size;
+//! - `.dupignore` files (gitignore syntax) in any of the walked directories.
+
+use std::array;
+use std::path::Path;
+use std::rc::Rc;
+
+use anyhow::Context;
+use globset::Glob;
+use globset::GlobBuilder;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use ignore::Match;
+use ignore::gitignore::Gitignore;
+
+/// Name of the file with additional ignore rules for a directory.
+pub const IGNORE_FILE_NAME: &str = ".dupignore";
+
+/////////////
+// Options //
+/////////////
+
+#[derive(Debug, Default, Clone)]
+/// Options for selecting files.
+pub struct FilterOptions {
+    /// Only search files matching any of these globs.
+    /// Searches all files if empty.
+    pub include: Vec<String>,
+    /// Skip files and directories matching any of these globs.
+    pub exclude: Vec<String>,
+    /// Skip files smaller than this size (in bytes).
+    pub min_size: Option<u64>,
+    /// Skip files larger than this size (in bytes).
+    pub max_size: Option<u64>,
+}
+
+//////////////
+// GlobList //
+//////////////
+
+#[derive(Debug)]
+/// Globs matched against the file name, or against the relative path.
+struct Globs {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Globs {
+    fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty()
+    }
+
+    fn is_match(&self, relative: &Path) -> bool {
+        let name_matches = relative
+            .file_name()
+            .is_some_and(|name| self.names.is_match(Path::new(name)));
+        name_matches || self.paths.is_match(relative)
+    }
+}
+
+#[derive(Debug)]
+/// A set of globs, following the conventions of most search tools:
+/// - globs without a `/` are matched against the file name;
+/// - other globs are matched against the path relative to the root;
+/// - globs ending in a `/` only match directories (and what is inside),
+///   as in `.gitignore`.
+struct GlobList {
+    any: Globs,
+    dirs: Globs,
+}
+
+impl GlobList {
+    fn new(globs: &[String]) -> crate::Result<Self> {
+        // Indexed by whether the glob only matches directories
+        let mut builders: [[GlobSetBuilder; 2]; 2] =
+            array::from_fn(|_| [GlobSetBuilder::new(), GlobSetBuilder::new()]);
+        for glob in globs {
+            let error = || format!("invalid glob '{}'", glob);
+            let (glob, is_dir) = match glob.strip_suffix('/') {
+                Some(glob) => (glob, true),
+                None => (glob.as_str(), false),
+            };
+            let [names, paths] = &mut builders[is_dir as usize];
+            if glob.contains('/') {
+                let glob = glob.trim_start_matches('/');
+                paths.add(
+                    GlobBuilder::new(glob)
+                        .literal_separator(true)
+                        .build()
+                        .with_context(error)?,
+                );
+            } else {
+                names.add(Glob::new(glob).with_context(error)?);
+            }
+        }
+        let [[names, paths], [dir_names, dir_paths]] = builders;
+        Ok(GlobList {
+            any: Globs { names: names.build()?, paths: paths.build()? },
+            dirs: Globs {
+                names: dir_names.build()?,
+                paths: dir_paths.build()?,
+            },
+        })
+    }
+
+    fn is_empty(&self) -> bool { self.any.is_empty() && self.dirs.is_empty() }
+
+    fn is_match(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        // Directory globs also match the directories the path is in
+        let mut dirs = relative.ancestors().skip(usize::from(!is_dir));
+        self.any.is_match(relative) ||
+            (!self.dirs.is_empty() &&
+                dirs.any(|dir| {
+                    !dir.as_os_str().is_empty() && self.dirs.is_match(dir)
+                }))
+    }
+}
+
+////////////
+// Filter //
+////////////
+
+#[derive(Debug)]
+/// Compiled form of [`FilterOptions`].
+pub struct Filter {
+    include: GlobList,
+    exclude: GlobList,
+    min_size: u64,
+    max_size: u64,
+}
+
+impl Filter {
+    /// Compiles the globs in the options.
+    pub fn new(
+        FilterOptions { include, exclude, min_size, max_size }: &FilterOptions,
+    ) -> crate::Result<Self> {
+        Ok(Filter {
+            include: GlobList::new(include)?,
+            exclude: GlobList::new(exclude)?,
+            min_size: min_size.unwrap_or(u64::MIN),
+            max_size: max_size.unwrap_or(u64::MAX),
+        })
+    }
+
+    /// Whether to descend into the given directory.
+    pub fn accepts_dir(&self, root: &Path, path: &Path) -> bool {
+        !self.exclude.is_match(root, path, true)
+    }
+
+    /// Whether to search the given file.
+    pub fn accepts_file(&self, root: &Path, path: &Path, size: u64) -> bool {
+        (self.min_size..=self.max_size).contains(&size) &&
+            (self.include.is_empty() ||
+                self.include.is_match(root, path, false)) &&
+            !self.exclude.is_match(root, path, false)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(&FilterOptions::default()).expect("no globs to fail on")
+    }
+}
+
+//////////////////
+// Ignore files //
+//////////////////
+
+#[derive(Debug, Default, Clone)]
+/// The `.dupignore` files that apply to a directory,
+/// from the directory itself up to the root.
+pub struct IgnoreStack {
+    // NB: Shared with the parent directory; pushing does not affect it.
+    files: Vec<Rc<Gitignore>>,
+}
+
+impl IgnoreStack {
+    /// Returns the stack for a subdirectory,
+    /// adding its `.dupignore` file if there is one.
+    pub fn enter(&self, dir: &Path) -> crate::Result<Self> {
+        let path = dir.join(IGNORE_FILE_NAME);
+        if !path.is_file() {
+            return Ok(self.clone());
+        }
+        let (gitignore, error) = Gitignore::new(&path);
+        if let Some(error) = error {
+            return Err(error)
+                .context(format!("failed to parse '{}'", path.display()));
+        }
+        let mut result = self.clone();
+        result.files.push(Rc::new(gitignore));
+        Ok(result)
+    }
+
+    /// Whether the path is ignored.
+    /// Rules in deeper directories take precedence.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gitignore in self.files.iter().rev() {
+            match gitignore.matched(path, is_dir) {
+                Match::Ignore(..) => return true,
+                Match::Whitelist(..) => return false,
+                Match::None => {},
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn globs_match_names_paths_and_directories() {
+        let options = FilterOptions {
+            include: vec!["*.jpg".into(), "docs/".into()],
+            exclude: vec!["node_modules/".into(), "/a/*.tmp".into()],
+            min_size: Some(1),
+            max_size: None,
+        };
+        let filter = Filter::new(&options).unwrap();
+        let root = Path::new("/r");
+        let file = |path: &str| filter.accepts_file(root, Path::new(path), 10);
+
+        assert!(file("/r/x/photo.jpg"));
+        assert!(file("/r/x/docs/notes.txt"));
+        assert!(!file("/r/x/notes.txt"));
+        assert!(!filter.accepts_file(root, Path::new("/r/photo.jpg"), 0));
+
+        // A trailing slash only matches directories, at any depth
+        assert!(!filter.accepts_dir(root, Path::new("/r/x/node_modules")));
+        assert!(!file("/r/node_modules/y/photo.jpg"));
+        let exclude = Filter::new(&FilterOptions {
+            exclude: options.exclude.clone(),
+            ..FilterOptions::default()
+        })
+        .unwrap();
+        assert!(exclude.accepts_file(root, Path::new("/r/node_modules"), 1));
+
+        // Globs with a slash are relative to the root
+        assert!(!exclude.accepts_file(root, Path::new("/r/a/x.tmp"), 1));
+        assert!(exclude.accepts_file(root, Path::new("/r/b/a/x.tmp"), 1));
+    }
+
+    #[test]
+    fn deeper_ignore_files_take_precedence() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-ignore", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join(IGNORE_FILE_NAME), "*.log\ncache/\n").unwrap();
+        fs::write(dir.join("sub").join(IGNORE_FILE_NAME), "!keep.log\n")
+            .unwrap();
+
+        let top = IgnoreStack::default().enter(&dir).unwrap();
+        let sub = top.enter(&dir.join("sub")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(top.is_ignored(&dir.join("keep.log"), false));
+        assert!(!sub.is_ignored(&dir.join("sub/keep.log"), false));
+        assert!(sub.is_ignored(&dir.join("sub/other.log"), false));
+        assert!(sub.is_ignored(&dir.join("sub/cache"), true));
+        assert!(!sub.is_ignored(&dir.join("sub/cache"), false));
+    }
+}
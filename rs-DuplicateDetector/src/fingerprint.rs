@@ -0,0 +1,82 @@
+//! Items to detect whether a file changed since it was last hashed.
+
+use std::fs;
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/////////////
+// File ID //
+/////////////
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize
+)]
+/// Identifies a file independent of its path.
+pub struct FileId {
+    /// The device the file resides on.
+    pub device: u64,
+    /// The inode of the file on that device.
+    pub inode: u64,
+}
+
+impl FileId {
+    #[cfg(unix)]
+    /// Retrieves the identity of a file from its metadata.
+    pub fn from_metadata(stat: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(FileId { device: stat.dev(), inode: stat.ino() })
+    }
+
+    #[cfg(not(unix))]
+    /// Retrieves the identity of a file from its metadata.
+    pub fn from_metadata(_stat: &Metadata) -> Option<Self> {
+        // MetadataExt::file_index is still unstable on Windows
+        None
+    }
+}
+
+/////////////////
+// Fingerprint //
+/////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Summary of the metadata of a file.
+/// If the fingerprint changed, the contents (probably) changed too.
+pub struct Fingerprint {
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Time of last modification.
+    pub modified: SystemTime,
+    /// Identity of the file, if the platform supports it.
+    pub id: Option<FileId>,
+}
+
+impl Fingerprint {
+    /// Creates a fingerprint from the metadata of a file.
+    pub fn from_metadata(stat: &Metadata) -> io::Result<Self> {
+        Ok(Fingerprint {
+            size: stat.len(),
+            modified: stat.modified()?,
+            id: FileId::from_metadata(stat),
+        })
+    }
+
+    /// Creates a fingerprint of the file at the given path.
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        Self::from_metadata(&fs::metadata(path)?)
+    }
+}
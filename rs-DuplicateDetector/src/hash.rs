@@ -2,8 +2,21 @@
 
 use std::fmt;
 use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
 use std::io::copy;
+use std::io::sink;
+use std::num::NonZero;
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use clap::ValueEnum;
 use serde::Deserialize;
@@ -11,16 +24,66 @@ use serde::Serialize;
 use sha2::Digest;
 use sha2::Sha256;
 use strum::Display;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::archive::is_member;
+use crate::archive::read_member;
+
+///////////////
+// Algorithm //
+///////////////
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    ValueEnum,
+    Display
+)]
+#[clap(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+/// Which algorithm to hash files with.
+pub enum HashAlgorithm {
+    #[default]
+    /// SHA-256. Slow but widely available.
+    Sha256,
+    /// BLAKE3. Cryptographic, but much faster than SHA-256.
+    Blake3,
+    /// XXH3 (128-bit). Very fast, but not cryptographic.
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    /// Number of bytes in a hash created by this algorithm.
+    pub fn byte_size(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Blake3 => 32,
+            Self::Xxh3 => 16,
+        }
+    }
+}
 
 //////////////
 // FileHash //
 //////////////
 
-type HashingAlgo = Sha256;
-// TODO: Can we use ↑ to derive ↓?
-const HASH_BYTE_SIZE: usize = 32;
+/// Size of the largest hash of all [`HashAlgorithm`]s.
+const MAX_HASH_BYTE_SIZE: usize = 32;
 
-/// The hashed contents of a file.
+/// The hashed contents of a file,
+/// tagged with the algorithm that produced it.
+///
+/// Hashes produced by different algorithms never compare equal.
 #[derive(
     Debug,
     Clone,
@@ -33,21 +96,38 @@ const HASH_BYTE_SIZE: usize = 32;
     Serialize,
     Deserialize
 )]
-#[repr(transparent)]
-#[serde(transparent)] // TODO: Store it as a hex string, not as an array of bytes
+#[serde(try_from = "TaggedBytes", into = "TaggedBytes")]
+// TODO: Store it as a hex string, not as an array of bytes
 pub struct FileHash {
-    #[serde(with = "serde_bytes", rename = "hash")]
-    bytes: [u8; HASH_BYTE_SIZE],
+    algorithm: HashAlgorithm,
+    /// Invariant: bytes past `algorithm.byte_size()` are zero.
+    bytes: [u8; MAX_HASH_BYTE_SIZE],
 }
 
 impl FileHash {
+    /// Creates a hash from the output of the given algorithm.
+    ///
+    /// Returns [`None`] if the length doesn't match the algorithm.
+    pub fn new(algorithm: HashAlgorithm, digest: &[u8]) -> Option<Self> {
+        let size = algorithm.byte_size();
+        if digest.len() != size {
+            return None;
+        }
+        let mut bytes = [0; MAX_HASH_BYTE_SIZE];
+        bytes[..size].copy_from_slice(digest);
+        Some(FileHash { algorithm, bytes })
+    }
+
+    /// Returns the algorithm that produced this hash.
+    pub fn algorithm(&self) -> HashAlgorithm { self.algorithm }
+
     /// Returns the bytes of this hash.
-    pub fn bytes(&self) -> &[u8] { &self.bytes }
+    pub fn bytes(&self) -> &[u8] { &self.bytes[..self.algorithm.byte_size()] }
 }
 
 impl fmt::Display for FileHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for byte in self.bytes {
+        for byte in self.bytes() {
             // Prefix with 0 to ensure the entire byte is printed
             write!(f, "{byte:02X}")?;
         }
@@ -55,27 +135,292 @@ impl fmt::Display for FileHash {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+/// Serialized form of [`FileHash`].
+struct TaggedBytes {
+    algorithm: HashAlgorithm,
+    #[serde(with = "serde_bytes")]
+    hash: Vec<u8>,
+}
+
+impl From<FileHash> for TaggedBytes {
+    fn from(value: FileHash) -> Self {
+        TaggedBytes { algorithm: value.algorithm, hash: value.bytes().to_vec() }
+    }
+}
+
+impl TryFrom<TaggedBytes> for FileHash {
+    type Error = String;
+    fn try_from(
+        TaggedBytes { algorithm, hash }: TaggedBytes,
+    ) -> Result<Self, Self::Error> {
+        FileHash::new(algorithm, &hash).ok_or_else(|| {
+            format!(
+                "{} hash must be {} bytes",
+                algorithm,
+                algorithm.byte_size()
+            )
+        })
+    }
+}
+
+////////////
+// Extent //
+////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How much of a file to hash.
+pub enum HashExtent {
+    /// Only the head and tail of the file.
+    /// Cheap to compute; used to quickly rule out files.
+    Partial,
+    /// The entire contents of the file.
+    Full,
+}
+
+impl HashExtent {
+    /// Number of bytes read from both the head and tail of a file
+    /// when computing a partial hash.
+    pub const PARTIAL_BYTES: u64 = 4 * 1024;
+
+    /// Whether the partial hash of a file with the given size
+    /// covers its entire contents, i.e. equals the full hash.
+    pub fn is_partial_complete(size: u64) -> bool {
+        size <= 2 * Self::PARTIAL_BYTES
+    }
+
+    /// Number of bytes hashed from a file with the given size.
+    pub fn bytes_read(self, size: u64) -> u64 {
+        match self {
+            Self::Partial => size.min(2 * Self::PARTIAL_BYTES),
+            Self::Full => size,
+        }
+    }
+}
+
 ///////////////////
 // Multi-hashing //
 ///////////////////
 
+/// Hasher state of the chosen algorithm.
+enum HasherState {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl HasherState {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Default::default()),
+            HashAlgorithm::Xxh3 => Self::Xxh3(Default::default()),
+        }
+    }
+
+    fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            Self::Sha256(..) => HashAlgorithm::Sha256,
+            Self::Blake3(..) => HashAlgorithm::Blake3,
+            Self::Xxh3(..) => HashAlgorithm::Xxh3,
+        }
+    }
+
+    fn finalize_reset(&mut self) -> FileHash {
+        let algorithm = self.algorithm();
+        let hash = match self {
+            Self::Sha256(h) => FileHash::new(algorithm, &h.finalize_reset()),
+            Self::Blake3(h) => {
+                let digest = h.finalize();
+                h.reset();
+                FileHash::new(algorithm, digest.as_bytes())
+            },
+            Self::Xxh3(h) => {
+                let digest = h.digest128();
+                h.reset();
+                FileHash::new(algorithm, &digest.to_be_bytes())
+            },
+        };
+        hash.expect("digest size must match algorithm")
+    }
+}
+
+impl Write for HasherState {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Sha256(h) => h.update(buf),
+            Self::Blake3(h) => _ = h.update(buf),
+            Self::Xxh3(h) => h.update(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
 /// Re-usable file hasher.
 pub struct FileHasher {
-    hasher: HashingAlgo,
+    hasher: HasherState,
+    buffer: Vec<u8>,
+    /// Counts the bytes hashed, to report progress from other threads.
+    progress: Option<Arc<AtomicU64>>,
+    /// Limits how fast files are read, if at all.
+    rate_limit: Option<Arc<RateLimit>>,
 }
 
 impl FileHasher {
-    /// Creates a new re-usable file hasher.
-    pub fn new() -> Self { FileHasher { hasher: HashingAlgo::new() } }
+    /// Size of the read buffer, unless specified otherwise.
+    pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+    /// Largest read buffer allowed; each thread allocates its own.
+    pub const MAX_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+
+    /// Creates a new re-usable file hasher using the given algorithm.
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        FileHasher {
+            hasher: HasherState::new(algorithm),
+            buffer: vec![0; Self::DEFAULT_BUFFER_SIZE],
+            progress: None,
+            rate_limit: None,
+        }
+    }
+
+    /// Sets the number of bytes read from a file at once,
+    /// up to [`Self::MAX_BUFFER_SIZE`].
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.buffer = vec![0; size.clamp(1, Self::MAX_BUFFER_SIZE)];
+        self
+    }
+
+    /// Adds the number of bytes hashed to the counter, as they are hashed.
+    pub fn with_progress(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.progress = Some(counter);
+        self
+    }
+
+    /// Reads no faster than the limit, which may be shared with others.
+    pub fn with_rate_limit(mut self, limit: Option<Arc<RateLimit>>) -> Self {
+        self.rate_limit = limit;
+        self
+    }
+
+    /// Feeds everything the reader yields to the hasher, chunk by chunk.
+    fn update(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        loop {
+            let len = match reader.read(&mut self.buffer) {
+                Ok(0) => return Ok(()),
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.hasher.write_all(&self.buffer[..len])?;
+            if let Some(progress) = &self.progress {
+                progress.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            if let Some(limit) = &self.rate_limit {
+                limit.consume(len as u64);
+            }
+        }
+    }
+}
+
+impl Default for FileHasher {
+    fn default() -> Self { Self::new(HashAlgorithm::default()) }
+}
+
+impl FileHasher {
+    /// Creates a hash from everything the reader yields.
+    pub fn from_reader(
+        &mut self,
+        reader: &mut dyn Read,
+    ) -> crate::Result<FileHash> {
+        self.update(reader)?;
+        Ok(self.hasher.finalize_reset())
+    }
 
     /// Creates a hash from the contents of the file at the given path.
+    /// The path may be the virtual path of a file inside an archive.
     pub fn from_contents(&mut self, path: &Path) -> crate::Result<FileHash> {
+        if is_member(path) {
+            return read_member(path, |reader, _| self.from_reader(reader));
+        }
+        self.from_reader(&mut File::open(path)?)
+    }
+
+    /// Creates a hash from the head and tail of the file at the given path.
+    /// Small files are hashed entirely.
+    pub fn from_partial_contents(
+        &mut self,
+        path: &Path,
+    ) -> crate::Result<FileHash> {
+        const PARTIAL_BYTES: u64 = HashExtent::PARTIAL_BYTES;
+        if is_member(path) {
+            // Members cannot seek, so the middle is read and discarded
+            return read_member(path, |reader, size| {
+                if !HashExtent::is_partial_complete(size) {
+                    let middle = size - 2 * PARTIAL_BYTES;
+                    self.update(&mut (&mut *reader).take(PARTIAL_BYTES))?;
+                    copy(&mut (&mut *reader).take(middle), &mut sink())?;
+                }
+                self.update(reader)?;
+                Ok(self.hasher.finalize_reset())
+            });
+        }
         let mut file = File::open(path)?;
-        copy(&mut file, &mut self.hasher)?;
+        let size = file.metadata()?.len();
+        if HashExtent::is_partial_complete(size) {
+            self.update(&mut file)?;
+        } else {
+            self.update(&mut (&mut file).take(PARTIAL_BYTES))?;
+            file.seek(SeekFrom::End(-(PARTIAL_BYTES as i64)))?;
+            self.update(&mut file)?;
+        }
+        Ok(self.hasher.finalize_reset())
+    }
+
+    /// Hashes the given extent of the file at the given path.
+    pub fn hash(
+        &mut self,
+        path: &Path,
+        extent: HashExtent,
+    ) -> crate::Result<FileHash> {
+        match extent {
+            HashExtent::Partial => self.from_partial_contents(path),
+            HashExtent::Full => self.from_contents(path),
+        }
+    }
+}
 
-        // let now = SystemTime::now();
-        let digest = self.hasher.finalize_reset();
-        Ok(FileHash { bytes: digest.into() })
+////////////////
+// Rate Limit //
+////////////////
+
+#[derive(Debug)]
+/// Limits the number of bytes read per second, across threads.
+pub struct RateLimit {
+    bytes_per_second: NonZero<u64>,
+    started: Instant,
+    bytes: AtomicU64,
+}
+
+impl RateLimit {
+    /// Creates a limit, starting now.
+    pub fn new(bytes_per_second: NonZero<u64>) -> Self {
+        RateLimit {
+            bytes_per_second,
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that bytes were read,
+    /// then sleeps until all bytes read so far are within the limit.
+    pub fn consume(&self, bytes: u64) {
+        let total = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let rate = self.bytes_per_second.get() as f64;
+        let due = Duration::from_secs_f64(total as f64 / rate);
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(wait);
+        }
     }
 }
 
@@ -106,3 +451,35 @@ impl HashStyle {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_round_trip_with_their_length() {
+        for algorithm in
+            [HashAlgorithm::Sha256, HashAlgorithm::Blake3, HashAlgorithm::Xxh3]
+        {
+            let digest = vec![7; algorithm.byte_size()];
+            let hash = FileHash::new(algorithm, &digest).unwrap();
+            let bytes = rmp_serde::to_vec(&hash).unwrap();
+            assert_eq!(
+                rmp_serde::from_slice::<FileHash>(&bytes).unwrap(),
+                hash
+            );
+            assert_eq!(hash.bytes(), digest);
+        }
+
+        // An XXH3 hash is too short for SHA-256, and vice versa
+        assert_eq!(FileHash::new(HashAlgorithm::Sha256, &[7; 16]), None);
+        let truncated = rmp_serde::to_vec(&TaggedBytes {
+            algorithm: HashAlgorithm::Sha256,
+            hash: vec![7; 16],
+        })
+        .unwrap();
+        assert!(rmp_serde::from_slice::<FileHash>(&truncated).is_err());
+        let json = r#"{"algorithm":"xxh3","hash":[1,2,3]}"#;
+        assert!(serde_json::from_str::<FileHash>(json).is_err());
+    }
+}
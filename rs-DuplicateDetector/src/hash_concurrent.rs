@@ -1,17 +1,23 @@
 //! Items to compute the hash of a set of files, concurrently.
 
-use std::io::stderr;
+use std::cmp::Reverse;
 use std::num::NonZero;
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
-use std::time::Instant;
 
-use crate::core::error::sequence;
 use crate::hash::FileHash;
 use crate::hash::FileHasher;
-use crate::status_line::StatusLine;
+use crate::hash::HashAlgorithm;
+use crate::hash::HashExtent;
+use crate::hash::RateLimit;
+use crate::progress::ProgressSink;
+use crate::progress::Stage;
+use crate::progress::track;
+use crate::skip::Skipped;
 
 ///////////////////////////
 // Parameters and return //
@@ -19,113 +25,307 @@ use crate::status_line::StatusLine;
 
 type Item<'a> = (&'a Path, FileHash);
 
+/// Receives each hash as soon as it is computed.
+pub type OnHash<'f, 'a> = &'f mut (dyn FnMut(&'a Path, FileHash) + Send);
+
+#[derive(Debug, Default)]
+/// The hashes computed, and the files that could not be read.
+pub struct HashResults<'a> {
+    /// The hash of every file that could be read.
+    pub hashes: Vec<Item<'a>>,
+    /// The files that could not be read.
+    pub skipped: Vec<Skipped>,
+}
+
+////////////////
+// Work queue //
+////////////////
+
+#[derive(Debug)]
+/// Items shared by workers, which take them one at a time, largest first.
+/// A huge item thus keeps one worker busy while the others drain the rest.
+pub(crate) struct WorkQueue<T> {
+    items: Vec<T>,
+    next: AtomicUsize,
+}
+
+impl<T> WorkQueue<T> {
+    /// Sorts the items by the given size, largest first.
+    pub(crate) fn new(mut items: Vec<T>, size: impl Fn(&T) -> u64) -> Self {
+        items.sort_by_key(|item| Reverse(size(item)));
+        WorkQueue { items, next: AtomicUsize::new(0) }
+    }
+
+    /// Takes the next item, if any are left.
+    pub(crate) fn next(&self) -> Option<&T> {
+        self.items.get(self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 ////////////
 // Search //
 ////////////
 
 // IDEA:
-// N workers each process a chunk of the files, hashing each file
-// then send the (path, hash) through a channel
-// recv then inserts them into the result
-// recv also writes updates to stderr to show progress
+// Files are sorted by size, largest first, into a shared queue.
+// N workers repeatedly take the next file from the queue and hash it,
+// then send the (path, hash) through a channel;
+// a huge file thus keeps one worker busy while the others drain the rest.
+// recv then passes them on, and inserts them into the result
+// Progress is tracked in bytes rather than files, as workers hash them
 fn algorithm_mpsc<'a>(
-    files: &'a [&'a Path],
-    threads: NonZero<usize>,
-) -> crate::Result<Vec<Item<'a>>> {
-    const UPDATE_PERIOD: Duration = Duration::from_millis(100);
-    const WAIT_PERIOD: Duration = Duration::from_millis(500);
+    files: &[(&'a Path, u64)],
+    extent: HashExtent,
+    HashFilesOptions { threads, algorithm, buffer_size, rate_limit }:
+        HashFilesOptions,
+    progress: &mut dyn ProgressSink,
+    on_hash: OnHash<'_, 'a>,
+) -> HashResults<'a> {
     const CHANNEL_SIZE: usize = 1 << 10;
 
     let file_count = files.len();
 
     if file_count == 0 {
-        return Ok(vec![]);
+        return HashResults::default();
     }
 
-    let worker_count = threads.get();
-    let chunk_size = file_count.div_ceil(worker_count);
-    // NB: Given a>=1 and b>=1,
-    // then ceil(a / b) >= 1
-    let mut results = Vec::with_capacity(file_count);
-
-    thread::scope(|scope| {
-        let (sender, receiver) = mpsc::sync_channel(CHANNEL_SIZE);
-
-        // Worker
-        for files_chunk in files.chunks(chunk_size) {
-            let sender = sender.clone();
-            scope.spawn(move || {
-                let mut hasher = FileHasher::new();
-                for &path in files_chunk {
-                    let message = match hasher.from_contents(path) {
-                        Ok(hash) => Ok((path, hash)),
-                        Err(error) => Err(error.context(format!(
-                            "failed to hash '{}'",
-                            path.display()
-                        ))),
-                    };
-                    if let Err(_) = sender.send(message) {
-                        break;
+    let queue = WorkQueue::new(files.to_vec(), |&(_, size)| size);
+    let total_bytes: u64 =
+        files.iter().map(|&(_, size)| extent.bytes_read(size)).sum();
+    let rate_limit = rate_limit.map(|limit| Arc::new(RateLimit::new(limit)));
+
+    let stage = match extent {
+        HashExtent::Partial => Stage::PartialHash,
+        HashExtent::Full => Stage::FullHash,
+    };
+    let worker_count = threads.get().min(file_count);
+
+    track(stage, file_count, total_bytes, progress, |tracker| {
+        let mut results = HashResults {
+            hashes: Vec::with_capacity(file_count),
+            skipped: Vec::new(),
+        };
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::sync_channel(CHANNEL_SIZE);
+
+            // Worker
+            for _ in 0..worker_count {
+                let sender = sender.clone();
+                let queue = &queue;
+                let rate_limit = rate_limit.clone();
+                scope.spawn(move || {
+                    let mut hasher = FileHasher::new(algorithm)
+                        .with_buffer_size(buffer_size)
+                        .with_progress(tracker.bytes_read())
+                        .with_rate_limit(rate_limit);
+                    while let Some(&(path, _)) = queue.next() {
+                        tracker.start(path);
+                        let message = match hasher.hash(path, extent) {
+                            Ok(hash) => Ok((path, hash)),
+                            Err(error) => Err(Skipped::new(path, error)),
+                        };
+                        if sender.send(message).is_err() {
+                            break;
+                        }
                     }
-                }
-            });
-        }
+                });
+            }
+            drop(sender);
 
-        // Collector
-        let results = &mut results;
-        scope.spawn(move || {
-            let mut status_line = StatusLine::new(stderr().lock());
-            let mut last_update = Instant::now();
-
-            let mut update = |so_far: usize| {
-                let percent = (100 * so_far / file_count).clamp(0, 100);
-                status_line.writeln(&format!(
-                    "hashed {} of {} file(s) ({}%)",
-                    so_far, file_count, percent,
-                ));
-            };
-
-            update(results.len());
-            while let Ok(item) = receiver.recv() {
-                results.push(item);
-                if last_update.elapsed() > UPDATE_PERIOD {
-                    last_update = Instant::now();
-                    update(results.len());
+            // Collector
+            for message in receiver {
+                tracker.finish();
+                match message {
+                    Ok(item) => {
+                        on_hash(item.0, item.1);
+                        results.hashes.push(item);
+                    },
+                    Err(skipped) => results.skipped.push(skipped),
                 }
             }
-            update(results.len());
-
-            thread::sleep(WAIT_PERIOD);
-            status_line.close();
         });
-    });
-    sequence(results)
+        results
+    })
 }
 
 /////////////////////////
 // Choice of algorithm //
 /////////////////////////
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 /// Options for the algorithm.
 pub struct HashFilesOptions {
     /// The number of threads to use.
     pub threads: NonZero<usize>,
+    /// The algorithm to hash files with.
+    pub algorithm: HashAlgorithm,
+    /// The number of bytes read from a file at once.
+    pub buffer_size: usize,
+    /// The number of bytes read per second at most, by all threads together.
+    pub rate_limit: Option<NonZero<u64>>,
 }
 
-/// Hashes multiple files in parallel.
+#[cfg(test)]
+impl HashFilesOptions {
+    /// Hashes on a single thread, with a small buffer and no rate limit.
+    pub(crate) fn for_tests() -> Self {
+        HashFilesOptions {
+            threads: NonZero::new(1).unwrap(),
+            algorithm: HashAlgorithm::Xxh3,
+            buffer_size: 4096,
+            rate_limit: None,
+        }
+    }
+}
+
+/// Hashes (the given extent of) multiple files in parallel.
+///
+/// Files are given with their size, so the largest can be hashed first.
+/// Files that cannot be read are skipped, without affecting the others.
 pub fn parallel_hash_files<'a>(
-    files: &'a [&'a Path],
-    HashFilesOptions { threads }: HashFilesOptions,
-) -> crate::Result<Vec<Item<'a>>> {
-    let result = algorithm_mpsc(files, threads)?;
+    files: &[(&'a Path, u64)],
+    extent: HashExtent,
+    options: HashFilesOptions,
+    progress: &mut dyn ProgressSink,
+) -> HashResults<'a> {
+    parallel_hash_files_with(files, extent, options, progress, &mut |_, _| {})
+}
+
+/// Like [`parallel_hash_files`],
+/// but also passes each hash on as soon as it is computed,
+/// so it can be recorded before the other files are done.
+pub fn parallel_hash_files_with<'a>(
+    files: &[(&'a Path, u64)],
+    extent: HashExtent,
+    options: HashFilesOptions,
+    progress: &mut dyn ProgressSink,
+    on_hash: OnHash<'_, 'a>,
+) -> HashResults<'a> {
+    let result = algorithm_mpsc(files, extent, options, progress, on_hash);
 
     let in_count = files.len();
-    let out_count = result.len();
+    let out_count = result.hashes.len() + result.skipped.len();
     debug_assert_eq!(
         in_count, out_count,
         "input and output count must be equal"
     );
 
-    Ok(result)
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::progress::ProgressEvent;
+    use crate::skip::SkipReason;
+
+    /// Keeps every event.
+    #[derive(Default)]
+    struct Recorder(Vec<ProgressEvent>);
+
+    impl ProgressSink for Recorder {
+        fn report(&mut self, event: ProgressEvent) { self.0.push(event); }
+    }
+
+    #[test]
+    fn reports_bytes_hashed() {
+        let dir = std::env::temp_dir().join(format!(
+            "duplicate-detector-{}-progress",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let (small, large) = (dir.join("small"), dir.join("large"));
+        fs::write(&small, [1; 10]).unwrap();
+        fs::write(&large, [2; 100_000]).unwrap();
+
+        let options = HashFilesOptions {
+            threads: NonZero::new(2).unwrap(),
+            ..HashFilesOptions::for_tests()
+        };
+        let files = [(small.as_path(), 10), (large.as_path(), 100_000)];
+        let mut recorder = Recorder::default();
+        let results = parallel_hash_files(
+            &files,
+            HashExtent::Full,
+            options,
+            &mut recorder,
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(results.hashes.len(), 2);
+
+        let events = recorder.0;
+        assert_eq!(
+            events.first(),
+            Some(&ProgressEvent::Started {
+                stage: Stage::FullHash,
+                files: 2,
+                bytes: 100_010,
+            })
+        );
+        assert_eq!(
+            events.last(),
+            Some(&ProgressEvent::Finished { stage: Stage::FullHash })
+        );
+        let ProgressEvent::Advanced(last) = &events[events.len() - 2] else {
+            panic!("expected progress before finishing");
+        };
+        assert_eq!((last.files_done, last.bytes_done), (2, 100_010));
+        let current = last.current.as_deref();
+        assert!(files.iter().any(|(path, _)| current == Some(path)));
+    }
+
+    #[test]
+    fn hashes_largest_first() {
+        let dir = std::env::temp_dir().join(format!(
+            "duplicate-detector-{}-largest-first",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<_> = [10, 1000, 1, 100]
+            .into_iter()
+            .map(|size| {
+                let path = dir.join(size.to_string());
+                fs::write(&path, vec![0; size]).unwrap();
+                (path, size as u64)
+            })
+            .collect();
+
+        let options = HashFilesOptions::for_tests();
+        let files: Vec<_> =
+            paths.iter().map(|(path, size)| (path.as_path(), *size)).collect();
+        let mut order = Vec::new();
+        parallel_hash_files_with(
+            &files,
+            HashExtent::Full,
+            options,
+            &mut Recorder::default(),
+            &mut |path, _| order.push(path.file_name().unwrap().to_owned()),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(order, ["1000", "100", "10", "1"]);
+    }
+
+    #[test]
+    fn skips_vanished_files() {
+        let path = std::env::temp_dir().join(format!(
+            "duplicate-detector-{}-vanished",
+            std::process::id()
+        ));
+        let options = HashFilesOptions::for_tests();
+        let files = [(path.as_path(), 10)];
+        let results = parallel_hash_files(
+            &files,
+            HashExtent::Partial,
+            options,
+            &mut Recorder::default(),
+        );
+        assert!(results.hashes.is_empty());
+        let [skipped] = results.skipped.as_slice() else { panic!() };
+        assert_eq!(
+            (&skipped.path, skipped.reason),
+            (&path, SkipReason::Vanished)
+        );
+    }
 }
@@ -3,50 +3,100 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod action;
+pub mod archive;
+pub mod cache;
+pub mod compare;
 pub mod connection;
 /// Stuff that should be in [`core`], but isn't.
 pub mod core {
     pub mod ansi;
     /// Contains additional collections.
     pub mod collections {
+        pub mod bktree;
         pub mod tinyvec;
     }
     pub mod error;
     pub mod fs;
+    pub mod term;
+    pub mod time;
+    pub mod units;
     pub mod xattr;
 }
 pub mod db;
+pub mod filter;
+pub mod fingerprint;
 pub mod hash;
 pub mod hash_concurrent;
+pub mod output;
+pub mod pipeline;
+pub mod portable;
+pub mod progress;
+pub mod report;
+pub mod scan;
 pub mod search;
+pub mod similar;
+pub mod similar_text;
+pub mod skip;
 pub mod status_line;
-
-use std::collections::HashSet;
+pub mod stored_hash;
+pub mod subtree;
+pub mod tui;
+pub mod verify;
+pub mod walk;
+pub mod waste;
+pub mod watch;
+
+use std::collections::HashMap;
 use std::fmt::Write;
-use std::fs::canonicalize;
-use std::io::Write as _;
-use std::io::stdin;
+use std::io::stderr;
 use std::io::stdout;
-use std::ops::Deref;
+use std::iter::once;
 use std::path::MAIN_SEPARATOR;
 use std::path::Path;
 use std::path::PathBuf;
 
 use url::Url;
 
-use crate::connection::Connection;
-use crate::connection::ConnectionKind;
+use crate::action::ActionOptions;
+use crate::action::resolve_duplicates;
+use crate::compare::Comparison;
 use crate::core::ansi::Anchor;
 use crate::core::ansi::Bold;
-use crate::core::fs::open_explorer;
-use crate::core::fs::read_dir_all;
-use crate::db::Database;
-use crate::hash::FileHash;
+use crate::core::units::Bytes;
 use crate::hash::HashStyle;
-use crate::hash_concurrent::HashFilesOptions;
-use crate::hash_concurrent::parallel_hash_files;
-use crate::search::Deduplicator;
+use crate::output::OutputFormat;
+use crate::output::collect_directory_groups;
+use crate::output::collect_groups;
+use crate::output::collect_similar_groups;
+use crate::output::collect_similar_text_groups;
+use crate::output::write_groups;
+use crate::portable::HostGroup;
+use crate::portable::PortableIndex;
+use crate::portable::export_index;
+use crate::portable::import_index;
+use crate::progress::ProgressSink;
+use crate::report::DuplicateGroup;
+use crate::scan::FindOptions;
+use crate::scan::ScanOptions;
+use crate::scan::scan;
 use crate::search::PathStyle;
+use crate::similar::ImageHash;
+use crate::similar_text::SimilarPair;
+use crate::skip::SkipReason;
+use crate::skip::Skipped;
+use crate::skip::count_reasons;
+use crate::subtree::DirectoryFindings;
+use crate::subtree::Superset;
+use crate::tui::BrowseOptions;
+use crate::tui::browse;
+use crate::verify::Corruption;
+use crate::verify::Verification;
+use crate::verify::VerifyOptions;
+use crate::waste::SummaryOptions;
+use crate::waste::WasteSummary;
+use crate::waste::write_summary;
+use crate::watch::Update;
 
 /////////////////
 // Error types //
@@ -62,45 +112,26 @@ pub type Result<T = (), E = Error> = ::std::result::Result<T, E>;
 // Output //
 ////////////
 
-fn print_findings(
-    findings: &Deduplicator,
+fn print_findings<'a>(
+    duplicates: impl IntoIterator<Item = &'a DuplicateGroup>,
     style: StyleOptions,
-    interactive: bool,
 ) -> crate::Result {
-    let mut lines = stdin().lines();
-    let ref mut entry = String::new();
-    let mut first = true;
-    for (hash, paths) in findings.duplicates() {
-        if interactive {
-            if first {
-                first = false;
-            } else {
-                let mut stdout = stdout().lock();
-                write!(stdout, "Press enter for the next set of files...")?;
-                stdout.flush()?;
-                let Some(Ok(_)) = lines.next() else { continue };
-                write!(stdout, "\x1B[1A\x1B[2K\r")?; //Clear last line, return to 
-                stdout.flush()?;
-            }
-            for &path in paths {
-                open_explorer(path)?;
-            }
-        }
-
+    let entry = &mut String::new();
+    for DuplicateGroup { hash, paths, .. } in duplicates {
         entry.clear();
         let count = paths.len();
         let hash = style.hash.format(hash);
         let header = format!("{} files with hash {}", count, hash);
         writeln!(entry, "{}:", Bold(&header))?;
-        for &path in paths {
+        for path in paths {
             let dir = style.path.format(path.parent().unwrap());
             let file = Path::new(path.file_name().unwrap());
 
-            let canonical_file_path = canonicalize(path)?;
+            let canonical_file_path = archive::canonicalize(path)?;
             let file_url = Url::from_file_path(&canonical_file_path).unwrap();
 
             let canonical_dir_path = canonical_file_path.parent().unwrap();
-            let dir_url = Url::from_file_path(&canonical_dir_path).unwrap();
+            let dir_url = Url::from_file_path(canonical_dir_path).unwrap();
 
             writeln!(
                 entry,
@@ -115,6 +146,220 @@ fn print_findings(
     Ok(())
 }
 
+fn print_directories(
+    findings: &DirectoryFindings,
+    style: StyleOptions,
+) -> crate::Result {
+    let entry = &mut String::new();
+    for dirs in &findings.identical {
+        entry.clear();
+        let info = &findings.info[&dirs[0]];
+        let header = format!(
+            "{} identical directories with {} file(s), {} each",
+            dirs.len(),
+            info.file_count,
+            Bytes(info.size),
+        );
+        writeln!(entry, "{}:", Bold(&header))?;
+        for dir in dirs {
+            writeln!(entry, "{}", style.path.format(dir).display())?;
+        }
+        println!("{}", entry.trim_ascii());
+    }
+    for Superset { superset, subset } in &findings.supersets {
+        entry.clear();
+        let count = findings.info[subset].file_count;
+        let header =
+            format!("1 directory containing all {} file(s) of another", count);
+        writeln!(entry, "{}:", Bold(&header))?;
+        for dir in [superset, subset] {
+            writeln!(entry, "{}", style.path.format(dir).display())?;
+        }
+        println!("{}", entry.trim_ascii());
+    }
+    Ok(())
+}
+
+fn print_similar(
+    similar: &[Vec<(PathBuf, ImageHash)>],
+    style: StyleOptions,
+) -> crate::Result {
+    let entry = &mut String::new();
+    for images in similar {
+        entry.clear();
+        let header = format!("{} similar images", images.len());
+        writeln!(entry, "{}:", Bold(&header))?;
+        for (path, hash) in images {
+            let path = style.path.format(path);
+            writeln!(entry, "{} ({:016x})", path.display(), hash.0)?;
+        }
+        println!("{}", entry.trim_ascii());
+    }
+    Ok(())
+}
+
+fn print_similar_text(
+    pairs: &[SimilarPair],
+    style: StyleOptions,
+) -> crate::Result {
+    let entry = &mut String::new();
+    for pair in pairs {
+        entry.clear();
+        let percent = pair.similarity * 100.0;
+        let header = format!("2 files {:.0}% similar", percent);
+        writeln!(entry, "{}:", Bold(&header))?;
+        for path in [&pair.first, &pair.second] {
+            writeln!(entry, "{}", style.path.format(path).display())?;
+        }
+        println!("{}", entry.trim_ascii());
+    }
+    Ok(())
+}
+
+fn print_hard_links(
+    hard_links: &HashMap<PathBuf, Vec<PathBuf>>,
+    style: StyleOptions,
+) -> crate::Result {
+    let mut sets: Vec<_> = hard_links.iter().collect();
+    sets.sort();
+    let entry = &mut String::new();
+    for (first_name, other_names) in sets {
+        entry.clear();
+        let count = 1 + other_names.len();
+        let header = format!("{} names for the same file", count);
+        writeln!(entry, "{} (already deduplicated):", Bold(&header))?;
+        for path in once(first_name).chain(other_names) {
+            writeln!(entry, "{}", style.path.format(path).display())?;
+        }
+        println!("{}", entry.trim_ascii());
+    }
+    Ok(())
+}
+
+fn print_comparison(
+    comparison: &Comparison,
+    [first, second]: [&Path; 2],
+    style: StyleOptions,
+) -> crate::Result {
+    let [first, second] = [first, second].map(|dir| style.path.format(dir));
+    let entry = &mut String::new();
+    let sections = [
+        (&comparison.only_in_first, format!("only in {}", first.display())),
+        (&comparison.only_in_second, format!("only in {}", second.display())),
+        (&comparison.different, "with different contents".to_string()),
+    ];
+    for (paths, description) in sections {
+        if paths.is_empty() {
+            continue;
+        }
+        entry.clear();
+        let header = format!("{} file(s) {}", paths.len(), description);
+        writeln!(entry, "{}:", Bold(&header))?;
+        for path in paths {
+            writeln!(entry, "{}", style.path.format(path).display())?;
+        }
+        println!("{}", entry.trim_ascii());
+    }
+    if comparison.is_empty() {
+        println!(
+            "{} and {} hold the same contents",
+            first.display(),
+            second.display(),
+        );
+    }
+    Ok(())
+}
+
+fn print_host_groups(
+    groups: &[HostGroup],
+    style: StyleOptions,
+) -> crate::Result {
+    let entry = &mut String::new();
+    for HostGroup { hash, files, .. } in groups {
+        entry.clear();
+        let hash = style.hash.format(hash);
+        let header = format!("{} files with hash {}", files.len(), hash);
+        writeln!(entry, "{}:", Bold(&header))?;
+        for file in files {
+            writeln!(entry, "{}", file)?;
+        }
+        println!("{}", entry.trim_ascii());
+    }
+    let wasted: u64 =
+        groups.iter().map(|g| g.size * (g.files.len() as u64 - 1)).sum();
+    println!(
+        "{}",
+        Bold(format!(
+            "{} group(s) across hosts, {} duplicated",
+            groups.len(),
+            Bytes(wasted),
+        )),
+    );
+    Ok(())
+}
+
+fn print_verification(
+    verification: &Verification,
+    style: StyleOptions,
+) -> crate::Result {
+    let Verification { corrupt, verified, bytes, changed, pending, .. } =
+        verification;
+    if !corrupt.is_empty() {
+        let entry = &mut String::new();
+        let header = format!("{} corrupt file(s)", corrupt.len());
+        writeln!(entry, "{}:", Bold(&header))?;
+        for Corruption { path, expected, actual } in corrupt {
+            writeln!(
+                entry,
+                "{} (hash was {}, is now {})",
+                style.path.format(path).display(),
+                style.hash.format(expected),
+                style.hash.format(actual),
+            )?;
+        }
+        println!("{}", entry.trim_ascii());
+    }
+    println!(
+        "{}",
+        Bold(format!("verified {} file(s), {}", verified, Bytes(*bytes))),
+    );
+    if *changed > 0 {
+        println!("{} file(s) changed since they were hashed", changed);
+    }
+    if *pending > 0 {
+        println!("{} file(s) left for later runs", pending);
+    }
+    Ok(())
+}
+
+fn print_skipped(skipped: &[Skipped], style: StyleOptions) {
+    let mut sorted: Vec<&Skipped> = skipped.iter().collect();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+    for Skipped { path, reason, error } in sorted {
+        let path = style.path.format(path);
+        match reason {
+            SkipReason::Io => {
+                eprintln!(
+                    "skipped '{}': {}: {:#}",
+                    path.display(),
+                    reason,
+                    error
+                )
+            },
+            _ => eprintln!("skipped '{}': {}", path.display(), reason),
+        }
+    }
+    if skipped.is_empty() {
+        return;
+    }
+    let reasons: Vec<String> = count_reasons(skipped)
+        .into_iter()
+        .map(|(reason, count)| format!("{} {}", count, reason))
+        .collect();
+    let header = format!("skipped {} unreadable path(s)", skipped.len());
+    eprintln!("{}: {}", Bold(&header), reasons.join(", "));
+}
+
 //////////
 // Main //
 //////////
@@ -130,124 +375,231 @@ pub struct StyleOptions {
 
 /// Options for finding duplicates.
 pub struct Options {
-    /// Where to look for duplicates.
-    pub directories: Vec<PathBuf>,
-    /// Options for hashing.
-    pub config: HashFilesOptions,
+    /// Options for finding files and hashing them.
+    pub scan: ScanOptions,
+    /// Options for what to look for, besides identical files.
+    pub find: FindOptions,
     /// Options for output formatting.
     pub style: StyleOptions,
-    /// Where to (re)store previously found information on duplicates.
-    pub cache: ConnectionKind,
-    /// Whether to wipe the cache before computation.
-    pub clean_cache: bool,
-    /// Whether to display TUI interface for duplicates
+    /// How to write the duplicates found.
+    pub format: OutputFormat,
+    /// Whether to browse the duplicates in a full-screen interface.
+    /// Only applies to [`OutputFormat::Text`].
     pub interactive: bool,
+    /// Options for the full-screen interface.
+    pub browse: BrowseOptions,
+    /// What to do with the duplicates found, if anything.
+    pub action: Option<ActionOptions>,
+    /// Whether to keep watching the directories after the search,
+    /// reporting duplicates as they appear. Only supported on Linux.
+    ///
+    /// Only identical files are reported, and skipped files never fail;
+    /// [`Options::find`] and [`Options::fail_on_error`] do not apply.
+    pub watch: bool,
+    /// How to summarize the space wasted, instead of listing duplicates.
+    pub summary: Option<SummaryOptions>,
+    /// Whether files that could not be read make the search fail,
+    /// after reporting what was found in the others.
+    pub fail_on_error: bool,
+    /// Where to report the progress of long-running work.
+    pub progress: Box<dyn ProgressSink>,
 }
 
-/// Finds duplicates using the specified parameters.
-pub fn run(
-    Options {
-        directories,
-        config,
-        style,
-        clean_cache,
-        cache,
-        interactive,
-    }: Options,
+/// Compares the contents of the two directories in the options, failing
+/// if they differ.
+pub fn compare(
+    options: ScanOptions,
+    style: StyleOptions,
+    mut progress: Box<dyn ProgressSink>,
 ) -> crate::Result {
-    ///////////////////////
-    // Load data sources //
-    ///////////////////////
-
-    let mut index = Connection::<Database>::open(cache)?;
-    if clean_cache {
-        index.clear();
-    }
-    let disk: Vec<PathBuf> = {
-        let mut all_files = Vec::new();
-        for dir in &directories {
-            all_files.extend(read_dir_all(dir)?);
-        }
-        // directories.iter().flat_map(read_dir_all).flatten()
-        // ...has the same result, but ignores errors
-        all_files
+    let scan = scan(options, &mut *progress)?;
+    let [first, second] = match scan.directories.as_slice() {
+        [first, second] => [first.as_path(), second.as_path()],
+        _ => anyhow::bail!("can only compare two directories"),
     };
+    // Files found in both would only be listed under the first
+    if first.starts_with(second) || second.starts_with(first) {
+        anyhow::bail!("cannot compare directories that contain one another");
+    }
+    let comparison = scan.compare(first, second);
+    print_comparison(&comparison, [first, second], style)?;
 
-    /////////////////////////////
-    // Compare index with disk //
-    /////////////////////////////
-
-    // Files in the index
-    let index_files: HashSet<&Path> = index.paths().collect();
-
-    // Files on disk
-    let disk_files: HashSet<&Path> =
-        disk.iter().map(|path| path.deref()).collect();
-
-    // Deleted files == Indexed files not on disk
-    let deleted_files: HashSet<&Path> =
-        index_files.difference(&disk_files).copied().collect();
-
-    // New files == Disk files not indexed
-    let new_files: HashSet<&Path> =
-        disk_files.difference(&index_files).copied().collect();
-
-    ////////////////////////
-    // Set plan of action //
-    ////////////////////////
-
-    let files_to_hash: Vec<&Path> = new_files.into_iter().collect();
-
-    let files_to_delete: Vec<PathBuf> =
-        deleted_files.into_iter().map(|path| path.to_path_buf()).collect();
+    for error in &scan.errors {
+        eprintln!("{:#}", error);
+    }
+    print_skipped(&scan.skipped, style);
+    if !comparison.is_empty() {
+        anyhow::bail!("{} file(s) differ", comparison.len());
+    }
+    Ok(())
+}
 
-    /////////////
-    // Execute //
-    /////////////
+/// Hashes every file in the directories, and writes a portable index
+/// of them to the output, labelled with the host.
+pub fn export(
+    mut options: ScanOptions,
+    host: &str,
+    output: &Path,
+    style: StyleOptions,
+    mut progress: Box<dyn ProgressSink>,
+) -> crate::Result {
+    options.hash_all = true;
+    let scan = scan(options, &mut *progress)?;
+    let index = scan.export(host);
+    export_index(output, &index)?;
+    let count: usize = index.roots.iter().map(|root| root.files.len()).sum();
+    println!(
+        "exported {} file(s) on {} to '{}'",
+        count,
+        host,
+        output.display(),
+    );
 
-    let files_to_insert: Vec<(PathBuf, FileHash)> =
-        parallel_hash_files(&files_to_hash, config)?
-            .into_iter()
-            .map(|(path, hash)| (path.to_path_buf(), hash))
-            .collect();
+    for error in &scan.errors {
+        eprintln!("{:#}", error);
+    }
+    print_skipped(&scan.skipped, style);
+    Ok(())
+}
 
-    ///////////////////
-    // Apply changes //
-    ///////////////////
+/// Reads portable indexes, and lists files with the same contents
+/// on different hosts.
+pub fn merge(inputs: &[PathBuf], style: StyleOptions) -> crate::Result {
+    let indexes: Vec<PortableIndex> = inputs
+        .iter()
+        .map(|path| import_index(path))
+        .collect::<crate::Result<_>>()?;
+    let groups = portable::merge(&indexes)?;
+    print_host_groups(&groups, style)
+}
 
-    // Index can contains paths of various different directories;
-    // This predicate selects those paths which are descendants of our target.
-    let is_our_file =
-        |file: &Path| directories.iter().any(|dir| file.starts_with(dir));
-    let mut did_modify = false;
+/// Hashes files in the index again,
+/// and reports those whose contents changed although their metadata did not.
+///
+/// Fails if any file is corrupt, so scheduled runs draw attention to it.
+pub fn verify(
+    options: VerifyOptions,
+    style: StyleOptions,
+    fail_on_error: bool,
+    mut progress: Box<dyn ProgressSink>,
+) -> crate::Result {
+    let verification = verify::verify(options, &mut *progress)?;
+    print_verification(&verification, style)?;
 
-    for file in files_to_delete {
-        if is_our_file(&file) {
-            index.remove(&file);
-            did_modify = true;
-        }
+    for error in &verification.errors {
+        eprintln!("{:#}", error);
     }
-
-    for (path, hash) in files_to_insert {
-        index.add(path, hash);
-        did_modify = true;
+    print_skipped(&verification.skipped, style);
+    if !verification.corrupt.is_empty() {
+        anyhow::bail!("{} file(s) are corrupt", verification.corrupt.len());
     }
-
-    if did_modify {
-        if let Err(e) = index.save() {
-            eprintln!("failed to save index: {}", e);
-        }
+    let error_count = verification.skipped.len() + verification.errors.len();
+    if fail_on_error && error_count > 0 {
+        anyhow::bail!("{} problem(s) during verification", error_count);
     }
+    Ok(())
+}
 
-    /////////////////////
-    // List duplicates //
-    /////////////////////
-
-    let findings = Deduplicator::from_iter(
-        index.entries().filter(|(file, _)| is_our_file(file)),
-    );
+/// Finds duplicates using the specified parameters.
+pub fn run(
+    Options {
+        scan: scan_options,
+        find,
+        style,
+        format,
+        interactive,
+        browse: browse_options,
+        action,
+        watch,
+        summary,
+        fail_on_error,
+        mut progress,
+    }: Options,
+) -> crate::Result {
+    let scan = scan(scan_options, &mut *progress)?;
+    if watch {
+        let on_update = |update: Update| {
+            match format {
+                OutputFormat::Text => {
+                    print_findings(&update.duplicates, style)?
+                },
+                _ => {
+                    let groups = collect_groups(
+                        &update.duplicates,
+                        &update.files,
+                        style,
+                    );
+                    write_groups(stdout().lock(), &groups, format)?;
+                },
+            }
+            for error in &update.errors {
+                eprintln!("{:#}", error);
+            }
+            print_skipped(&update.skipped, style);
+            match update.changed {
+                0 => eprintln!("watching for changes..."),
+                changed => eprintln!(
+                    "{} path(s) changed; hashed {} file(s)",
+                    changed, update.cache.hashed,
+                ),
+            }
+            Ok(())
+        };
+        return scan.watch(on_update, &mut *progress);
+    }
+    let report = scan.report(find, &mut *progress);
+
+    if let Some(options) = summary {
+        // Groups inside identical directories waste space all the same
+        let summary = WasteSummary::new(
+            &report.duplicates,
+            &report.roots,
+            options,
+            style,
+        );
+        write_summary(stdout().lock(), &summary, format)?;
+    } else if format == OutputFormat::Text && interactive {
+        browse(&report.duplicates, &report.files, style, browse_options)?;
+    } else if format == OutputFormat::Text {
+        print_findings(report.listed_duplicates(), style)?;
+        print_directories(&report.directories, style)?;
+        print_similar(&report.similar_images, style)?;
+        print_similar_text(&report.similar_text, style)?;
+        print_hard_links(&report.hard_links, style)?;
+    } else {
+        let files = &report.files;
+        let mut groups =
+            collect_groups(report.listed_duplicates(), files, style);
+        groups.extend(collect_directory_groups(&report.directories, style));
+        groups.extend(collect_similar_groups(
+            &report.similar_images,
+            files,
+            style,
+        ));
+        groups.extend(collect_similar_text_groups(
+            &report.similar_text,
+            files,
+            style,
+        ));
+        write_groups(stdout().lock(), &groups, format)?;
+    }
 
-    print_findings(&findings, style, interactive)?;
+    if let Some(action) = &action {
+        // Keep machine-readable output on stdout parseable
+        let out: Box<dyn std::io::Write> = match format {
+            OutputFormat::Text => Box::new(stdout().lock()),
+            _ => Box::new(stderr().lock()),
+        };
+        resolve_duplicates(&report.duplicates, &report.files, action, out)?;
+    }
 
+    for error in &report.errors {
+        eprintln!("{:#}", error);
+    }
+    print_skipped(&report.skipped, style);
+    let error_count = report.skipped.len() + report.errors.len();
+    if fail_on_error && error_count > 0 {
+        anyhow::bail!("{} problem(s) during the search", error_count);
+    }
     Ok(())
 }
@@ -1,33 +1,165 @@
 #![forbid(unsafe_code)]
 
+use std::io::stdout;
 use std::num::NonZero;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::ExitCode;
 use std::thread::available_parallelism;
+use std::time::Duration;
 
+use anyhow::Context;
+use clap::Args;
 use clap::Parser;
+use clap::Subcommand;
 use duplicate_detector::Options;
 pub use duplicate_detector::Result;
 use duplicate_detector::StyleOptions;
+use duplicate_detector::action::ActionKind;
+use duplicate_detector::action::ActionOptions;
+use duplicate_detector::action::KeepPolicy;
+use duplicate_detector::cache::CacheCommand;
+use duplicate_detector::cache::default_cache_path;
+use duplicate_detector::cache::maintain;
 use duplicate_detector::connection::ConnectionKind;
 use duplicate_detector::core::ansi::AnsiColor;
 use duplicate_detector::core::ansi::Bold;
 use duplicate_detector::core::ansi::ColorTarget;
 use duplicate_detector::core::ansi::Colored;
+use duplicate_detector::core::units::parse_bytes;
+use duplicate_detector::filter::FilterOptions;
+use duplicate_detector::hash::FileHasher;
+use duplicate_detector::hash::HashAlgorithm;
 use duplicate_detector::hash::HashStyle;
 use duplicate_detector::hash_concurrent::HashFilesOptions;
+use duplicate_detector::output::OutputFormat;
+use duplicate_detector::portable::host_name;
+use duplicate_detector::progress::TerminalProgress;
+use duplicate_detector::scan::FindOptions;
+use duplicate_detector::scan::ScanOptions;
 use duplicate_detector::search::PathStyle;
+use duplicate_detector::similar::ImageHashKind;
+use duplicate_detector::similar::SimilarityOptions;
+use duplicate_detector::similar_text::TextSimilarityOptions;
+use duplicate_detector::subtree::DirectoryOptions;
+use duplicate_detector::tui::BrowseOptions;
+use duplicate_detector::verify::VerifyOptions;
+use duplicate_detector::walk::WalkOptions;
+use duplicate_detector::waste::SummaryOptions;
 
 ////////////////////
 // CLI Parameters //
 ////////////////////
 
+/// Options for locating the cache.
+#[derive(Debug, Clone, Args)]
+#[deny(missing_docs)]
+pub struct CacheArgs {
+    /// Where to store the cache.
+    /// Defaults to a file in the per-user cache directory.
+    #[arg(long, global = true)]
+    pub cache_path: Option<PathBuf>,
+
+    /// Append changes to a journal, instead of rewriting the whole cache.
+    #[arg(long, global = true)]
+    pub journal: bool,
+}
+
+impl CacheArgs {
+    fn connection_kind(self) -> crate::Result<ConnectionKind> {
+        let path = match self.cache_path {
+            Some(path) => path,
+            None => default_cache_path()
+                .context("no cache directory; use --cache-path")?,
+        };
+        Ok(match self.journal {
+            true => ConnectionKind::Journal(path),
+            false => ConnectionKind::Disk(path),
+        })
+    }
+}
+
+/// Maintenance tasks for the cache.
+#[derive(Debug, Clone, Subcommand)]
+#[deny(missing_docs)]
+pub enum CacheTask {
+    /// Show what the cache contains.
+    Stats,
+    /// Forget directories that no longer exist.
+    Prune,
+    /// Forget directories not searched recently,
+    /// and the files found only in them.
+    ///
+    /// Files age with the directories they were found in:
+    /// a file is kept as long as any directory containing it
+    /// was searched recently.
+    Vacuum {
+        /// Forget directories last searched more than this many days ago.
+        #[arg(long, default_value_t = 90)]
+        days: u64,
+    },
+}
+
+/// Additional commands.
+#[derive(Debug, Clone, Subcommand)]
+#[deny(missing_docs)]
+pub enum Command {
+    /// Inspect or maintain the cache.
+    Cache {
+        /// What to do.
+        #[command(subcommand)]
+        task: CacheTask,
+    },
+    /// List what differs between two directories, by contents,
+    /// e.g. to check that a backup holds everything in the original.
+    /// Exits with an error if anything differs.
+    Compare {
+        /// The original directory.
+        first: PathBuf,
+        /// The directory to compare with, e.g. the backup.
+        second: PathBuf,
+    },
+    /// Hash every file in the directories, and write a portable index,
+    /// to find duplicates on other machines with `merge`.
+    Export {
+        /// Where to write the index.
+        output: PathBuf,
+        /// The directories to index.
+        #[arg(required = true)]
+        directories: Vec<PathBuf>,
+        /// Names this machine in the index. Defaults to its host name.
+        #[arg(long)]
+        host: Option<String>,
+    },
+    /// Hash indexed files again, and report those whose contents changed
+    /// although their size and modification time did not, i.e. bit rot.
+    Verify {
+        /// Only verify the indexed files in these directories.
+        directories: Vec<PathBuf>,
+        /// Only verify this percentage of the files,
+        /// starting with those verified least recently.
+        #[arg(long, value_name = "PERCENT", default_value_t = 100)]
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=100))]
+        sample: u8,
+    },
+    /// List files with the same contents on different machines,
+    /// given the indexes written by `export` on each.
+    Merge {
+        /// The indexes to merge.
+        #[arg(required = true, num_args = 2..)]
+        indexes: Vec<PathBuf>,
+    },
+}
+
 /// Searches for duplicates in the given directory.
 #[derive(Debug, Clone, Parser)]
 #[command(version, about)]
 #[deny(missing_docs)]
 pub struct Cli {
+    /// Runs a command instead of searching.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// The directory to search.
     pub directories: Vec<PathBuf>,
 
@@ -35,14 +167,26 @@ pub struct Cli {
     #[arg(long)]
     pub threads: Option<usize>,
 
+    /// Algorithm to hash files with.
+    #[arg(long, default_value_t)]
+    pub algorithm: HashAlgorithm,
+
+    /// Number of bytes to read from a file at once, e.g. `1M`.
+    /// At most 64 MiB.
+    #[arg(long, value_name = "SIZE", value_parser = parse_buffer_size)]
+    pub buffer_size: Option<usize>,
+
+    /// Read at most this many bytes per second, e.g. `20M`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_bytes)]
+    pub rate_limit: Option<u64>,
+
     /// Display the full hash.
     #[arg(long)]
     pub long: bool,
 
-    // TODO: Invert this, store hashes (with time) in a AppData/Local cache
-    /// Store hashes in a global cache.
-    #[arg(long)]
-    pub incremental: bool,
+    /// Do not read or store hashes in the cache.
+    #[arg(long, conflicts_with_all = ["cache_path", "journal", "clean_cache"])]
+    pub no_cache: bool,
 
     /// Display the absolute path.
     #[arg(long)]
@@ -52,17 +196,168 @@ pub struct Cli {
     #[arg(long)]
     pub canonical: bool,
 
+    /// How to write the duplicates found.
+    #[arg(long, default_value_t)]
+    pub format: OutputFormat,
+
     /// Clean cache before processing.
     #[arg(long)]
     pub clean_cache: bool,
 
-    /// Use TUI to browse results.
+    /// Browse the duplicates in a full-screen interface.
     #[arg(long)]
     pub interactive: bool,
 
-    /// Where to store the cache.
+    /// Command to reveal a file, with `{path}` and `{dir}` placeholders.
+    /// Defaults to the system file manager.
+    #[arg(long, value_name = "COMMAND", requires = "interactive")]
+    pub reveal_command: Option<String>,
+
+    /// Where the cache is stored.
+    #[command(flatten)]
+    pub cache: CacheArgs,
+
+    /// Also store hashes in the extended attributes of each file.
     #[arg(long)]
-    pub cache_path: Option<PathBuf>,
+    pub xattr: bool,
+
+    /// Follow symbolic links to files and directories.
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Do not descend into directories on other file systems.
+    #[arg(long)]
+    pub one_file_system: bool,
+
+    /// Also search the files inside zip and tar archives.
+    /// These are shown as e.g. `backup.zip!/dir/file`, and never changed.
+    #[arg(long)]
+    pub archives: bool,
+
+    /// Only search files matching this glob. Can be repeated.
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip files and directories matching this glob. Can be repeated.
+    /// Globs ending in `/`, e.g. `node_modules/`, only match directories.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Skip files smaller than this size, e.g. `4K`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_bytes)]
+    pub min_size: Option<u64>,
+
+    /// Skip files larger than this size, e.g. `1G`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_bytes)]
+    pub max_size: Option<u64>,
+
+    /// List every duplicate file, even inside identical directories.
+    #[arg(long)]
+    pub no_collapse_dirs: bool,
+
+    /// Also find directories containing everything in another.
+    #[arg(long, conflicts_with = "no_collapse_dirs")]
+    pub superset_dirs: bool,
+
+    /// Also find images that look alike, such as resized copies.
+    #[arg(long)]
+    pub similar_images: bool,
+
+    /// How to compare images.
+    #[arg(long, default_value_t, requires = "similar_images")]
+    pub image_hash: ImageHashKind,
+
+    /// How many bits (of 64) the hashes of similar images may differ by.
+    #[arg(long, default_value_t = 8, requires = "similar_images")]
+    pub max_distance: u32,
+
+    /// Also find text files that are nearly the same, such as forked configs.
+    #[arg(long)]
+    pub similar_text: bool,
+
+    /// How similar text files must be, e.g. `0.8` or `80%`.
+    #[arg(
+        long,
+        default_value = "80%",
+        value_parser = parse_fraction,
+        requires = "similar_text",
+    )]
+    pub min_similarity: f64,
+
+    /// What to do with duplicates. Previews changes unless `--execute`.
+    #[arg(long)]
+    pub action: Option<ActionKind>,
+
+    /// Which file of a set of duplicates to keep.
+    #[arg(long, default_value_t, requires = "action")]
+    pub keep: KeepPolicy,
+
+    /// Directory to prefer when keeping files. Can be repeated.
+    #[arg(long, value_name = "DIR", requires = "action")]
+    pub prefer: Vec<PathBuf>,
+
+    /// Only print what the action would change (the default).
+    #[arg(long, requires = "action")]
+    pub dry_run: bool,
+
+    /// Apply the action, instead of previewing it.
+    #[arg(long, requires = "action", conflicts_with = "dry_run")]
+    pub execute: bool,
+
+    /// File to append a record of every change to.
+    #[arg(long, value_name = "FILE", default_value = "duplicate-detector.log")]
+    pub action_log: PathBuf,
+
+    /// Summarize the space duplicates waste, by extension, top-level
+    /// directory and size, instead of listing every duplicate.
+    #[arg(long, conflicts_with_all = ["interactive", "watch"])]
+    pub summary: bool,
+
+    /// Number of groups wasting the most space to list in the summary.
+    #[arg(long, value_name = "N", default_value_t = 10, requires = "summary")]
+    pub top: usize,
+
+    /// Keep watching the directories, reporting duplicates as they appear.
+    /// Lists every duplicate file, as with `--no-collapse-dirs`.
+    /// Only supported on Linux.
+    #[arg(long, conflicts_with_all = [
+        "interactive",
+        "action",
+        "superset_dirs",
+        "similar_images",
+        "similar_text",
+        "fail_on_error",
+    ])]
+    pub watch: bool,
+
+    /// Exit with an error if any file could not be read.
+    /// Either way, such files are skipped and listed at the end.
+    #[arg(long)]
+    pub fail_on_error: bool,
+}
+
+/// Parses a fraction between 0 and 1, or a percentage.
+fn parse_fraction(text: &str) -> Result<f64, String> {
+    let value = match text.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|p| p / 100.0),
+        None => text.parse::<f64>(),
+    };
+    match value {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        Ok(_) => Err("must be between 0 and 1, or 0% and 100%".into()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Parses a size in bytes, which a read buffer can hold.
+fn parse_buffer_size(text: &str) -> Result<usize, String> {
+    match parse_bytes(text)? {
+        0 => Err("must not be zero".into()),
+        size => usize::try_from(size)
+            .ok()
+            .filter(|&size| size <= FileHasher::MAX_BUFFER_SIZE)
+            .ok_or_else(|| "must be at most 64 MiB".into()),
+    }
 }
 
 ///////////
@@ -73,21 +368,67 @@ pub fn start(
     Cli {
         mut directories,
         threads,
-        incremental,
+        algorithm,
+        buffer_size,
+        rate_limit,
+        command,
+        no_cache,
         clean_cache,
-        cache_path,
+        cache,
         absolute,
         canonical,
+        format,
         interactive,
+        reveal_command,
         long,
+        xattr,
+        follow_symlinks,
+        one_file_system,
+        archives,
+        include,
+        exclude,
+        min_size,
+        max_size,
+        no_collapse_dirs,
+        superset_dirs,
+        similar_images,
+        image_hash,
+        max_distance,
+        similar_text,
+        min_similarity,
+        action,
+        keep,
+        prefer,
+        dry_run,
+        execute,
+        action_log,
+        watch,
+        summary,
+        top,
+        fail_on_error,
     }: Cli,
 ) -> crate::Result {
-    let cache = match incremental {
-        true => ConnectionKind::Disk(match cache_path {
-            Some(file) => file,
-            None => "hash-cache.dat".into(),
-        }),
-        false => ConnectionKind::Memory,
+    if command.is_some() && !directories.is_empty() {
+        anyhow::bail!("directories cannot be given along with a command");
+    }
+    let command = match command {
+        Some(Command::Cache { task }) => {
+            let command = match task {
+                CacheTask::Stats => CacheCommand::Stats,
+                CacheTask::Prune => CacheCommand::Prune,
+                CacheTask::Vacuum { days } => CacheCommand::Vacuum {
+                    max_age: Duration::from_secs(days * 24 * 60 * 60),
+                },
+            };
+            let out = stdout().lock();
+            return maintain(cache.connection_kind()?, command, out);
+        },
+        command => command,
+    };
+
+    let cache = match no_cache {
+        true => ConnectionKind::Memory,
+        false => cache.connection_kind()?,
     };
 
     let config = HashFilesOptions {
@@ -96,9 +437,12 @@ pub fn start(
             .or_else(|| available_parallelism().ok())
             .or_else(|| NonZero::new(1))
             .unwrap(),
+        algorithm,
+        buffer_size: buffer_size.unwrap_or(FileHasher::DEFAULT_BUFFER_SIZE),
+        rate_limit: rate_limit.and_then(NonZero::new),
     };
 
-    if directories.len() == 0 {
+    if directories.is_empty() {
         directories.push(Path::new(".").to_path_buf());
     }
 
@@ -114,13 +458,87 @@ pub fn start(
         },
     };
 
-    duplicate_detector::run(Options {
-        config,
+    let walk = WalkOptions { follow_symlinks, one_file_system, archives };
+    let filter = FilterOptions { include, exclude, min_size, max_size };
+    let scan = ScanOptions {
         directories,
+        config,
         cache,
+        clean_cache,
+        xattr,
+        walk,
+        filter,
+        hash_all: false,
+    };
+    let progress = Box::new(TerminalProgress::default());
+    match command {
+        Some(Command::Compare { first, second }) => {
+            let directories = vec![first, second];
+            let scan = ScanOptions { directories, ..scan };
+            return duplicate_detector::compare(scan, style, progress);
+        },
+        Some(Command::Export { output, directories, host }) => {
+            let host = match host.or_else(host_name) {
+                Some(host) => host,
+                None => anyhow::bail!(
+                    "cannot tell the name of this machine; use --host"
+                ),
+            };
+            let scan = ScanOptions { directories, ..scan };
+            return duplicate_detector::export(
+                scan, &host, &output, style, progress,
+            );
+        },
+        Some(Command::Verify { directories, sample }) => {
+            if no_cache {
+                anyhow::bail!("can only verify files in the cache");
+            }
+            let ScanOptions { config, cache, .. } = scan;
+            let verify = VerifyOptions { directories, config, cache, sample };
+            return duplicate_detector::verify(
+                verify,
+                style,
+                fail_on_error,
+                progress,
+            );
+        },
+        Some(Command::Merge { indexes }) => {
+            return duplicate_detector::merge(&indexes, style);
+        },
+        Some(Command::Cache { .. }) => unreachable!("cache was maintained"),
+        None => {},
+    }
+
+    duplicate_detector::run(Options {
+        scan,
+        find: FindOptions {
+            similar: similar_images.then_some(SimilarityOptions {
+                kind: image_hash,
+                max_distance,
+                threads: config.threads,
+            }),
+            similar_text: similar_text.then_some(TextSimilarityOptions {
+                min_similarity,
+                threads: config.threads,
+            }),
+            subtrees: (!no_collapse_dirs)
+                .then_some(DirectoryOptions { supersets: superset_dirs }),
+        },
         style,
+        format,
         interactive,
-        clean_cache,
+        browse: BrowseOptions { reveal_command },
+        action: action.map(|kind| ActionOptions {
+            kind,
+            keep,
+            preferred: prefer,
+            dry_run: dry_run || !execute,
+            log: Some(action_log),
+        }),
+        watch,
+        summary: summary.then_some(SummaryOptions { top }),
+        fail_on_error,
+        progress,
     })
 }
 
@@ -0,0 +1,332 @@
+//! Items to write findings in machine-readable formats.
+//!
+//! Groups are sorted by hash, and files by path,
+//! so the same findings always produce the same bytes.
+
+use std::collections::HashMap;
+use std::fs::metadata;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use clap::ValueEnum;
+use serde::Serialize;
+use strum::Display;
+
+use crate::StyleOptions;
+use crate::archive::canonicalize;
+use crate::fingerprint::Fingerprint;
+use crate::report::DuplicateGroup;
+use crate::similar::ImageHash;
+use crate::similar_text::SimilarPair;
+use crate::subtree::DirInfo;
+use crate::subtree::DirectoryFindings;
+
+////////////
+// Format //
+////////////
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Display)]
+#[clap(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+/// How to write findings.
+pub enum OutputFormat {
+    #[default]
+    /// Human-readable text with hyperlinks.
+    Text,
+    /// A single JSON array of groups.
+    Json,
+    /// One JSON object per group, per line.
+    Ndjson,
+    /// One row per file, with a header.
+    Csv,
+}
+
+//////////////////
+// Output model //
+//////////////////
+
+#[derive(Debug, Serialize)]
+/// A file in a group of duplicates.
+pub struct FileEntry {
+    /// The path, formatted according to the [`StyleOptions`].
+    pub path: String,
+    /// The canonical path, i.e. absolute with symlinks resolved.
+    pub canonical_path: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Time of last modification, in seconds since the Unix epoch.
+    pub modified: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+/// Why files are grouped together.
+pub enum GroupKind {
+    /// The files have the same contents.
+    Exact,
+    /// The files are images that look alike.
+    Similar,
+    /// The files are text files with mostly the same contents.
+    SimilarText,
+    /// The entries are directories with the same contents.
+    Directory,
+    /// The first directory contains every file of the second.
+    Superset,
+}
+
+#[derive(Debug, Serialize)]
+/// A group of files with the same contents, or which look alike.
+pub struct Group {
+    /// Why the files are grouped together.
+    pub kind: GroupKind,
+    /// The hash, formatted according to the [`StyleOptions`].
+    /// For similar images, this is the perceptual hash of the first image.
+    /// For similar text files, this is empty.
+    pub hash: String,
+    /// For similar text files, the estimated similarity between 0 and 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f64>,
+    /// The files in this group.
+    pub files: Vec<FileEntry>,
+}
+
+impl FileEntry {
+    fn for_directory(path: &Path, info: &DirInfo, style: StyleOptions) -> Self {
+        let canonical_path = canonicalize(path);
+        let canonical_path = canonical_path.as_deref().unwrap_or(path);
+        FileEntry {
+            path: style.path.format(path).display().to_string(),
+            canonical_path: canonical_path.display().to_string(),
+            size: info.size,
+            modified: metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    fn new(
+        path: &Path,
+        fingerprint: &Fingerprint,
+        style: StyleOptions,
+    ) -> Self {
+        let canonical_path = canonicalize(path);
+        let canonical_path = canonical_path.as_deref().unwrap_or(path);
+        FileEntry {
+            path: style.path.format(path).display().to_string(),
+            canonical_path: canonical_path.display().to_string(),
+            size: fingerprint.size,
+            modified: fingerprint
+                .modified
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+/// Describes the files, leaving out those whose fingerprint is not known.
+fn file_entries<'a>(
+    paths: impl IntoIterator<Item = &'a PathBuf>,
+    fingerprints: &HashMap<PathBuf, Fingerprint>,
+    style: StyleOptions,
+) -> Vec<FileEntry> {
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let fingerprint = fingerprints.get(path)?;
+            Some(FileEntry::new(path, fingerprint, style))
+        })
+        .collect()
+}
+
+/// Collects the duplicates found, in a stable order.
+///
+/// Paths without a known fingerprint are left out,
+/// as are groups left with a single file.
+pub fn collect_groups<'a>(
+    duplicates: impl IntoIterator<Item = &'a DuplicateGroup>,
+    fingerprints: &HashMap<PathBuf, Fingerprint>,
+    style: StyleOptions,
+) -> Vec<Group> {
+    duplicates
+        .into_iter()
+        .map(|group| Group {
+            kind: GroupKind::Exact,
+            hash: style.hash.format(&group.hash),
+            similarity: None,
+            files: file_entries(&group.paths, fingerprints, style),
+        })
+        .filter(|group| group.files.len() > 1)
+        .collect()
+}
+
+/// Collects groups of similar images.
+///
+/// Paths without a known fingerprint are left out,
+/// as are groups left with a single file.
+pub fn collect_similar_groups(
+    similar: &[Vec<(PathBuf, ImageHash)>],
+    fingerprints: &HashMap<PathBuf, Fingerprint>,
+    style: StyleOptions,
+) -> Vec<Group> {
+    similar
+        .iter()
+        .map(|images| Group {
+            kind: GroupKind::Similar,
+            hash: format!("{:016x}", images[0].1.0),
+            similarity: None,
+            files: file_entries(
+                images.iter().map(|(path, _)| path),
+                fingerprints,
+                style,
+            ),
+        })
+        .filter(|group| group.files.len() > 1)
+        .collect()
+}
+
+/// Collects pairs of similar text files.
+///
+/// Pairs with a path whose fingerprint is not known are left out.
+pub fn collect_similar_text_groups(
+    pairs: &[SimilarPair],
+    fingerprints: &HashMap<PathBuf, Fingerprint>,
+    style: StyleOptions,
+) -> Vec<Group> {
+    pairs
+        .iter()
+        .map(|pair| Group {
+            kind: GroupKind::SimilarText,
+            hash: String::new(),
+            similarity: Some(pair.similarity),
+            files: file_entries(
+                [&pair.first, &pair.second],
+                fingerprints,
+                style,
+            ),
+        })
+        .filter(|group| group.files.len() > 1)
+        .collect()
+}
+
+/// Collects identical directories, and directories containing another.
+pub fn collect_directory_groups(
+    findings: &DirectoryFindings,
+    style: StyleOptions,
+) -> Vec<Group> {
+    let entry =
+        |dir: &Path| FileEntry::for_directory(dir, &findings.info[dir], style);
+    let identical = findings.identical.iter().map(|dirs| Group {
+        kind: GroupKind::Directory,
+        hash: findings.info[&dirs[0]]
+            .hash
+            .map_or_else(String::new, |hash| hash.to_hex().to_string()),
+        similarity: None,
+        files: dirs.iter().map(|dir| entry(dir)).collect(),
+    });
+    let supersets = findings.supersets.iter().map(|pair| Group {
+        kind: GroupKind::Superset,
+        hash: String::new(),
+        similarity: None,
+        files: vec![entry(&pair.superset), entry(&pair.subset)],
+    });
+    identical.chain(supersets).collect()
+}
+
+/////////////
+// Writers //
+/////////////
+
+#[derive(Serialize)]
+/// A row in CSV output.
+struct CsvRow<'a> {
+    group: usize,
+    kind: GroupKind,
+    hash: &'a str,
+    similarity: Option<f64>,
+    path: &'a str,
+    canonical_path: &'a str,
+    size: u64,
+    modified: u64,
+}
+
+/// Writes groups in the given format.
+/// Text is written without any styling or hyperlinks.
+pub fn write_groups(
+    mut out: impl Write,
+    groups: &[Group],
+    format: OutputFormat,
+) -> crate::Result {
+    match format {
+        OutputFormat::Text => {
+            for group in groups {
+                let count = group.files.len();
+                match (group.kind, group.similarity) {
+                    (GroupKind::SimilarText, Some(similarity)) => writeln!(
+                        out,
+                        "{} files {:.0}% similar:",
+                        count,
+                        similarity * 100.0,
+                    )?,
+                    (GroupKind::Directory, _) => writeln!(
+                        out,
+                        "{} identical directories with hash {}:",
+                        count, group.hash,
+                    )?,
+                    (GroupKind::Superset, _) => writeln!(
+                        out,
+                        "1 directory containing everything in another:",
+                    )?,
+                    (GroupKind::Similar, _) => writeln!(
+                        out,
+                        "{} similar images with hash {}:",
+                        count, group.hash,
+                    )?,
+                    _ => writeln!(
+                        out,
+                        "{} files with hash {}:",
+                        count, group.hash,
+                    )?,
+                }
+                for file in &group.files {
+                    writeln!(out, "{}", file.path)?;
+                }
+            }
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, groups)?;
+            writeln!(out)?;
+        },
+        OutputFormat::Ndjson => {
+            for group in groups {
+                serde_json::to_writer(&mut out, group)?;
+                writeln!(out)?;
+            }
+        },
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for (index, group) in groups.iter().enumerate() {
+                for file in &group.files {
+                    writer.serialize(CsvRow {
+                        group: index,
+                        kind: group.kind,
+                        hash: &group.hash,
+                        similarity: group.similarity,
+                        path: &file.path,
+                        canonical_path: &file.canonical_path,
+                        size: file.size,
+                        modified: file.modified,
+                    })?;
+                }
+            }
+            writer.flush()?;
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,225 @@
+//! Items to narrow down which files need to be fully hashed, in stages.
+//!
+//! Two files can only be duplicates if they have the same size,
+//! and can only have the same contents if they start and end the same.
+//! Each stage rules out files using a cheaper test than a full hash:
+//! 1. Group by size, and drop sizes that occur once.
+//! 2. Hash the head and tail of each file, and drop unique partial hashes.
+//! 3. Fully hash the remaining candidates.
+//!
+//! Partial hashes are returned too, so they can be cached;
+//! a file ruled out in stage 2 then need not be read again next time.
+//!
+//! Files inside archives skip stage 2, as reading their tail means
+//! decompressing the archive up to it. Their full hash is known up front,
+//! so files of the same size are fully hashed to compare with them instead.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::archive::is_member;
+use crate::hash::FileHash;
+use crate::hash::HashExtent;
+use crate::hash_concurrent::HashFilesOptions;
+use crate::hash_concurrent::HashResults;
+use crate::hash_concurrent::parallel_hash_files_with;
+use crate::progress::ProgressSink;
+use crate::skip::Skipped;
+
+///////////////
+// Candidate //
+///////////////
+
+#[derive(Debug, Clone, Copy)]
+/// A file that may or may not be a duplicate.
+pub struct Candidate<'a> {
+    /// Path to the file.
+    pub path: &'a Path,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Whether the full hash of this file is already known.
+    pub is_known: bool,
+    /// The partial hash of this file, if already known.
+    pub partial: Option<FileHash>,
+}
+
+#[derive(Debug, Default)]
+/// The hashes computed by [`hash_candidates`].
+pub struct CandidateHashes<'a> {
+    /// The full hash of every unknown candidate that could be a duplicate.
+    pub hashes: Vec<(&'a Path, FileHash)>,
+    /// The partial hash of every candidate read partially.
+    pub partial_hashes: Vec<(&'a Path, FileHash)>,
+    /// The candidates that could not be read.
+    pub skipped: Vec<Skipped>,
+}
+
+//////////////
+// Pipeline //
+//////////////
+
+/// Groups items by key, keeping only groups with more than 1 item.
+fn group_by<K: Eq + std::hash::Hash, T>(
+    items: impl IntoIterator<Item = (K, T)>,
+) -> impl Iterator<Item = Vec<T>> {
+    let mut groups = HashMap::<K, Vec<T>>::new();
+    for (key, item) in items {
+        groups.entry(key).or_default().push(item);
+    }
+    groups.into_values().filter(|group| group.len() > 1)
+}
+
+/// Computes the full hash of every unknown candidate
+/// that could be a duplicate of another candidate.
+///
+/// Unknown candidates that are ruled out are not part of the result.
+/// Known candidates are read partially to compare with unknown candidates,
+/// but are never fully hashed.
+/// Candidates with a known partial hash are not read partially,
+/// nor are files inside archives, which must be known.
+/// Candidates that cannot be read are skipped, in either stage.
+///
+/// Each hash is also passed on as soon as it is known, with its extent.
+pub fn hash_candidates<'a>(
+    candidates: &[Candidate<'a>],
+    config: HashFilesOptions,
+    progress: &mut dyn ProgressSink,
+    on_hash: &mut (dyn FnMut(HashExtent, &'a Path, FileHash) + Send),
+) -> CandidateHashes<'a> {
+    /////////////
+    // Stage 1 //
+    /////////////
+
+    // Only sizes with an unknown file can lead to new findings
+    let (with_members, by_size): (Vec<Vec<&Candidate>>, _) =
+        group_by(candidates.iter().map(|c| (c.size, c)))
+            .filter(|group| group.iter().any(|c| !c.is_known))
+            .partition(|group| group.iter().any(|c| is_member(c.path)));
+
+    /////////////
+    // Stage 2 //
+    /////////////
+
+    let sizes: HashMap<&Path, u64> =
+        by_size.iter().flatten().map(|c| (c.path, c.size)).collect();
+    let is_known: HashMap<&Path, bool> =
+        by_size.iter().flatten().map(|c| (c.path, c.is_known)).collect();
+
+    let to_partially_hash: Vec<(&Path, u64)> = by_size
+        .iter()
+        .flatten()
+        .filter(|c| c.partial.is_none())
+        .map(|c| (c.path, c.size))
+        .collect();
+    let HashResults { hashes: partial_hashes, mut skipped } =
+        parallel_hash_files_with(
+            &to_partially_hash,
+            HashExtent::Partial,
+            config,
+            progress,
+            &mut |path, hash| on_hash(HashExtent::Partial, path, hash),
+        );
+    let cached_hashes =
+        by_size.iter().flatten().filter_map(|c| Some((c.path, c.partial?)));
+
+    let by_partial_hash = group_by(
+        partial_hashes
+            .iter()
+            .copied()
+            .chain(cached_hashes)
+            .map(|(path, hash)| ((sizes[path], hash), (path, hash))),
+    );
+
+    /////////////
+    // Stage 3 //
+    /////////////
+
+    let mut hashes = Vec::new();
+    let mut to_fully_hash: Vec<(&Path, u64)> = with_members
+        .iter()
+        .flatten()
+        .filter(|c| !c.is_known && !is_member(c.path))
+        .map(|c| (c.path, c.size))
+        .collect();
+    for (path, partial_hash) in by_partial_hash.flatten() {
+        if is_known[path] {
+            continue;
+        }
+        if HashExtent::is_partial_complete(sizes[path]) {
+            // Partial hash covered the entire file; no need to read it again
+            on_hash(HashExtent::Full, path, partial_hash);
+            hashes.push((path, partial_hash));
+        } else {
+            to_fully_hash.push((path, sizes[path]));
+        }
+    }
+
+    let full = parallel_hash_files_with(
+        &to_fully_hash,
+        HashExtent::Full,
+        config,
+        progress,
+        &mut |path, hash| on_hash(HashExtent::Full, path, hash),
+    );
+    hashes.extend(full.hashes);
+    skipped.extend(full.skipped);
+    CandidateHashes { hashes, partial_hashes, skipped }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::hash::HashAlgorithm;
+    use crate::progress::NoProgress;
+
+    #[test]
+    fn reads_only_what_it_must() {
+        let dir = std::env::temp_dir().join(format!(
+            "duplicate-detector-{}-pipeline",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let real = dir.join("real");
+        fs::write(&real, [1; 100]).unwrap();
+        // Reading a file that does not exist would skip it
+        let (unique, cached) = (dir.join("unique"), dir.join("cached"));
+        let partial = FileHash::new(HashAlgorithm::Xxh3, &[2; 16]).unwrap();
+
+        let candidates = [
+            Candidate {
+                path: &unique,
+                size: 7,
+                is_known: false,
+                partial: None,
+            },
+            Candidate {
+                path: &cached,
+                size: 100,
+                is_known: false,
+                partial: Some(partial),
+            },
+            Candidate {
+                path: &real,
+                size: 100,
+                is_known: false,
+                partial: None,
+            },
+        ];
+        let options = HashFilesOptions::for_tests();
+        let hashed = hash_candidates(
+            &candidates,
+            options,
+            &mut NoProgress,
+            &mut |_, _, _| {},
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(hashed.skipped.is_empty());
+        // The partial hashes differ, so neither is a duplicate
+        assert!(hashed.hashes.is_empty());
+        let [(path, _)] = hashed.partial_hashes.as_slice() else { panic!() };
+        assert_eq!(*path, real.as_path());
+    }
+}
@@ -0,0 +1,295 @@
+//! Items to find duplicates across machines, without copying any files.
+//!
+//! Each machine exports a [`PortableIndex`] of the directories it searched,
+//! labelled with the name of the machine. Paths are stored relative to
+//! the directory searched, so the index reads the same on any platform.
+//! Indexes of several machines can then be merged on any one of them.
+
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::bail;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::core::fs::replace_file;
+use crate::hash::FileHash;
+use crate::hash::HashAlgorithm;
+use crate::scan::Scan;
+use crate::search::Deduplicator;
+
+/// Version of the portable index format.
+/// Increment whenever the serialized layout changes.
+pub const PORTABLE_VERSION: u32 = 1;
+
+///////////
+// Index //
+///////////
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// The hashes of the files in a set of directories on one machine.
+pub struct PortableIndex {
+    /// The version of the format, see [`PORTABLE_VERSION`].
+    pub version: u32,
+    /// Names the machine the files are on.
+    pub host: String,
+    /// The algorithm every file was hashed with.
+    pub algorithm: HashAlgorithm,
+    /// The directories searched.
+    pub roots: Vec<PortableRoot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A directory searched, and every file inside.
+pub struct PortableRoot {
+    /// The absolute path of the directory, as named on its machine.
+    pub path: String,
+    /// The files inside, sorted by path.
+    pub files: Vec<PortableFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A file inside a directory searched.
+pub struct PortableFile {
+    /// The path relative to the directory, separated by `/`.
+    pub path: String,
+    /// The size of the file, in bytes.
+    pub size: u64,
+    /// The hash of the contents.
+    pub hash: FileHash,
+}
+
+/// Joins the components of a relative path with `/`.
+///
+/// Names that are not valid Unicode are replaced lossily;
+/// they are only shown, never opened, on other machines.
+fn portable_path(relative: &Path) -> String {
+    let components: Vec<_> = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect();
+    components.join("/")
+}
+
+impl Scan {
+    /// Lists the hash of every file found, by directory searched.
+    ///
+    /// Files that were not hashed are left out,
+    /// so the scan should be made with [`ScanOptions::hash_all`].
+    ///
+    /// [`ScanOptions::hash_all`]: crate::scan::ScanOptions::hash_all
+    pub fn export(&self, host: &str) -> PortableIndex {
+        let mut roots: Vec<PortableRoot> = self
+            .directories
+            .iter()
+            .map(|dir| PortableRoot {
+                path: dir.to_string_lossy().into_owned(),
+                files: Vec::new(),
+            })
+            .collect();
+        for (path, fingerprint) in &self.files {
+            let Some(record) = self.index.get(path) else { continue };
+            // Attribute each file to the innermost directory containing it
+            let root = self
+                .directories
+                .iter()
+                .enumerate()
+                .filter(|(_, dir)| path.starts_with(dir))
+                .max_by_key(|(_, dir)| dir.as_os_str().len());
+            let Some((index, dir)) = root else { continue };
+            let relative = path.strip_prefix(dir).unwrap_or(path);
+            roots[index].files.push(PortableFile {
+                path: portable_path(relative),
+                size: fingerprint.size,
+                hash: record.hash,
+            });
+        }
+        for root in &mut roots {
+            root.files.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        PortableIndex {
+            version: PORTABLE_VERSION,
+            host: host.to_string(),
+            algorithm: self.config.algorithm,
+            roots,
+        }
+    }
+}
+
+/// Returns the name of this machine, if it can be told.
+pub fn host_name() -> Option<String> {
+    let name = fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .or_else(|| env::var("HOSTNAME").ok())?;
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Writes an index to a file, as JSON.
+pub fn export_index(path: &Path, index: &PortableIndex) -> crate::Result {
+    replace_file(path, |temp| {
+        let mut out = BufWriter::new(File::create(temp)?);
+        serde_json::to_writer(&mut out, index)?;
+        out.flush()
+    })
+    .with_context(|| format!("failed to write '{}'", path.display()))
+}
+
+/// Reads an index written by [`export_index`].
+pub fn import_index(path: &Path) -> crate::Result<PortableIndex> {
+    let context = || format!("failed to read '{}'", path.display());
+    let file = BufReader::new(File::open(path).with_context(context)?);
+    let index: PortableIndex =
+        serde_json::from_reader(file).with_context(context)?;
+    if index.version != PORTABLE_VERSION {
+        bail!(
+            "'{}' has format version {}, but this program reads version {}",
+            path.display(),
+            index.version,
+            PORTABLE_VERSION,
+        );
+    }
+    Ok(index)
+}
+
+/////////////
+// Merging //
+/////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A file in a portable index, tagged with where it came from.
+pub struct Origin<'a> {
+    /// Names the machine the file is on.
+    pub host: &'a str,
+    /// The directory searched, as named on that machine.
+    pub root: &'a str,
+    /// The path of the file, relative to the directory.
+    pub path: &'a str,
+}
+
+impl fmt::Display for Origin<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let separator = match self.root.ends_with(['/', '\\']) {
+            true => "",
+            false => "/",
+        };
+        write!(f, "{}:{}{}{}", self.host, self.root, separator, self.path)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Files with the same contents, on more than one machine.
+pub struct HostGroup<'a> {
+    /// The hash of the contents.
+    pub hash: &'a FileHash,
+    /// The size of each file, in bytes.
+    pub size: u64,
+    /// The files, sorted by origin.
+    pub files: Vec<Origin<'a>>,
+}
+
+/// Groups the files of several indexes by contents,
+/// keeping groups with files on more than one machine, sorted by hash.
+///
+/// Duplicates on a single machine are left out;
+/// a search on that machine finds those.
+pub fn merge(indexes: &[PortableIndex]) -> crate::Result<Vec<HostGroup<'_>>> {
+    let mut hosts = HashSet::new();
+    for index in indexes {
+        if !hosts.insert(&index.host) {
+            bail!("more than one index is labelled '{}'", index.host);
+        }
+        if index.algorithm != indexes[0].algorithm {
+            bail!(
+                "'{}' was hashed with {}, but '{}' with {}",
+                indexes[0].host,
+                indexes[0].algorithm,
+                index.host,
+                index.algorithm,
+            );
+        }
+    }
+
+    let files = indexes.iter().flat_map(|index| {
+        index.roots.iter().flat_map(move |root| {
+            root.files.iter().map(move |file| {
+                let origin = Origin {
+                    host: &index.host,
+                    root: &root.path,
+                    path: &file.path,
+                };
+                ((origin, file.size), &file.hash)
+            })
+        })
+    });
+    let findings = Deduplicator::from_iter(files);
+    Ok(findings
+        .sorted_duplicates()
+        .into_iter()
+        .filter(|(_, files)| {
+            files.iter().any(|(f, _)| f.host != files[0].0.host)
+        })
+        .map(|(hash, files)| HostGroup {
+            hash,
+            size: files[0].1,
+            files: files.into_iter().map(|(origin, _)| origin).collect(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(host: &str, files: &[(&str, u8)]) -> PortableIndex {
+        let file = |&(path, byte): &(&str, u8)| PortableFile {
+            path: path.to_string(),
+            size: 1,
+            hash: FileHash::new(HashAlgorithm::Xxh3, &[byte; 16]).unwrap(),
+        };
+        PortableIndex {
+            version: PORTABLE_VERSION,
+            host: host.to_string(),
+            algorithm: HashAlgorithm::Xxh3,
+            roots: vec![PortableRoot {
+                path: format!("/{host}"),
+                files: files.iter().map(file).collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn groups_across_hosts() {
+        let indexes = [
+            index("nas", &[("a", 1), ("b", 1), ("c", 2)]),
+            index("laptop", &[("x", 2), ("y", 3)]),
+        ];
+        let groups = merge(&indexes).unwrap();
+        assert_eq!(groups.len(), 1);
+        let files: Vec<String> =
+            groups[0].files.iter().map(|f| f.to_string()).collect();
+        assert_eq!(files, ["laptop:/laptop/x", "nas:/nas/c"]);
+
+        let twice = [index("nas", &[]), index("nas", &[])];
+        assert!(merge(&twice).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}.json", std::process::id()));
+        let index = index("nas", &[("dir/a", 1)]);
+        export_index(&path, &index).unwrap();
+        assert_eq!(import_index(&path).unwrap(), index);
+        fs::remove_file(&path).unwrap();
+    }
+}
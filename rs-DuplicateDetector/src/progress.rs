@@ -0,0 +1,351 @@
+//! Items to report the progress of long-running work.
+//!
+//! Work is reported as events to a [`ProgressSink`],
+//! so the library does not decide how (or whether) progress is shown.
+
+use std::io::Stderr;
+use std::io::stderr;
+use std::panic;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::core::time::Clock;
+use crate::core::units::Bytes;
+use crate::status_line::StatusLine;
+
+////////////
+// Events //
+////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A long-running stage of work.
+pub enum Stage {
+    /// Hashing the head and tail of files.
+    PartialHash,
+    /// Hashing the entire contents of files.
+    FullHash,
+    /// Hashing the files inside archives; counts archives.
+    Archives,
+    /// Hashing what images look like.
+    Images,
+    /// Reading text files.
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// How far a stage of work has come.
+pub struct Progress {
+    /// The stage of work.
+    pub stage: Stage,
+    /// Number of files done.
+    pub files_done: usize,
+    /// Number of files to do in total.
+    pub files_total: usize,
+    /// Number of bytes read.
+    pub bytes_done: u64,
+    /// Number of bytes to read in total.
+    pub bytes_total: u64,
+    /// Time since the stage started.
+    pub elapsed: Duration,
+    /// The file most recently started on, if any.
+    pub current: Option<PathBuf>,
+}
+
+impl Progress {
+    /// Bytes read per second, on average.
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            0.0 => 0.0,
+            secs => self.bytes_done as f64 / secs,
+        }
+    }
+
+    /// Estimated time until the stage is done,
+    /// assuming the throughput stays the same.
+    pub fn eta(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        let remaining = self.bytes_total.saturating_sub(self.bytes_done);
+        (throughput > 0.0)
+            .then(|| Duration::from_secs_f64(remaining as f64 / throughput))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Something that happened to a stage of work.
+pub enum ProgressEvent {
+    /// A stage started, with the given amount of work.
+    Started {
+        /// The stage of work.
+        stage: Stage,
+        /// Number of files to do.
+        files: usize,
+        /// Number of bytes to read, if known; otherwise 0.
+        bytes: u64,
+    },
+    /// A stage made progress.
+    Advanced(Progress),
+    /// A stage finished.
+    Finished {
+        /// The stage of work.
+        stage: Stage,
+    },
+}
+
+/// Receives reports of progress.
+///
+/// Each stage is reported as [`ProgressEvent::Started`],
+/// any number of [`ProgressEvent::Advanced`],
+/// and then [`ProgressEvent::Finished`].
+/// Stages do not overlap, but may be reported from different threads.
+pub trait ProgressSink: Send {
+    /// Handles an event.
+    fn report(&mut self, event: ProgressEvent);
+}
+
+//////////////
+// Tracking //
+//////////////
+
+#[derive(Debug, Default)]
+/// Counts the work done by several threads, so it can be reported.
+pub struct Tracker<'a> {
+    files_done: AtomicUsize,
+    bytes_done: Arc<AtomicU64>,
+    current: Mutex<Option<&'a Path>>,
+}
+
+impl<'a> Tracker<'a> {
+    /// Notes work started on the given file.
+    pub fn start(&self, path: &'a Path) {
+        *self.current.lock().unwrap() = Some(path);
+    }
+
+    /// Notes the given number of bytes were read.
+    pub fn read(&self, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Notes work finished on a file.
+    pub fn finish(&self) { self.files_done.fetch_add(1, Ordering::Relaxed); }
+
+    /// The counter of bytes read, to be updated while reading.
+    pub fn bytes_read(&self) -> Arc<AtomicU64> { self.bytes_done.clone() }
+}
+
+/// Runs work on another thread, reporting its progress periodically
+/// while it runs, as counted by the given tracker.
+///
+/// Reports [`ProgressEvent::Started`] with the given amount of work first,
+/// and [`ProgressEvent::Finished`] once the work returns.
+pub fn track<'a, T: Send>(
+    stage: Stage,
+    files_total: usize,
+    bytes_total: u64,
+    progress: &mut dyn ProgressSink,
+    work: impl FnOnce(&Tracker<'a>) -> T + Send,
+) -> T {
+    const UPDATE_PERIOD: Duration = Duration::from_millis(100);
+
+    progress.report(ProgressEvent::Started {
+        stage,
+        files: files_total,
+        bytes: bytes_total,
+    });
+    let started = Instant::now();
+    let tracker = Tracker::default();
+    let mut update = || {
+        progress.report(ProgressEvent::Advanced(Progress {
+            stage,
+            files_done: tracker.files_done.load(Ordering::Relaxed),
+            files_total,
+            bytes_done: tracker.bytes_done.load(Ordering::Relaxed),
+            bytes_total,
+            elapsed: started.elapsed(),
+            current: tracker.current.lock().unwrap().map(Path::to_path_buf),
+        }));
+    };
+
+    let result = thread::scope(|scope| {
+        let (done, finished) = mpsc::channel::<()>();
+        let tracker = &tracker;
+        let worker = scope.spawn(move || {
+            // NB: Dropped however the work ends, which wakes the reporter
+            let _done = done;
+            work(tracker)
+        });
+
+        // Updates also while a large file keeps every worker busy
+        update();
+        while let Err(RecvTimeoutError::Timeout) =
+            finished.recv_timeout(UPDATE_PERIOD)
+        {
+            update();
+        }
+        worker.join().unwrap_or_else(|panic| panic::resume_unwind(panic))
+    });
+    update();
+    progress.report(ProgressEvent::Finished { stage });
+    result
+}
+
+///////////
+// Sinks //
+///////////
+
+#[derive(Debug, Default, Clone, Copy)]
+/// Ignores all progress.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn report(&mut self, _: ProgressEvent) {}
+}
+
+#[derive(Debug, Default)]
+/// Shows progress in a status line on stderr, if that is a terminal.
+pub struct TerminalProgress {
+    status_line: Option<StatusLine<Stderr>>,
+}
+
+fn started_message(stage: Stage, count: usize) -> String {
+    match stage {
+        Stage::PartialHash => format!("pre-hashing {} file(s)...", count),
+        Stage::FullHash => format!("hashing {} file(s)...", count),
+        Stage::Archives => {
+            format!("hashing files inside {} archive(s)...", count)
+        },
+        Stage::Images => format!("hashing {} image(s)...", count),
+        Stage::Text => format!("reading {} file(s)...", count),
+    }
+}
+
+fn progress_message(progress: &Progress) -> String {
+    let verb = match progress.stage {
+        Stage::PartialHash => "pre-hashed",
+        Stage::FullHash | Stage::Archives | Stage::Images => "hashed",
+        Stage::Text => "read",
+    };
+    let percent = match progress.bytes_total {
+        0 => 100,
+        total => (100 * progress.bytes_done / total).min(100),
+    };
+    let mut message = format!(
+        "{} {} of {} ({} of {} file(s), {}%), {}/s",
+        verb,
+        Bytes(progress.bytes_done),
+        Bytes(progress.bytes_total),
+        progress.files_done,
+        progress.files_total,
+        percent,
+        Bytes(progress.throughput() as u64),
+    );
+    if let Some(eta) = progress.eta() {
+        message += &format!(", {} left", Clock(eta));
+    }
+    let current = progress.current.as_deref();
+    if let Some(name) = current.and_then(|path| path.file_name()) {
+        message += &format!(": {}", name.display());
+    }
+    message
+}
+
+impl ProgressSink for TerminalProgress {
+    fn report(&mut self, event: ProgressEvent) {
+        let status_line =
+            self.status_line.get_or_insert_with(|| StatusLine::new(stderr()));
+        match event {
+            ProgressEvent::Started { stage, files, .. } => {
+                status_line.writeln(&started_message(stage, files));
+            },
+            ProgressEvent::Advanced(progress) => {
+                status_line.writeln(&progress_message(&progress));
+            },
+            ProgressEvent::Finished { .. } => {
+                if let Some(status_line) = self.status_line.take() {
+                    status_line.close();
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_assumes_constant_throughput() {
+        let progress = Progress {
+            stage: Stage::FullHash,
+            files_done: 1,
+            files_total: 4,
+            bytes_done: 100,
+            bytes_total: 400,
+            elapsed: Duration::from_secs(2),
+            current: Some(PathBuf::from("/data/big.iso")),
+        };
+        assert_eq!(progress.throughput(), 50.0);
+        assert_eq!(progress.eta(), Some(Duration::from_secs(6)));
+        assert_eq!(
+            progress_message(&progress),
+            "hashed 100 B of 400 B (1 of 4 file(s), 25%), 50 B/s, 0:06 left: \
+             big.iso",
+        );
+
+        let stalled = Progress { bytes_done: 0, ..progress.clone() };
+        assert_eq!(stalled.eta(), None);
+    }
+
+    #[test]
+    fn tracks_work_while_it_runs() {
+        let mut events = Vec::new();
+        struct Recorder<'e>(&'e mut Vec<ProgressEvent>);
+        impl ProgressSink for Recorder<'_> {
+            fn report(&mut self, event: ProgressEvent) { self.0.push(event); }
+        }
+
+        let path = Path::new("/data/slow.txt");
+        let result =
+            track(Stage::Text, 2, 30, &mut Recorder(&mut events), |t| {
+                t.start(path);
+                t.read(10);
+                t.finish();
+                thread::sleep(Duration::from_millis(250));
+                t.read(20);
+                t.finish();
+                "done"
+            });
+        assert_eq!(result, "done");
+
+        let started =
+            ProgressEvent::Started { stage: Stage::Text, files: 2, bytes: 30 };
+        assert_eq!(events.first(), Some(&started));
+        assert_eq!(
+            events.last(),
+            Some(&ProgressEvent::Finished { stage: Stage::Text })
+        );
+        let advanced: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Advanced(progress) => Some(progress),
+                _ => None,
+            })
+            .collect();
+        // Reported while sleeping, and once more at the end
+        assert!(
+            advanced.iter().any(|p| (p.files_done, p.bytes_done) == (1, 10))
+        );
+        let last = advanced.last().unwrap();
+        assert_eq!((last.files_done, last.bytes_done), (2, 30));
+        assert_eq!(last.current.as_deref(), Some(path));
+    }
+}
@@ -0,0 +1,79 @@
+//! Items to describe everything a search found, independent of output.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::fingerprint::Fingerprint;
+use crate::hash::FileHash;
+use crate::similar::ImageHash;
+use crate::similar_text::SimilarPair;
+use crate::skip::Skipped;
+use crate::subtree::DirectoryFindings;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Files with the same contents.
+pub struct DuplicateGroup {
+    /// The hash of the contents.
+    pub hash: FileHash,
+    /// The size of each file, in bytes.
+    pub size: u64,
+    /// The files, sorted by path. There are at least two.
+    pub paths: Vec<PathBuf>,
+    /// Whether every file lies in a reported identical directory,
+    /// so the group is already shown as part of the directories.
+    pub covered: bool,
+}
+
+impl DuplicateGroup {
+    /// The number of bytes freed by keeping only one of the files.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// How the cache was used during a search.
+pub struct CacheStats {
+    /// Files whose hash was taken from the cache.
+    pub reused: usize,
+    /// Files whose hash was taken from their extended attributes.
+    pub restored: usize,
+    /// Files that were hashed.
+    pub hashed: usize,
+    /// Records removed, as the file was deleted or changed.
+    pub removed: usize,
+    /// Records in the cache afterwards, for all directories.
+    pub records: usize,
+}
+
+#[derive(Debug, Default)]
+/// Everything a search found.
+pub struct Report {
+    /// The directories searched.
+    pub roots: Vec<PathBuf>,
+    /// Groups of identical files, sorted by hash.
+    pub duplicates: Vec<DuplicateGroup>,
+    /// Identical directories, and directories containing another.
+    pub directories: DirectoryFindings,
+    /// Groups of images that look alike, sorted by path.
+    pub similar_images: Vec<Vec<(PathBuf, ImageHash)>>,
+    /// Pairs of nearly identical text files, most similar first.
+    pub similar_text: Vec<SimilarPair>,
+    /// Maps the path of a file to its other names.
+    pub hard_links: HashMap<PathBuf, Vec<PathBuf>>,
+    /// The fingerprint, and thus size, of every file found.
+    pub files: HashMap<PathBuf, Fingerprint>,
+    /// How the cache was used.
+    pub cache: CacheStats,
+    /// Files that could not be read, and were left out.
+    pub skipped: Vec<Skipped>,
+    /// Other problems that did not stop the search.
+    pub errors: Vec<crate::Error>,
+}
+
+impl Report {
+    /// Groups of identical files not already shown as part of a directory.
+    pub fn listed_duplicates(&self) -> impl Iterator<Item = &DuplicateGroup> {
+        self.duplicates.iter().filter(|group| !group.covered)
+    }
+}
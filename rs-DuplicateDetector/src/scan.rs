@@ -0,0 +1,844 @@
+//! Items to search directories for duplicates, in two phases.
+//!
+//! 1. [`scan`] finds all files, and brings the cache up to date with them.
+//! 2. [`Scan::report`] looks for duplicates among the files found.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::path::Path;
+use std::path::PathBuf;
+use std::path::absolute;
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::archive::hash_members;
+use crate::archive::is_member;
+use crate::archive::lies_in;
+use crate::archive::split_member;
+use crate::connection::Connection;
+use crate::connection::ConnectionKind;
+use crate::db::Change;
+use crate::db::Database;
+use crate::db::ImageRecord;
+use crate::db::Record;
+use crate::filter::Filter;
+use crate::filter::FilterOptions;
+use crate::fingerprint::Fingerprint;
+use crate::hash::FileHash;
+use crate::hash::HashExtent;
+use crate::hash_concurrent::HashFilesOptions;
+use crate::hash_concurrent::HashResults;
+use crate::hash_concurrent::parallel_hash_files_with;
+use crate::pipeline::Candidate;
+use crate::pipeline::CandidateHashes;
+use crate::pipeline::hash_candidates;
+use crate::progress::ProgressSink;
+use crate::report::CacheStats;
+use crate::report::DuplicateGroup;
+use crate::report::Report;
+use crate::search::Deduplicator;
+use crate::similar::ImageHash;
+use crate::similar::SimilarImages;
+use crate::similar::SimilarityOptions;
+use crate::similar::find_similar;
+use crate::similar::is_image;
+use crate::similar_text::SimilarText;
+use crate::similar_text::TextSimilarityOptions;
+use crate::similar_text::find_similar_text;
+use crate::similar_text::is_likely_text;
+use crate::skip::Skipped;
+use crate::stored_hash::read_stored_hash;
+use crate::stored_hash::write_stored_hash;
+use crate::subtree::DirectoryFindings;
+use crate::subtree::DirectoryOptions;
+use crate::subtree::FileInfo;
+use crate::subtree::find_duplicate_directories;
+use crate::walk::Walk;
+use crate::walk::WalkOptions;
+use crate::walk::walk;
+
+/////////////
+// Options //
+/////////////
+
+/// Options for finding files and hashing them.
+pub struct ScanOptions {
+    /// Where to look for duplicates.
+    pub directories: Vec<PathBuf>,
+    /// Options for hashing.
+    pub config: HashFilesOptions,
+    /// Where to (re)store previously found information on duplicates.
+    pub cache: ConnectionKind,
+    /// Whether to ignore (and overwrite) the existing cache.
+    pub clean_cache: bool,
+    /// Whether to (re)store hashes in the extended attributes of each file.
+    pub xattr: bool,
+    /// Options for finding files.
+    pub walk: WalkOptions,
+    /// Options for selecting files.
+    pub filter: FilterOptions,
+    /// Whether to hash every file, not just those that could be duplicates,
+    /// e.g. to compare them with files on another machine.
+    pub hash_all: bool,
+}
+
+#[cfg(test)]
+impl ScanOptions {
+    /// Searches the directories without filters or extended attributes,
+    /// hashing as [`HashFilesOptions::for_tests`] does.
+    pub(crate) fn for_tests(
+        directories: Vec<PathBuf>,
+        cache: ConnectionKind,
+    ) -> Self {
+        ScanOptions {
+            directories,
+            config: HashFilesOptions::for_tests(),
+            cache,
+            clean_cache: false,
+            xattr: false,
+            walk: WalkOptions::default(),
+            filter: FilterOptions::default(),
+            hash_all: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+/// Options for what to look for, besides identical files.
+pub struct FindOptions {
+    /// How to find images that look alike, if at all.
+    pub similar: Option<SimilarityOptions>,
+    /// How to find text files that are nearly the same, if at all.
+    pub similar_text: Option<TextSimilarityOptions>,
+    /// How to find identical directories, if at all.
+    /// Files inside are then left out of the per-file listing.
+    pub subtrees: Option<DirectoryOptions>,
+}
+
+//////////
+// Scan //
+//////////
+
+/// The files found, with their hashes in an up-to-date cache.
+pub struct Scan {
+    pub(crate) index: Connection<Database>,
+    pub(crate) directories: Vec<PathBuf>,
+    pub(crate) files: HashMap<PathBuf, Fingerprint>,
+    pub(crate) hard_links: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Every directory read, so changes inside can be watched.
+    pub(crate) dirs: Vec<PathBuf>,
+    pub(crate) config: HashFilesOptions,
+    pub(crate) xattr: bool,
+    pub(crate) walk: WalkOptions,
+    pub(crate) filter: Filter,
+    pub(crate) hash_all: bool,
+    pub(crate) cache: CacheStats,
+    pub(crate) skipped: Vec<Skipped>,
+    pub(crate) errors: Vec<crate::Error>,
+}
+
+/// Finds all files in the directories,
+/// and hashes those that could be duplicates, unless cached.
+pub fn scan(
+    ScanOptions {
+        directories,
+        config,
+        cache,
+        clean_cache,
+        xattr,
+        walk: walk_options,
+        filter,
+        hash_all,
+    }: ScanOptions,
+    progress: &mut dyn ProgressSink,
+) -> crate::Result<Scan> {
+    let mut index = match clean_cache {
+        true => Connection::<Database>::open_empty(cache)?,
+        false => Connection::<Database>::open(cache)
+            .context("failed to open cache; use --clean-cache to rebuild it")?,
+    };
+    // The index is shared between directories, so paths must be absolute
+    let directories: Vec<PathBuf> = directories
+        .iter()
+        .map(absolute)
+        .collect::<Result<_, _>>()
+        .context("failed to resolve directories")?;
+    let scanned_at = SystemTime::now();
+    let filter = Filter::new(&filter)?;
+    let known = |path: &Path| index.get_listing(path).cloned();
+    let Walk { files, hard_links, dirs, skipped, listings, .. } =
+        walk(&directories, walk_options, &filter, &known)
+            .context("failed to read directories")?;
+    let files: HashMap<PathBuf, Fingerprint> = files
+        .into_iter()
+        .map(|entry| (entry.path, entry.fingerprint))
+        .collect();
+
+    for dir in &directories {
+        index.apply(Change::ScanRoot(dir.clone(), scanned_at))?;
+    }
+    let mut scan = Scan {
+        index,
+        directories,
+        files,
+        hard_links,
+        dirs,
+        config,
+        xattr,
+        walk: walk_options,
+        filter,
+        hash_all,
+        cache: CacheStats::default(),
+        skipped,
+        errors: Vec::new(),
+    };
+    scan.sync(None, progress)?;
+    // NB: Applied after syncing, which forgets about archives that changed
+    for (path, listing) in listings {
+        scan.index.apply(Change::AddListing(path, listing))?;
+    }
+    if let Err(e) = scan.index.save() {
+        scan.errors.push(e.context("failed to save index"));
+    }
+    Ok(scan)
+}
+
+impl Scan {
+    /// Brings the index up to date with the files found,
+    /// hashing those that could be duplicates, unless cached.
+    ///
+    /// If only the given paths changed since the index was last brought up
+    /// to date, only files at or under them are compared with the index,
+    /// and only files of the same size as those may be hashed.
+    /// Records of files outside the directories, or inside a directory that
+    /// could not be read, are kept.
+    /// Sets the cache statistics, and adds the files that were skipped.
+    pub(crate) fn sync(
+        &mut self,
+        changed: Option<&[PathBuf]>,
+        progress: &mut dyn ProgressSink,
+    ) -> crate::Result {
+        let Scan {
+            index,
+            directories,
+            files: disk,
+            config,
+            xattr,
+            hash_all,
+            skipped,
+            ..
+        } = self;
+        let (config, xattr, hash_all) = (*config, *xattr, *hash_all);
+        let mut stats = CacheStats::default();
+        let is_changed = |file: &Path| {
+            changed.is_none_or(|paths| paths.iter().any(|p| lies_in(file, p)))
+        };
+
+        /////////////////////////////
+        // Compare index with disk //
+        /////////////////////////////
+
+        // Files in the index
+        let index_files: HashSet<&Path> =
+            index.paths().filter(|f| is_changed(f)).collect();
+
+        // Files on disk
+        let disk_files: HashSet<&Path> = disk
+            .keys()
+            .map(|path| path.deref())
+            .filter(|f| is_changed(f))
+            .collect();
+
+        // Files on disk which could be duplicates of those;
+        // other files are no more duplicates than before
+        let sizes: HashSet<u64> =
+            disk_files.iter().map(|file| disk[*file].size).collect();
+        let relevant: HashMap<&Path, Fingerprint> = disk
+            .iter()
+            .filter(|(_, fingerprint)| sizes.contains(&fingerprint.size))
+            .map(|(path, fingerprint)| (path.deref(), *fingerprint))
+            .collect();
+
+        // Files not on disk are only known to be gone if they were looked for,
+        // i.e. do not lie in a directory that could not be read
+        let unread: HashSet<&Path> =
+            skipped.iter().map(|skipped| skipped.path.deref()).collect();
+        let is_gone = |path: &Path| {
+            !disk.contains_key(path) &&
+                !path.ancestors().any(|dir| unread.contains(dir))
+        };
+
+        // Deleted files == Indexed files not on disk
+        let deleted_files: HashSet<&Path> =
+            index_files.iter().copied().filter(|f| is_gone(f)).collect();
+
+        // Partial and perceptual hashes of deleted files are of no use either
+        let deleted_partial: Vec<PathBuf> = index
+            .partial_paths()
+            .chain(index.image_paths())
+            .chain(index.listing_paths())
+            .filter(|f| is_changed(f) && is_gone(f) && index.get(f).is_none())
+            .map(|path| path.to_path_buf())
+            .collect();
+
+        // Stale files == Disk files not indexed, changed since indexing,
+        // or indexed using a different algorithm
+        let stale_files: HashSet<&Path> = relevant
+            .iter()
+            .filter(|(path, fingerprint)| match index.get(path) {
+                Some(record) => !record.is_fresh(fingerprint, config.algorithm),
+                None => true,
+            })
+            .map(|(path, _)| *path)
+            .collect();
+        stats.reused = disk_files.difference(&stale_files).count();
+
+        ////////////////////////
+        // Set plan of action //
+        ////////////////////////
+
+        // Hashes stored next to the file are as good as hashes in the index;
+        // those of files that did not change were looked for before
+        let mut files_to_insert: Vec<(PathBuf, FileHash)> = Vec::new();
+        let mut unknown_files: HashSet<&Path> = HashSet::new();
+        for file in stale_files {
+            let fingerprint = &relevant[file];
+            let stored_hash = (xattr && !is_member(file) && is_changed(file))
+                .then(|| read_stored_hash(file, fingerprint))
+                .flatten()
+                .filter(|hash| hash.algorithm() == config.algorithm);
+            match stored_hash {
+                Some(hash) => files_to_insert.push((file.to_path_buf(), hash)),
+                None => _ = unknown_files.insert(file),
+            }
+        }
+        stats.restored = files_to_insert.len();
+
+        // Files in archives cannot be partially read without decompressing
+        // them, so those that could be duplicates are fully hashed up front,
+        // reading each archive once
+        let mut size_counts: HashMap<u64, usize> = HashMap::new();
+        for fingerprint in relevant.values() {
+            *size_counts.entry(fingerprint.size).or_default() += 1;
+        }
+        let mut members: HashMap<&Path, HashMap<&str, u64>> = HashMap::new();
+        for &file in &unknown_files {
+            let size = relevant[file].size;
+            if let Some((archive, name)) = split_member(file) &&
+                (hash_all || size_counts[&size] > 1)
+            {
+                members.entry(archive).or_default().insert(name, size);
+            }
+        }
+        let (member_hashes, skipped_archives) =
+            hash_members(&members, config, progress);
+        self.skipped.extend(skipped_archives);
+        for (path, hash) in member_hashes {
+            unknown_files.remove(path.as_path());
+            files_to_insert.push((path, hash));
+        }
+
+        // Outdated records must go, even if the file is not hashed again
+        let files_to_delete: Vec<PathBuf> = deleted_files
+            .into_iter()
+            .chain(
+                unknown_files
+                    .iter()
+                    .copied()
+                    .filter(|f| index.get(f).is_some()),
+            )
+            .map(|path| path.to_path_buf())
+            .collect();
+
+        // Unknown files in archives were ruled out, or cannot be read
+        let candidates: Vec<Candidate> = relevant
+            .iter()
+            .filter(|(path, _)| {
+                !(is_member(path) && unknown_files.contains(*path))
+            })
+            .map(|(&path, fingerprint)| Candidate {
+                path,
+                size: fingerprint.size,
+                is_known: !unknown_files.contains(path),
+                partial: index
+                    .get_partial(path)
+                    .filter(|r| r.is_fresh(fingerprint, config.algorithm))
+                    .map(|record| record.hash),
+            })
+            .collect();
+
+        ///////////////////
+        // Apply changes //
+        ///////////////////
+
+        // Index can contains paths of various different directories;
+        // This predicate selects those paths which are descendants of our
+        // target.
+        let is_our_file =
+            |file: &Path| directories.iter().any(|dir| file.starts_with(dir));
+
+        for file in files_to_delete {
+            if is_our_file(&file) {
+                index.apply(Change::Remove(file))?;
+                stats.removed += 1;
+            }
+        }
+        for file in deleted_partial {
+            if is_our_file(&file) {
+                index.apply(Change::Remove(file))?;
+            }
+        }
+
+        stats.hashed = files_to_insert.len() - stats.restored;
+        for (path, hash) in files_to_insert {
+            let fingerprint = disk[&path];
+            index.apply(Change::Add(path, Record { fingerprint, hash }))?;
+        }
+
+        /////////////
+        // Execute //
+        /////////////
+
+        // Hashes are recorded as they arrive, so a crash loses little
+        let mut failed = None;
+        let mut record = |extent, path: &Path, hash| {
+            if failed.is_some() {
+                return;
+            }
+            let record = Record { fingerprint: disk[path], hash };
+            let path = path.to_path_buf();
+            let change = match extent {
+                HashExtent::Partial => Change::AddPartial(path, record),
+                HashExtent::Full => Change::Add(path, record),
+            };
+            failed = index.apply(change).err();
+        };
+        let hashed = match hash_all {
+            true => {
+                let unknown: Vec<(&Path, u64)> = candidates
+                    .iter()
+                    .filter(|candidate| !candidate.is_known)
+                    .map(|candidate| (candidate.path, candidate.size))
+                    .collect();
+                let HashResults { hashes, skipped } = parallel_hash_files_with(
+                    &unknown,
+                    HashExtent::Full,
+                    config,
+                    progress,
+                    &mut |path, hash| record(HashExtent::Full, path, hash),
+                );
+                CandidateHashes { hashes, partial_hashes: vec![], skipped }
+            },
+            false => {
+                hash_candidates(&candidates, config, progress, &mut record)
+            },
+        };
+        if let Some(error) = failed {
+            return Err(error);
+        }
+        self.skipped.extend(hashed.skipped);
+        stats.hashed += hashed.hashes.len();
+        if xattr {
+            for (path, hash) in hashed.hashes {
+                if !is_member(path) {
+                    // Not every file system supports xattrs; that is fine
+                    let _ = write_stored_hash(path, disk[path], hash);
+                }
+            }
+        }
+
+        stats.records = index.paths().count();
+        self.cache = stats;
+        Ok(())
+    }
+
+    /// Groups the files found by hash, sorted by hash.
+    ///
+    /// The index also holds records of files found by earlier searches
+    /// elsewhere, and of files not looked for, e.g. in directories
+    /// that could not be read. These are left out.
+    pub(crate) fn duplicate_groups(
+        &self,
+        directories: &DirectoryFindings,
+    ) -> Vec<DuplicateGroup> {
+        let findings = Deduplicator::from_iter(
+            self.index
+                .entries()
+                .filter(|(file, _)| self.files.contains_key(*file)),
+        );
+        findings
+            .sorted_duplicates()
+            .into_iter()
+            .map(|(hash, paths)| DuplicateGroup {
+                hash: *hash,
+                size: self.files[paths[0]].size,
+                covered: paths.iter().all(|p| directories.is_covered(p)),
+                paths: paths.into_iter().map(Path::to_path_buf).collect(),
+            })
+            .collect()
+    }
+
+    /// Looks for duplicates among the files found.
+    pub fn report(
+        mut self,
+        FindOptions { similar, similar_text, subtrees }: FindOptions,
+        progress: &mut dyn ProgressSink,
+    ) -> Report {
+        // Identical directories, which replace the groups of files inside
+        let directories = match subtrees {
+            Some(options) => {
+                let index = &self.index;
+                let files: HashMap<&Path, FileInfo> = self
+                    .files
+                    .iter()
+                    .map(|(path, fingerprint)| {
+                        let hash = index.get(path).map(|record| &record.hash);
+                        let size = fingerprint.size;
+                        (path.deref(), FileInfo { size, hash })
+                    })
+                    .collect();
+                find_duplicate_directories(&self.directories, &files, options)
+            },
+            None => DirectoryFindings::default(),
+        };
+        let duplicates = self.duplicate_groups(&directories);
+
+        // Similar images, except those which are all byte-identical
+        let similar_images = match similar {
+            Some(options) => {
+                let images: Vec<(&Path, u64, Option<ImageHash>)> = self
+                    .files
+                    .iter()
+                    .filter(|(path, _)| is_image(path))
+                    .map(|(path, fingerprint)| {
+                        let cached = self
+                            .index
+                            .get_image(path)
+                            .filter(|r| r.is_fresh(fingerprint, options.kind))
+                            .map(|record| record.hash);
+                        (path.deref(), fingerprint.size, cached)
+                    })
+                    .collect();
+                let SimilarImages { mut groups, hashed, skipped } =
+                    find_similar(&images, options, progress);
+                self.skipped.extend(skipped);
+
+                // What the images look like is cached, like their hashes
+                let is_cached = !hashed.is_empty();
+                for (path, hash) in hashed {
+                    let fingerprint = self.files[path];
+                    let record =
+                        ImageRecord { fingerprint, kind: options.kind, hash };
+                    let change = Change::AddImage(path.to_path_buf(), record);
+                    if let Err(e) = self.index.apply(change) {
+                        self.errors.push(e.context("failed to cache images"));
+                        break;
+                    }
+                }
+                if is_cached && let Err(e) = self.index.save() {
+                    self.errors.push(e.context("failed to save index"));
+                }
+
+                groups.retain(|group| {
+                    // NB: files without a hash have a unique size
+                    let mut hashes = HashSet::new();
+                    let mut unique_count = 0;
+                    for (path, _) in group {
+                        match self.index.get(path) {
+                            Some(record) => _ = hashes.insert(record.hash),
+                            None => unique_count += 1,
+                        }
+                    }
+                    hashes.len() + unique_count > 1
+                });
+                groups
+                    .into_iter()
+                    .map(|group| {
+                        let owned = |(path, hash): (&Path, _)| {
+                            (path.to_path_buf(), hash)
+                        };
+                        group.into_iter().map(owned).collect()
+                    })
+                    .collect()
+            },
+            None => vec![],
+        };
+
+        // Similar text files, except those which are byte-identical
+        let similar_text = match similar_text {
+            Some(options) => {
+                let files: Vec<(&Path, u64)> = self
+                    .files
+                    .iter()
+                    .map(|(path, fingerprint)| (path.deref(), fingerprint.size))
+                    .filter(|(path, _)| is_likely_text(path))
+                    .collect();
+                let SimilarText { mut pairs, skipped } =
+                    find_similar_text(&files, options, progress);
+                self.skipped.extend(skipped);
+                let index = &self.index;
+                pairs.retain(|pair| {
+                    let hash = |path| index.get(path).map(|record| record.hash);
+                    let (first, second) =
+                        (hash(&pair.first), hash(&pair.second));
+                    first.is_none() || first != second
+                });
+                pairs
+            },
+            None => vec![],
+        };
+
+        Report {
+            roots: self.directories,
+            duplicates,
+            directories,
+            similar_images,
+            similar_text,
+            hard_links: self.hard_links,
+            files: self.files,
+            cache: self.cache,
+            skipped: self.skipped,
+            errors: self.errors,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::num::NonZero;
+    use std::panic;
+    use std::panic::AssertUnwindSafe;
+    use std::time::Duration;
+
+    use image::GrayImage;
+    use image::Luma;
+
+    use super::*;
+    use crate::progress::NoProgress;
+    use crate::progress::ProgressEvent;
+    use crate::progress::Stage;
+    use crate::similar::ImageHashKind;
+
+    #[test]
+    fn report_owns_findings() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-scan", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), "same").unwrap();
+        fs::write(dir.join("b"), "same").unwrap();
+        fs::write(dir.join("c"), "other").unwrap();
+
+        let options =
+            ScanOptions::for_tests(vec![dir.clone()], ConnectionKind::Memory);
+        let scan = scan(options, &mut NoProgress).unwrap();
+        let report = scan.report(FindOptions::default(), &mut NoProgress);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let [group] = report.duplicates.as_slice() else { panic!() };
+        assert_eq!(group.paths, vec![dir.join("a"), dir.join("b")]);
+        assert_eq!(group.wasted_bytes(), 4);
+        assert_eq!(report.listed_duplicates().count(), 1);
+        assert_eq!(report.files.len(), 3);
+        assert_eq!(report.cache.hashed, 2);
+        assert_eq!(report.cache.records, 2);
+        assert!(report.skipped.is_empty() && report.errors.is_empty());
+    }
+
+    #[test]
+    fn touched_files_are_hashed_again() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-touch", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "same").unwrap();
+        fs::write(&b, "same").unwrap();
+
+        let options = || {
+            ScanOptions::for_tests(
+                vec![dir.clone()],
+                ConnectionKind::Disk(dir.join("index.dat")),
+            )
+        };
+        let first = scan(options(), &mut NoProgress)
+            .unwrap()
+            .report(FindOptions::default(), &mut NoProgress);
+
+        // Same contents, but a later modification time
+        let modified = fs::metadata(&a).unwrap().modified().unwrap();
+        File::options()
+            .write(true)
+            .open(&a)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        let touched = scan(options(), &mut NoProgress)
+            .unwrap()
+            .report(FindOptions::default(), &mut NoProgress);
+
+        // Other contents of the same size
+        fs::write(&a, "diff").unwrap();
+        let edited = scan(options(), &mut NoProgress)
+            .unwrap()
+            .report(FindOptions::default(), &mut NoProgress);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!((first.cache.reused, first.cache.hashed), (0, 2));
+        assert_eq!((touched.cache.reused, touched.cache.hashed), (1, 1));
+        assert_eq!(touched.duplicates.len(), 1);
+        assert_eq!((edited.cache.reused, edited.cache.removed), (1, 1));
+        assert!(edited.duplicates.is_empty());
+    }
+
+    #[test]
+    fn records_are_kept_unless_looked_for() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-keep", std::process::id()));
+        let (files, other) = (dir.join("files"), dir.join("other"));
+        let sub = files.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        fs::create_dir_all(&other).unwrap();
+        for dir in [&files, &other] {
+            fs::write(dir.join("a"), "same").unwrap();
+            fs::write(dir.join("b"), "same").unwrap();
+        }
+        // Duplicates of their own, so only kept records form their group
+        fs::write(sub.join("a"), "kept").unwrap();
+        fs::write(sub.join("b"), "kept").unwrap();
+
+        let options = |directories| {
+            ScanOptions::for_tests(
+                directories,
+                ConnectionKind::Journal(dir.join("index.dat")),
+            )
+        };
+        scan(options(vec![other.clone()]), &mut NoProgress).unwrap();
+        scan(options(vec![files.clone()]), &mut NoProgress).unwrap();
+        // An ignore file that cannot be parsed makes the directory unreadable
+        fs::write(sub.join(".dupignore"), "[z-a]").unwrap();
+        fs::remove_file(files.join("b")).unwrap();
+        let rescan = scan(options(vec![files.clone()]), &mut NoProgress)
+            .unwrap()
+            .report(FindOptions::default(), &mut NoProgress);
+        let index = Connection::<Database>::open(ConnectionKind::Journal(
+            dir.join("index.dat"),
+        ))
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let [skipped] = rescan.skipped.as_slice() else { panic!() };
+        assert_eq!(skipped.path, sub);
+        assert_eq!(rescan.cache.removed, 1);
+        assert!(rescan.duplicates.is_empty());
+        let mut paths: Vec<&Path> = index.paths().collect();
+        paths.sort();
+        assert_eq!(paths, [
+            files.join("a"),
+            sub.join("a"),
+            sub.join("b"),
+            other.join("a"),
+            other.join("b"),
+        ]);
+    }
+
+    /// Dies as soon as every file has been fully hashed.
+    struct Crash;
+
+    impl ProgressSink for Crash {
+        fn report(&mut self, event: ProgressEvent) {
+            if let ProgressEvent::Finished { stage: Stage::FullHash } = event {
+                panic!("simulated crash");
+            }
+        }
+    }
+
+    #[test]
+    fn hashes_survive_a_crash() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-crash", std::process::id()));
+        let files = dir.join("files");
+        fs::create_dir_all(&files).unwrap();
+        // Too large to be fully hashed by the partial hash
+        fs::write(files.join("a"), [1; 10_000]).unwrap();
+        fs::write(files.join("b"), [1; 10_000]).unwrap();
+
+        let options = || {
+            ScanOptions::for_tests(
+                vec![files.clone()],
+                ConnectionKind::Journal(dir.join("index.dat")),
+            )
+        };
+        let crashed = panic::catch_unwind(AssertUnwindSafe(|| {
+            scan(options(), &mut Crash)
+        }));
+        assert!(crashed.is_err());
+        let rerun = scan(options(), &mut NoProgress)
+            .unwrap()
+            .report(FindOptions::default(), &mut NoProgress);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!((rerun.cache.reused, rerun.cache.hashed), (2, 0));
+        assert_eq!(rerun.duplicates.len(), 1);
+    }
+
+    /// Counts the images hashed.
+    #[derive(Default)]
+    struct ImageCount(usize);
+
+    impl ProgressSink for ImageCount {
+        fn report(&mut self, event: ProgressEvent) {
+            if let ProgressEvent::Started {
+                stage: Stage::Images, files, ..
+            } = event
+            {
+                self.0 += files;
+            }
+        }
+    }
+
+    #[test]
+    fn images_are_hashed_once() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-images", std::process::id()));
+        let images = dir.join("images");
+        fs::create_dir_all(&images).unwrap();
+        // The same smooth pattern, at different sizes
+        for (name, width) in [("a.png", 64), ("b.png", 128)] {
+            let image = GrayImage::from_fn(width, width, |x, y| {
+                let (x, y) = (x as f32 / width as f32, y as f32 / width as f32);
+                let value = (5.0 * x).sin() * (3.0 * y + 2.0 * x * x).cos();
+                Luma([(127.5 + 127.5 * value) as u8])
+            });
+            image.save(images.join(name)).unwrap();
+        }
+
+        let options = || {
+            ScanOptions::for_tests(
+                vec![images.clone()],
+                ConnectionKind::Journal(dir.join("index.dat")),
+            )
+        };
+        let find = FindOptions {
+            similar: Some(SimilarityOptions {
+                kind: ImageHashKind::PHash,
+                max_distance: 4,
+                threads: NonZero::new(2).unwrap(),
+            }),
+            ..FindOptions::default()
+        };
+        let mut counts = [ImageCount::default(), ImageCount::default()];
+        let reports = counts.each_mut().map(|count| {
+            scan(options(), &mut NoProgress).unwrap().report(find, count)
+        });
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(counts.map(|count| count.0), [2, 0]);
+        for report in reports {
+            assert_eq!(report.similar_images.len(), 1);
+            assert!(report.skipped.is_empty() && report.errors.is_empty());
+        }
+    }
+}
@@ -4,13 +4,14 @@ use std::borrow::Cow;
 use std::borrow::Cow::Borrowed;
 use std::borrow::Cow::Owned;
 use std::collections::HashMap;
-use std::fs::canonicalize;
+use std::env::current_dir;
 use std::path::Path;
 use std::path::absolute;
 
 use clap::ValueEnum;
 use strum::Display;
 
+use crate::archive::canonicalize;
 use crate::core::collections::tinyvec::TinyVec;
 use crate::hash::FileHash;
 
@@ -19,11 +20,14 @@ use crate::hash::FileHash;
 //////////////
 
 /// Stores the hashes and paths of all searched files.
-pub struct Deduplicator<'a> {
-    entries: HashMap<&'a FileHash, TinyVec<&'a Path>>,
+///
+/// Files are usually identified by path,
+/// but can be tagged with more, e.g. the machine they are on.
+pub struct Deduplicator<'a, P = &'a Path> {
+    entries: HashMap<&'a FileHash, TinyVec<P>>,
 }
 
-impl<'a> Deduplicator<'a> {
+impl<'a, P> Deduplicator<'a, P> {
     /// Creates an empty deduplicator with atleast the specified capacity.
     pub fn with_capacity(cap: usize) -> Self {
         Deduplicator { entries: HashMap::with_capacity(cap) }
@@ -33,25 +37,43 @@ impl<'a> Deduplicator<'a> {
     pub fn new() -> Self { Deduplicator::with_capacity(0) }
 
     /// Registers the hash for a given path
-    pub fn insert(&mut self, (path, hash): (&'a Path, &'a FileHash)) {
-        self.entries.entry(hash).or_insert_with(TinyVec::new).push(path);
+    pub fn insert(&mut self, (path, hash): (P, &'a FileHash)) {
+        self.entries.entry(hash).or_default().push(path);
     }
 
     /// Iterates over all hashes and paths.
-    pub fn iter(&self) -> impl Iterator<Item = (&FileHash, &[&'a Path])> {
+    pub fn iter(&self) -> impl Iterator<Item = (&'a FileHash, &[P])> {
         self.entries.iter().map(|(&k, v)| (k, v.as_slice()))
     }
 
     /// Iterates over all entries that have more than 1 file.
-    pub fn duplicates(&self) -> impl Iterator<Item = (&FileHash, &[&'a Path])> {
+    pub fn duplicates(&self) -> impl Iterator<Item = (&'a FileHash, &[P])> {
         self.iter().filter(|(_, files)| files.len() > 1)
     }
+
+    /// Returns all entries that have more than 1 file,
+    /// sorted by hash and then by path, for stable output.
+    pub fn sorted_duplicates(&self) -> Vec<(&'a FileHash, Vec<P>)>
+    where P: Ord + Clone {
+        let mut result: Vec<_> = self
+            .duplicates()
+            .map(|(hash, paths)| {
+                let mut paths = paths.to_vec();
+                paths.sort();
+                (hash, paths)
+            })
+            .collect();
+        result.sort();
+        result
+    }
+}
+
+impl<'a, P> Default for Deduplicator<'a, P> {
+    fn default() -> Self { Self::new() }
 }
 
-impl<'a> FromIterator<(&'a Path, &'a FileHash)> for Deduplicator<'a> {
-    fn from_iter<I: IntoIterator<Item = (&'a Path, &'a FileHash)>>(
-        iter: I,
-    ) -> Self {
+impl<'a, P> FromIterator<(P, &'a FileHash)> for Deduplicator<'a, P> {
+    fn from_iter<I: IntoIterator<Item = (P, &'a FileHash)>>(iter: I) -> Self {
         let iter = iter.into_iter();
         let mut result = Self::with_capacity(iter.size_hint().0);
         for item in iter {
@@ -71,7 +93,8 @@ impl<'a> FromIterator<(&'a Path, &'a FileHash)> for Deduplicator<'a> {
 /// Formatting for paths
 pub enum PathStyle {
     #[default]
-    /// Format relative to the current working directory.
+    /// Format relative to the current working directory,
+    /// if the path is inside it.
     Relative,
     /// Format as an absolute path, but without resolving symlinks.
     Absolute,
@@ -83,9 +106,18 @@ impl PathStyle {
     /// Tries to apply a formatting style.
     ///
     /// Can fail if the path is empty, the file at the path doesn't exist, etc.
-    pub fn try_apply(self, path: &Path) -> crate::Result<Cow<Path>> {
+    pub fn try_apply(self, path: &Path) -> crate::Result<Cow<'_, Path>> {
         Ok(match self {
-            Self::Relative => Borrowed(path),
+            Self::Relative => match current_dir() {
+                Ok(dir) => match path.strip_prefix(dir) {
+                    Ok(relative) if relative.as_os_str().is_empty() => {
+                        Borrowed(Path::new("."))
+                    },
+                    Ok(relative) => Borrowed(relative),
+                    Err(_) => Borrowed(path),
+                },
+                Err(_) => Borrowed(path),
+            },
             Self::Absolute => Owned(absolute(path)?),
             Self::Canonical => Owned(canonicalize(path)?),
         })
@@ -93,7 +125,7 @@ impl PathStyle {
 
     /// Applies a formatting style,
     /// falling back to the original path if formatting fails.
-    pub fn format(self, path: &Path) -> Cow<Path> {
+    pub fn format(self, path: &Path) -> Cow<'_, Path> {
         match self.try_apply(path) {
             Ok(cow) => cow,
             Err(_) => Borrowed(path),
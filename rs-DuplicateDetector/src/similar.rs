@@ -0,0 +1,340 @@
+//! Items to find images that look alike, even if their bytes differ.
+//!
+//! Each image is reduced to a 64-bit perceptual hash,
+//! such that similar images have hashes that differ in few bits.
+
+use std::collections::HashMap;
+use std::num::NonZero;
+use std::panic;
+use std::path::Path;
+use std::thread;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use image::GrayImage;
+use image::ImageReader;
+use image::imageops::FilterType;
+use image::imageops::resize;
+use serde::Deserialize;
+use serde::Serialize;
+use strum::Display;
+
+use crate::core::collections::bktree::BkTree;
+use crate::core::collections::bktree::Metric;
+use crate::progress::ProgressSink;
+use crate::progress::Stage;
+use crate::progress::Tracker;
+use crate::progress::track;
+use crate::skip::Skipped;
+
+/// File extensions of the image formats that can be decoded.
+const IMAGE_EXTENSIONS: &[&str] =
+    &["bmp", "gif", "jpeg", "jpg", "png", "tif", "tiff", "webp"];
+
+/// Whether the file at the path looks like an image, judging by its name.
+pub fn is_image(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| {
+        IMAGE_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e))
+    })
+}
+
+/////////////
+// Options //
+/////////////
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    ValueEnum,
+    Display,
+    Serialize,
+    Deserialize
+)]
+#[clap(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+/// How to compute a perceptual hash.
+pub enum ImageHashKind {
+    /// Average hash. Fastest, but sensitive to changes in brightness.
+    AHash,
+    /// Difference hash. Fast, and robust against changes in brightness.
+    DHash,
+    #[default]
+    /// Perceptual hash based on the DCT. Slowest, but most robust.
+    PHash,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Options for finding similar images.
+pub struct SimilarityOptions {
+    /// How to hash images.
+    pub kind: ImageHashKind,
+    /// The maximum number of bits two hashes can differ by to be similar.
+    pub max_distance: u32,
+    /// The number of threads to use.
+    pub threads: NonZero<usize>,
+}
+
+/////////////
+// Hashing //
+/////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// A 64-bit perceptual hash of an image.
+pub struct ImageHash(pub u64);
+
+impl Metric for ImageHash {
+    fn distance(&self, other: &Self) -> u32 { self.0.distance(&other.0) }
+}
+
+/// Packs bits into a hash, with the first bit as most significant.
+fn from_bits(bits: impl Iterator<Item = bool>) -> ImageHash {
+    ImageHash(bits.fold(0, |hash, bit| (hash << 1) | u64::from(bit)))
+}
+
+fn average_hash(image: &GrayImage) -> ImageHash {
+    let small = resize(image, 8, 8, FilterType::Triangle);
+    let pixels = small.as_raw();
+    let mean = pixels.iter().map(|&p| u32::from(p)).sum::<u32>() / 64;
+    from_bits(pixels.iter().map(|&p| u32::from(p) > mean))
+}
+
+fn difference_hash(image: &GrayImage) -> ImageHash {
+    let small = resize(image, 9, 8, FilterType::Triangle);
+    from_bits(small.rows().flat_map(|row| {
+        let row: Vec<u8> = row.map(|p| p.0[0]).collect();
+        (0..8).map(move |x| row[x] < row[x + 1])
+    }))
+}
+
+fn perceptual_hash(image: &GrayImage) -> ImageHash {
+    const N: usize = 32;
+    const K: usize = 8;
+    let small = resize(image, N as u32, N as u32, FilterType::Triangle);
+    let pixels: Vec<f32> =
+        small.as_raw().iter().map(|&p| f32::from(p)).collect();
+
+    // DCT-II of the rows, then of the columns, keeping only low frequencies
+    let cosines: Vec<f32> = (0..K * N)
+        .map(|i| {
+            let (u, x) = (i / N, i % N);
+            let angle = std::f32::consts::PI * u as f32 * (2 * x + 1) as f32;
+            (angle / (2 * N) as f32).cos()
+        })
+        .collect();
+    let dct = |input: &[f32], stride: usize, u: usize| -> f32 {
+        (0..N).map(|x| input[x * stride] * cosines[u * N + x]).sum()
+    };
+    let rows: Vec<f32> = (0..N)
+        .flat_map(|y| (0..K).map(move |u| (y, u)))
+        .map(|(y, u)| dct(&pixels[y * N..], 1, u))
+        .collect();
+    let coefficients: Vec<f32> = (0..K)
+        .flat_map(|v| (0..K).map(move |u| (v, u)))
+        .map(|(v, u)| dct(&rows[u..], K, v))
+        .collect();
+
+    // The DC coefficient is the average brightness, so it is left out
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    from_bits(coefficients.iter().map(|&c| c > median))
+}
+
+impl ImageHashKind {
+    /// Computes the perceptual hash of the image at the path.
+    pub fn hash(self, path: &Path) -> crate::Result<ImageHash> {
+        let image = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+        let gray = image.to_luma8();
+        Ok(match self {
+            Self::AHash => average_hash(&gray),
+            Self::DHash => difference_hash(&gray),
+            Self::PHash => perceptual_hash(&gray),
+        })
+    }
+}
+
+/// Hashes images in parallel.
+/// Images that cannot be decoded are left out;
+/// images that crash the decoder are skipped.
+fn parallel_hash_images<'a>(
+    images: &[(&'a Path, u64)],
+    kind: ImageHashKind,
+    threads: NonZero<usize>,
+    tracker: &Tracker<'a>,
+) -> (Vec<(&'a Path, ImageHash)>, Vec<Skipped>) {
+    if images.is_empty() {
+        return (vec![], vec![]);
+    }
+    let chunk_size = images.len().div_ceil(threads.get());
+    let results: Vec<Vec<_>> = thread::scope(|scope| {
+        let workers: Vec<_> = images
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let hash = |&(path, size)| {
+                        tracker.start(path);
+                        // NB: Decoders of untrusted files are not above
+                        // panicking
+                        let result = panic::catch_unwind(|| kind.hash(path));
+                        tracker.read(size);
+                        tracker.finish();
+                        match result {
+                            Ok(Ok(hash)) => Some(Ok((path, hash))),
+                            Ok(Err(_)) => None,
+                            Err(_) => Some(Err(Skipped::new(
+                                path,
+                                anyhow!("decoder panicked"),
+                            ))),
+                        }
+                    };
+                    chunk.iter().filter_map(hash).collect()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().expect("panics are caught per image"))
+            .collect()
+    });
+    let (mut hashes, mut skipped) = (Vec::new(), Vec::new());
+    for result in results.into_iter().flatten() {
+        match result {
+            Ok(item) => hashes.push(item),
+            Err(error) => skipped.push(error),
+        }
+    }
+    (hashes, skipped)
+}
+
+//////////////
+// Grouping //
+//////////////
+
+/// Minimal union-find, to merge matches into groups.
+struct DisjointSet {
+    parents: Vec<usize>,
+}
+
+impl DisjointSet {
+    fn new(len: usize) -> Self { DisjointSet { parents: (0..len).collect() } }
+
+    fn find(&mut self, mut index: usize) -> usize {
+        while self.parents[index] != index {
+            self.parents[index] = self.parents[self.parents[index]];
+            index = self.parents[index];
+        }
+        index
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a.max(b)] = a.min(b);
+    }
+}
+
+#[derive(Debug, Default)]
+/// The images found by [`find_similar`] to look alike.
+pub struct SimilarImages<'a> {
+    /// Groups of images that look alike.
+    pub groups: Vec<Vec<(&'a Path, ImageHash)>>,
+    /// The images hashed, rather than known beforehand, so they can be cached.
+    pub hashed: Vec<(&'a Path, ImageHash)>,
+    /// The images that crashed the decoder.
+    pub skipped: Vec<Skipped>,
+}
+
+/// Groups images whose hashes are within the maximum distance,
+/// directly or through other images in the same group.
+///
+/// Images are given with their size, and their hash if known,
+/// in which case they are not decoded again.
+/// Groups and the files inside are sorted by path.
+/// Only groups of at least two images are returned;
+/// note these include byte-identical images.
+pub fn find_similar<'a>(
+    images: &[(&'a Path, u64, Option<ImageHash>)],
+    SimilarityOptions { kind, max_distance, threads }: SimilarityOptions,
+    progress: &mut dyn ProgressSink,
+) -> SimilarImages<'a> {
+    let unknown: Vec<(&Path, u64)> = images
+        .iter()
+        .filter(|(_, _, hash)| hash.is_none())
+        .map(|&(path, size, _)| (path, size))
+        .collect();
+    let bytes = unknown.iter().map(|(_, size)| size).sum();
+    let (new_hashes, skipped) =
+        track(Stage::Images, unknown.len(), bytes, progress, |tracker| {
+            parallel_hash_images(&unknown, kind, threads, tracker)
+        });
+    let hashed: Vec<(&Path, ImageHash)> = images
+        .iter()
+        .filter_map(|&(path, _, hash)| Some((path, hash?)))
+        .chain(new_hashes.iter().copied())
+        .collect();
+
+    // Identical hashes are merged up front, keeping the tree small
+    let mut indices_by_hash: HashMap<ImageHash, Vec<usize>> = HashMap::new();
+    for (index, (_, hash)) in hashed.iter().enumerate() {
+        indices_by_hash.entry(*hash).or_default().push(index);
+    }
+    let mut tree = BkTree::new();
+    for hash in indices_by_hash.keys() {
+        tree.insert(*hash);
+    }
+
+    let mut sets = DisjointSet::new(hashed.len());
+    for (hash, indices) in &indices_by_hash {
+        for (other, _) in tree.find(hash, max_distance) {
+            for &index in indices.iter().chain(&indices_by_hash[other]) {
+                sets.union(indices[0], index);
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<(&Path, ImageHash)>> = HashMap::new();
+    for (index, item) in hashed.iter().enumerate() {
+        groups.entry(sets.find(index)).or_default().push(*item);
+    }
+    let mut groups: Vec<_> =
+        groups.into_values().filter(|group| group.len() > 1).collect();
+    for group in &mut groups {
+        group.sort_by_key(|(path, _)| *path);
+    }
+    groups.sort_by_key(|group| group[0].0);
+    SimilarImages { groups, hashed: new_hashes, skipped }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    /// A smooth pattern, which looks the same at any size,
+    /// but different when mirrored.
+    fn pattern(width: u32, height: u32, flip: bool) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            let x = x as f32 / width as f32;
+            let y = y as f32 / height as f32;
+            let x = if flip { 1.0 - x } else { x };
+            let value = (5.0 * x).sin() * (3.0 * y + 2.0 * x * x).cos();
+            Luma([(127.5 + 127.5 * value) as u8])
+        })
+    }
+
+    #[test]
+    fn resized_images_are_similar() {
+        let hashes = [average_hash, difference_hash, perceptual_hash];
+        for hash in hashes {
+            let original = hash(&pattern(200, 150, false));
+            let resized = hash(&pattern(64, 48, false));
+            let mirrored = hash(&pattern(200, 150, true));
+            assert!(original.distance(&resized) <= 4);
+            assert!(original.distance(&mirrored) > 16);
+        }
+    }
+}
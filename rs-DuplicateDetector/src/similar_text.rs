@@ -0,0 +1,320 @@
+//! Items to find text files that are nearly the same.
+//!
+//! Each file is split into overlapping runs of words (shingles),
+//! and summarized by a MinHash signature.
+//! Signatures estimate the Jaccard similarity of the sets of shingles,
+//! and locality-sensitive hashing finds pairs worth comparing.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::num::NonZero;
+use std::panic;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+use anyhow::anyhow;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::archive::is_member;
+use crate::progress::ProgressSink;
+use crate::progress::Stage;
+use crate::progress::Tracker;
+use crate::progress::track;
+use crate::skip::Skipped;
+
+/// Number of words per shingle.
+const SHINGLE_WORDS: usize = 3;
+
+/// Number of hash functions in a signature.
+const SIGNATURE_LEN: usize = BANDS * ROWS;
+
+/// Number of bands used for locality-sensitive hashing.
+const BANDS: usize = 32;
+
+/// Number of hashes per band.
+/// Pairs with a similarity above roughly `(1 / BANDS) ^ (1 / ROWS)`,
+/// i.e. 42%, are likely to share a band.
+const ROWS: usize = 4;
+
+/// Files larger than this are not considered text.
+const MAX_TEXT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Number of bytes inspected to decide whether a file is text.
+const SNIFF_BYTES: u64 = 8 * 1024;
+
+/// File extensions of common formats that are never text.
+const BINARY_EXTENSIONS: &[&str] = &[
+    "7z", "a", "avi", "bin", "bmp", "bz2", "class", "dll", "dmg", "doc",
+    "docx", "exe", "flac", "gif", "gz", "ico", "iso", "jar", "jpeg", "jpg",
+    "mkv", "mov", "mp3", "mp4", "o", "ogg", "pdf", "png", "pyc", "so", "tar",
+    "tif", "tiff", "wav", "webm", "webp", "xls", "xlsx", "xz", "zip", "zst",
+];
+
+/// Whether the file at the path could be text, judging by its name.
+///
+/// Files inside archives are not considered, since they cannot be opened.
+pub fn is_likely_text(path: &Path) -> bool {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    !is_member(path) &&
+        !extension.is_some_and(|ext| {
+            BINARY_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e))
+        })
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Options for finding similar text files.
+pub struct TextSimilarityOptions {
+    /// The minimum estimated Jaccard similarity, between 0 and 1.
+    pub min_similarity: f64,
+    /// The number of threads to use.
+    pub threads: NonZero<usize>,
+}
+
+///////////////
+// Signature //
+///////////////
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A MinHash signature.
+pub struct Signature([u64; SIGNATURE_LEN]);
+
+/// Returns the next value of a SplitMix64 generator.
+fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Parameters `(a, b)` of the hash functions `a * x + b`.
+/// Every `a` is odd, so every function is a permutation.
+fn permutations() -> [(u64, u64); SIGNATURE_LEN] {
+    let mut state = 0;
+    std::array::from_fn(|_| (split_mix(&mut state) | 1, split_mix(&mut state)))
+}
+
+impl Signature {
+    /// Computes the signature of a text.
+    /// Returns [`None`] if the text has no shingles (i.e. is empty).
+    pub fn new(text: &str) -> Option<Self> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let shingles: HashSet<u64> = words
+            .windows(SHINGLE_WORDS.min(words.len()).max(1))
+            .map(|shingle| xxh3_64(shingle.join(" ").as_bytes()))
+            .collect();
+        if shingles.is_empty() {
+            return None;
+        }
+
+        let mut minimums = [u64::MAX; SIGNATURE_LEN];
+        for (minimum, (a, b)) in minimums.iter_mut().zip(permutations()) {
+            for shingle in &shingles {
+                let hash = shingle.wrapping_mul(a).wrapping_add(b);
+                *minimum = (*minimum).min(hash);
+            }
+        }
+        Some(Signature(minimums))
+    }
+
+    /// Estimates the Jaccard similarity of the underlying sets of shingles.
+    pub fn similarity(&self, other: &Self) -> f64 {
+        let equal = self.0.iter().zip(&other.0).filter(|(a, b)| a == b);
+        equal.count() as f64 / SIGNATURE_LEN as f64
+    }
+}
+
+/// Reads a file, if it looks like text.
+///
+/// Only the start of the file is read to decide,
+/// so binary files are not read in full.
+fn read_text(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    if size == 0 || size > MAX_TEXT_BYTES {
+        return None;
+    }
+    let mut bytes = Vec::new();
+    (&mut file).take(SNIFF_BYTES).read_to_end(&mut bytes).ok()?;
+    if bytes.contains(&0) {
+        return None;
+    }
+    bytes.reserve(size.saturating_sub(SNIFF_BYTES) as usize);
+    file.take(MAX_TEXT_BYTES - SNIFF_BYTES).read_to_end(&mut bytes).ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Computes signatures in parallel.
+/// Files that are not text are left out;
+/// files that crash the computation are skipped.
+fn parallel_signatures<'a>(
+    files: &[(&'a Path, u64)],
+    threads: NonZero<usize>,
+    tracker: &Tracker<'a>,
+) -> (Vec<(&'a Path, Signature)>, Vec<Skipped>) {
+    if files.is_empty() {
+        return (vec![], vec![]);
+    }
+    let chunk_size = files.len().div_ceil(threads.get());
+    let results: Vec<Vec<_>> = thread::scope(|scope| {
+        let workers: Vec<_> = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let sign = |&(path, size)| {
+                        tracker.start(path);
+                        let sign = || Signature::new(&read_text(path)?);
+                        let result = panic::catch_unwind(sign);
+                        tracker.read(size);
+                        tracker.finish();
+                        match result {
+                            Ok(signature) => Some(Ok((path, signature?))),
+                            Err(_) => Some(Err(Skipped::new(
+                                path,
+                                anyhow!("reading the text panicked"),
+                            ))),
+                        }
+                    };
+                    chunk.iter().filter_map(sign).collect()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().expect("panics are caught per file"))
+            .collect()
+    });
+    let (mut signed, mut skipped) = (Vec::new(), Vec::new());
+    for result in results.into_iter().flatten() {
+        match result {
+            Ok(item) => signed.push(item),
+            Err(error) => skipped.push(error),
+        }
+    }
+    (signed, skipped)
+}
+
+///////////
+// Pairs //
+///////////
+
+#[derive(Debug, Clone, PartialEq)]
+/// Two text files that are nearly the same.
+pub struct SimilarPair {
+    /// The first file; sorts before the second.
+    pub first: PathBuf,
+    /// The second file.
+    pub second: PathBuf,
+    /// The estimated Jaccard similarity, between 0 and 1.
+    pub similarity: f64,
+}
+
+#[derive(Debug, Default)]
+/// The text files found by [`find_similar_text`] to be nearly the same.
+pub struct SimilarText {
+    /// Pairs of files that are nearly the same.
+    pub pairs: Vec<SimilarPair>,
+    /// The files that crashed the computation.
+    pub skipped: Vec<Skipped>,
+}
+
+/// Finds pairs of text files with at least the minimum similarity.
+///
+/// Files are given with their size, to report progress in bytes.
+/// Pairs are sorted by decreasing similarity, then by path.
+/// Note these include byte-identical files.
+pub fn find_similar_text(
+    files: &[(&Path, u64)],
+    TextSimilarityOptions { min_similarity, threads }: TextSimilarityOptions,
+    progress: &mut dyn ProgressSink,
+) -> SimilarText {
+    let bytes = files.iter().map(|(_, size)| size).sum();
+    let (mut signed, skipped) =
+        track(Stage::Text, files.len(), bytes, progress, |tracker| {
+            parallel_signatures(files, threads, tracker)
+        });
+    signed.sort_by_key(|(path, _)| *path);
+
+    // Files which agree on every row of a band are candidates
+    let mut candidates: HashSet<(usize, usize)> = HashSet::new();
+    for band in 0..BANDS {
+        let rows = band * ROWS..(band + 1) * ROWS;
+        let mut buckets: HashMap<&[u64], Vec<usize>> = HashMap::new();
+        for (index, (_, signature)) in signed.iter().enumerate() {
+            buckets.entry(&signature.0[rows.clone()]).or_default().push(index);
+        }
+        for bucket in buckets.values() {
+            for (i, &a) in bucket.iter().enumerate() {
+                candidates.extend(bucket[i + 1..].iter().map(|&b| (a, b)));
+            }
+        }
+    }
+
+    let mut pairs: Vec<SimilarPair> = candidates
+        .into_iter()
+        .filter_map(|(a, b)| {
+            let similarity = signed[a].1.similarity(&signed[b].1);
+            (similarity >= min_similarity).then_some(SimilarPair {
+                first: signed[a].0.to_path_buf(),
+                second: signed[b].0.to_path_buf(),
+                similarity,
+            })
+        })
+        .collect();
+    pairs.sort_by(|a, b| {
+        b.similarity
+            .total_cmp(&a.similarity)
+            .then(a.first.cmp(&b.first))
+            .then(a.second.cmp(&b.second))
+    });
+    SimilarText { pairs, skipped }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity_estimates_jaccard() {
+        let lines: Vec<String> =
+            (0..200).map(|i| format!("key{} = value{}", i, i)).collect();
+        let original = lines.join("\n");
+        let mut forked = lines.clone();
+        forked[100] = "key100 = changed".into();
+        let forked = forked.join("\n");
+        let unrelated: String =
+            (0..200).map(|i| format!("other {} line\n", i * 7)).collect();
+
+        let original = Signature::new(&original).unwrap();
+        let forked = Signature::new(&forked).unwrap();
+        let unrelated = Signature::new(&unrelated).unwrap();
+        assert_eq!(original.similarity(&original), 1.0);
+        assert!(original.similarity(&forked) > 0.9);
+        assert!(original.similarity(&unrelated) < 0.1);
+        assert!(Signature::new(" \n ").is_none());
+    }
+
+    #[test]
+    fn only_text_is_read() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-text", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (text, binary) = (dir.join("app.conf"), dir.join("blob"));
+        std::fs::write(&text, "a = 1\n".repeat(2000)).unwrap();
+        let mut bytes = vec![b'a'; 2 * SNIFF_BYTES as usize];
+        bytes[10] = 0;
+        std::fs::write(&binary, bytes).unwrap();
+        let (text, binary) = (read_text(&text), read_text(&binary));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(text.map(|text| text.len()), Some(12_000));
+        assert_eq!(binary, None);
+        assert!(is_likely_text(Path::new("/r/Makefile")));
+        assert!(is_likely_text(Path::new("/r/nginx.conf")));
+        assert!(!is_likely_text(Path::new("/r/photo.JPG")));
+        assert!(!is_likely_text(Path::new("/r/x.zip!/notes.txt")));
+    }
+}
@@ -0,0 +1,108 @@
+//! Items to record files that could not be read, so a search can go on.
+
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Why a file was skipped.
+pub enum SkipReason {
+    /// The file could not be opened for lack of permission.
+    PermissionDenied,
+    /// The file was deleted (or moved) after it was found.
+    Vanished,
+    /// Reading the file failed otherwise.
+    Io,
+}
+
+impl SkipReason {
+    /// Classifies an error, judging by the first I/O error in its chain.
+    pub fn of(error: &crate::Error) -> Self {
+        let kind = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+            .map(|error| error.kind());
+        match kind {
+            Some(ErrorKind::PermissionDenied) => SkipReason::PermissionDenied,
+            Some(ErrorKind::NotFound) => SkipReason::Vanished,
+            _ => SkipReason::Io,
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkipReason::PermissionDenied => "permission denied",
+            SkipReason::Vanished => "vanished",
+            SkipReason::Io => "I/O error",
+        }
+        .fmt(f)
+    }
+}
+
+#[derive(Debug)]
+/// A file (or directory, or archive) that could not be read.
+pub struct Skipped {
+    /// The path that could not be read.
+    pub path: PathBuf,
+    /// Why it could not be read.
+    pub reason: SkipReason,
+    /// The underlying error.
+    pub error: crate::Error,
+}
+
+impl Skipped {
+    /// Records that the path was skipped because of the error.
+    pub fn new(
+        path: impl Into<PathBuf>,
+        error: impl Into<crate::Error>,
+    ) -> Self {
+        let error = error.into();
+        Skipped { path: path.into(), reason: SkipReason::of(&error), error }
+    }
+}
+
+/// Counts the skipped paths for each reason, in order of reason.
+pub fn count_reasons(skipped: &[Skipped]) -> Vec<(SkipReason, usize)> {
+    let mut counts: Vec<(SkipReason, usize)> = Vec::new();
+    let mut reasons: Vec<SkipReason> =
+        skipped.iter().map(|s| s.reason).collect();
+    reasons.sort();
+    for reason in reasons {
+        match counts.last_mut() {
+            Some((last, count)) if *last == reason => *count += 1,
+            _ => counts.push((reason, 1)),
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn reasons_come_from_the_io_error() {
+        let denied = Err::<(), _>(io::Error::from(ErrorKind::PermissionDenied))
+            .context("failed to hash 'a'")
+            .unwrap_err();
+        let gone = io::Error::from(ErrorKind::NotFound);
+        let other = anyhow::anyhow!("invalid zip archive");
+        let skipped = [
+            Skipped::new("a", denied),
+            Skipped::new("b", gone),
+            Skipped::new("c", other),
+            Skipped::new("d", io::Error::from(ErrorKind::NotFound)),
+        ];
+        assert_eq!(skipped[0].reason, SkipReason::PermissionDenied);
+        assert_eq!(count_reasons(&skipped), vec![
+            (SkipReason::PermissionDenied, 1),
+            (SkipReason::Vanished, 2),
+            (SkipReason::Io, 1),
+        ]);
+    }
+}
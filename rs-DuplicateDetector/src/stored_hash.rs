@@ -0,0 +1,99 @@
+//! Items to store the hash of a file next to the file itself,
+//! using [extended attributes](crate::core::xattr).
+//!
+//! The fingerprint of the file is stored alongside the hash,
+//! so that a stale hash (i.e. the file was edited afterwards) is never used.
+
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::core::xattr::get_xattr;
+use crate::core::xattr::set_xattr;
+use crate::fingerprint::Fingerprint;
+use crate::hash::FileHash;
+
+/// Name of the attribute that holds the hash.
+const ATTRIBUTE_NAME: &str = "duplicate-detector.hash";
+
+#[derive(Debug, Serialize, Deserialize)]
+/// The contents of the attribute.
+struct StoredHash {
+    fingerprint: Fingerprint,
+    hash: FileHash,
+}
+
+/// Reads the hash stored next to a file with the given (current) fingerprint.
+///
+/// Returns [`None`] if there is no hash, if it is stale,
+/// or if the file system doesn't support extended attributes.
+pub fn read_stored_hash(
+    path: &Path,
+    current: &Fingerprint,
+) -> Option<FileHash> {
+    let bytes = get_xattr(path, ATTRIBUTE_NAME).ok()?;
+    let stored: StoredHash = rmp_serde::from_slice(&bytes).ok()?;
+    (stored.fingerprint == *current).then_some(stored.hash)
+}
+
+/// Stores a hash next to a file.
+/// The fingerprint should be taken _before_ hashing the file.
+///
+/// Callers are expected to ignore failure,
+/// since not every file system supports extended attributes.
+pub fn write_stored_hash(
+    path: &Path,
+    fingerprint: Fingerprint,
+    hash: FileHash,
+) -> crate::Result {
+    let stored = StoredHash { fingerprint, hash };
+    set_xattr(path, ATTRIBUTE_NAME, &rmp_serde::to_vec(&stored)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::hash::HashAlgorithm;
+
+    #[test]
+    fn stale_or_missing_hashes_are_ignored() {
+        let path = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-stored", std::process::id()));
+        fs::write(&path, "contents").unwrap();
+        let fingerprint = Fingerprint::from_path(&path).unwrap();
+        let hash = FileHash::new(HashAlgorithm::Xxh3, &[1; 16]).unwrap();
+        let missing = read_stored_hash(&path, &fingerprint);
+        let written = write_stored_hash(&path, fingerprint, hash);
+        let stored = read_stored_hash(&path, &fingerprint);
+        let edited = Fingerprint {
+            modified: fingerprint.modified + Duration::from_secs(1),
+            ..fingerprint
+        };
+        let stale = read_stored_hash(&path, &edited);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(missing, None);
+        assert_eq!(stale, None);
+        // Without xattrs, callers fall back to hashing the file
+        match written {
+            Ok(()) => assert_eq!(stored, Some(hash)),
+            Err(_) => assert_eq!(stored, None),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unsupported_files_fall_back() {
+        // The `user.` namespace is reserved for regular files and directories
+        let path = Path::new("/dev/null");
+        let fingerprint = Fingerprint::from_path(path).unwrap();
+        let hash = FileHash::new(HashAlgorithm::Xxh3, &[1; 16]).unwrap();
+        assert!(write_stored_hash(path, fingerprint, hash).is_err());
+        assert_eq!(read_stored_hash(path, &fingerprint), None);
+    }
+}
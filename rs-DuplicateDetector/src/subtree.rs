@@ -0,0 +1,332 @@
+//! Items to find directories with the same contents.
+//!
+//! Directories are hashed like a Merkle tree:
+//! the hash of a directory covers the names and hashes of its children.
+//! A directory has no hash if any file inside has none;
+//! such a file has a unique size, so the directory has no copy either.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::hash::FileHash;
+
+#[derive(Debug, Clone, Copy)]
+/// Options for finding duplicate directories.
+pub struct DirectoryOptions {
+    /// Whether to report directories containing everything in another.
+    pub supersets: bool,
+}
+
+/// A file as input to directory hashing.
+#[derive(Debug, Clone, Copy)]
+pub struct FileInfo<'a> {
+    /// The size of the file, in bytes.
+    pub size: u64,
+    /// The full hash of the file, if known.
+    pub hash: Option<&'a FileHash>,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Aggregate information on a directory.
+pub struct DirInfo {
+    /// The Merkle hash, if every file inside has a hash.
+    pub hash: Option<blake3::Hash>,
+    /// Number of files inside, recursively.
+    pub file_count: usize,
+    /// Total size of the files inside, recursively.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+/// A directory that contains every file of another, and more.
+pub struct Superset {
+    /// The directory containing everything.
+    pub superset: PathBuf,
+    /// The directory whose files are all in the superset.
+    pub subset: PathBuf,
+}
+
+#[derive(Debug, Default)]
+/// The duplicate directories found.
+pub struct DirectoryFindings {
+    /// Groups of identical directories, sorted by path.
+    pub identical: Vec<Vec<PathBuf>>,
+    /// Directories containing another, sorted by path.
+    pub supersets: Vec<Superset>,
+    /// Information on every directory.
+    pub info: HashMap<PathBuf, DirInfo>,
+    /// Every directory in `identical`, to look up quickly.
+    covered: HashSet<PathBuf>,
+}
+
+impl DirectoryFindings {
+    /// Whether the path lies inside a reported identical directory.
+    ///
+    /// Takes time in the depth of the path, not the number of directories.
+    pub fn is_covered(&self, path: &Path) -> bool {
+        !self.covered.is_empty() &&
+            path.ancestors().skip(1).any(|dir| self.covered.contains(dir))
+    }
+}
+
+/////////////
+// Hashing //
+/////////////
+
+enum Child {
+    File(Option<FileHash>),
+    Dir(Option<blake3::Hash>),
+}
+
+/// Returns the outermost root containing the path.
+fn outermost_root<'a>(roots: &'a [PathBuf], path: &Path) -> Option<&'a Path> {
+    roots
+        .iter()
+        .filter(|root| path.starts_with(root))
+        .min_by_key(|root| root.components().count())
+        .map(|root| root.as_path())
+}
+
+/// Computes information on every directory under the roots.
+fn hash_directories(
+    roots: &[PathBuf],
+    files: &HashMap<&Path, FileInfo>,
+) -> HashMap<PathBuf, DirInfo> {
+    let mut children: HashMap<&Path, BTreeMap<&OsStr, &Path>> = HashMap::new();
+    let mut info: HashMap<PathBuf, DirInfo> = HashMap::new();
+    for (&path, file) in files {
+        let Some(root) = outermost_root(roots, path) else { continue };
+        let mut child = path;
+        while let Some(dir) = child.parent() &&
+            dir.starts_with(root)
+        {
+            let entry = info.entry(dir.to_path_buf()).or_insert(DirInfo {
+                hash: None,
+                file_count: 0,
+                size: 0,
+            });
+            entry.file_count += 1;
+            entry.size += file.size;
+            if let Some(name) = child.file_name() {
+                children.entry(dir).or_default().insert(name, child);
+            }
+            child = dir;
+        }
+    }
+
+    // Children must be hashed before their parents
+    let mut dirs: Vec<&Path> = children.keys().copied().collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs {
+        let mut hasher = blake3::Hasher::new();
+        let mut is_complete = true;
+        for (name, &path) in &children[dir] {
+            let child = match files.get(path) {
+                Some(file) => Child::File(file.hash.copied()),
+                None => Child::Dir(info[path].hash),
+            };
+            let name = name.as_encoded_bytes();
+            hasher.update(&(name.len() as u64).to_le_bytes());
+            hasher.update(name);
+            match child {
+                Child::File(Some(hash)) => {
+                    hasher.update(b"f");
+                    hasher.update(hash.bytes());
+                },
+                Child::Dir(Some(hash)) => {
+                    hasher.update(b"d");
+                    hasher.update(hash.as_bytes());
+                },
+                Child::File(None) | Child::Dir(None) => is_complete = false,
+            }
+        }
+        if let Some(entry) = info.get_mut(dir) {
+            entry.hash = is_complete.then(|| hasher.finalize());
+        }
+    }
+    info
+}
+
+/////////////
+// Finding //
+/////////////
+
+/// Groups directories with the same hash,
+/// leaving out groups whose members lie in larger identical directories.
+fn find_identical(info: &HashMap<PathBuf, DirInfo>) -> Vec<Vec<PathBuf>> {
+    let mut by_hash: HashMap<blake3::Hash, Vec<&Path>> = HashMap::new();
+    for (dir, dir_info) in info {
+        if let Some(hash) = dir_info.hash &&
+            dir_info.file_count > 0
+        {
+            by_hash.entry(hash).or_default().push(dir);
+        }
+    }
+    let is_duplicate = |dir: &Path| {
+        let hash = info.get(dir).and_then(|info| info.hash);
+        hash.is_some_and(|hash| by_hash[&hash].len() > 1)
+    };
+
+    let mut groups: Vec<Vec<PathBuf>> = by_hash
+        .values()
+        .filter(|dirs| dirs.len() > 1)
+        .filter(|dirs| {
+            // Shown as part of the parent, unless there is a copy elsewhere
+            !dirs.iter().all(|dir| dir.parent().is_some_and(is_duplicate))
+        })
+        .map(|dirs| dirs.iter().map(|dir| dir.to_path_buf()).collect())
+        .collect();
+    for group in &mut groups {
+        group.sort();
+    }
+    groups.sort();
+    groups
+}
+
+/// Finds directories containing every file of another directory,
+/// at the same relative path and with the same hash.
+fn find_supersets(
+    files: &HashMap<&Path, FileInfo>,
+    info: &HashMap<PathBuf, DirInfo>,
+) -> Vec<Superset> {
+    let mut by_hash: HashMap<&FileHash, Vec<&Path>> = HashMap::new();
+    let mut files_in: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    for (&path, file) in files {
+        if let Some(hash) = file.hash {
+            by_hash.entry(hash).or_default().push(path);
+        }
+        for dir in path.ancestors().skip(1) {
+            if !info.contains_key(dir) {
+                break;
+            }
+            files_in.entry(dir).or_default().push(path);
+        }
+    }
+
+    let mut pairs: HashSet<(&Path, &Path)> = HashSet::new();
+    for (subset, contents) in &files_in {
+        let Some(subset_hash) = info[*subset].hash else { continue };
+        let Some(anchor) = contents.first() else { continue };
+        let anchor_hash = files[anchor].hash.expect("directory has a hash");
+        let anchor_relative = anchor.strip_prefix(subset).unwrap();
+
+        for other in &by_hash[anchor_hash] {
+            let depth = anchor_relative.components().count();
+            let Some(superset) = other.ancestors().nth(depth) else {
+                continue;
+            };
+            if !other.ends_with(anchor_relative) ||
+                superset.starts_with(subset) ||
+                subset.starts_with(superset) ||
+                info.get(superset).and_then(|i| i.hash) == Some(subset_hash)
+            {
+                continue;
+            }
+            let contains_all = contents.iter().all(|file| {
+                let relative = file.strip_prefix(subset).unwrap();
+                let counterpart = superset.join(relative);
+                files.get(counterpart.as_path()).and_then(|f| f.hash) ==
+                    files[file].hash
+            });
+            if contains_all {
+                pairs.insert((superset, subset));
+            }
+        }
+    }
+
+    // Shown as part of the parents, if those contain one another too
+    let mut supersets: Vec<Superset> = pairs
+        .iter()
+        .filter(|(superset, subset)| {
+            let parents = superset.parent().zip(subset.parent());
+            !parents.is_some_and(|parents| pairs.contains(&parents))
+        })
+        .map(|(superset, subset)| Superset {
+            superset: superset.to_path_buf(),
+            subset: subset.to_path_buf(),
+        })
+        .collect();
+    supersets.sort_by(|a, b| {
+        (&a.superset, &a.subset).cmp(&(&b.superset, &b.subset))
+    });
+    supersets
+}
+
+/// Finds duplicate directories under the roots.
+///
+/// Files must be given by absolute path, like the roots.
+pub fn find_duplicate_directories(
+    roots: &[PathBuf],
+    files: &HashMap<&Path, FileInfo>,
+    DirectoryOptions { supersets }: DirectoryOptions,
+) -> DirectoryFindings {
+    let info = hash_directories(roots, files);
+    let identical = find_identical(&info);
+    DirectoryFindings {
+        covered: identical.iter().flatten().cloned().collect(),
+        identical,
+        supersets: match supersets {
+            true => find_supersets(files, &info),
+            false => vec![],
+        },
+        info,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::HashAlgorithm;
+
+    fn hash(byte: u8) -> FileHash {
+        FileHash::new(HashAlgorithm::Xxh3, &[byte; 16]).unwrap()
+    }
+
+    #[test]
+    fn copied_tree_is_one_finding() {
+        let (a, b, c) = (hash(1), hash(2), hash(3));
+        let file = |hash| FileInfo { size: 1, hash: Some(hash) };
+        let files: HashMap<&Path, FileInfo> = [
+            (Path::new("/r/one/x"), file(&a)),
+            (Path::new("/r/one/sub/y"), file(&b)),
+            (Path::new("/r/two/x"), file(&a)),
+            (Path::new("/r/two/sub/y"), file(&b)),
+            (Path::new("/r/three/x"), file(&a)),
+            (Path::new("/r/three/sub/y"), file(&b)),
+            (Path::new("/r/three/z"), file(&c)),
+        ]
+        .into_iter()
+        .collect();
+        let roots = [PathBuf::from("/r")];
+        let options = DirectoryOptions { supersets: true };
+        let findings = find_duplicate_directories(&roots, &files, options);
+
+        // The subdirectory has a third copy, so it is reported too
+        let identical: Vec<Vec<PathBuf>> =
+            vec![vec!["/r/one".into(), "/r/two".into()], vec![
+                "/r/one/sub".into(),
+                "/r/three/sub".into(),
+                "/r/two/sub".into(),
+            ]];
+        assert_eq!(findings.identical, identical);
+        assert!(findings.is_covered(Path::new("/r/one/sub/y")));
+        assert!(findings.is_covered(Path::new("/r/three/sub/y")));
+        assert!(!findings.is_covered(Path::new("/r/three/x")));
+        assert!(!findings.is_covered(Path::new("/r/one")));
+        assert!(findings.is_covered(Path::new("/r/one/sub")));
+
+        let supersets: Vec<(PathBuf, PathBuf)> = findings
+            .supersets
+            .into_iter()
+            .map(|s| (s.superset, s.subset))
+            .collect();
+        assert_eq!(supersets, vec![
+            ("/r/three".into(), "/r/one".into()),
+            ("/r/three".into(), "/r/two".into()),
+        ]);
+    }
+}
@@ -0,0 +1,595 @@
+//! A full-screen terminal interface to browse duplicates.
+//!
+//! Groups are listed by wasted bytes, largest first.
+//! Files can be marked to delete or keep;
+//! marked files are deleted (after confirmation) when quitting.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::io::stdout;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::StyleOptions;
+use crate::action::ActionKind;
+use crate::action::apply_verified;
+use crate::archive::canonicalize;
+use crate::archive::is_member;
+use crate::archive::read_member;
+use crate::core::ansi::Bold;
+use crate::core::ansi::CURSOR_HOME;
+use crate::core::ansi::Faint;
+use crate::core::ansi::Inverted;
+use crate::core::fs::open_explorer;
+use crate::core::fs::reveal_with;
+use crate::core::term::Key;
+use crate::core::term::Terminal;
+use crate::core::time::Timestamp;
+use crate::core::units::Bytes;
+use crate::fingerprint::Fingerprint;
+use crate::hash::FileHash;
+use crate::report::DuplicateGroup;
+
+/////////////
+// Options //
+/////////////
+
+#[derive(Debug, Default, Clone)]
+/// Options for the interface.
+pub struct BrowseOptions {
+    /// Command to reveal a file in a file manager.
+    /// See [`reveal_with`] for the syntax.
+    /// Uses the default file manager if not set.
+    pub reveal_command: Option<String>,
+}
+
+///////////
+// Model //
+///////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    None,
+    Delete,
+    Keep,
+}
+
+struct Entry<'a> {
+    path: &'a Path,
+    fingerprint: Fingerprint,
+    mark: Mark,
+}
+
+struct Group<'a> {
+    hash: &'a FileHash,
+    files: Vec<Entry<'a>>,
+    wasted: u64,
+}
+
+impl Group<'_> {
+    /// The file that remains if all files marked for deletion are deleted.
+    fn survivor(&self) -> Option<&Path> {
+        let find = |mark| self.files.iter().find(|e| e.mark == mark);
+        find(Mark::Keep).or_else(|| find(Mark::None)).map(|e| e.path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Groups,
+    Files,
+}
+
+enum Flow {
+    Continue,
+    Quit,
+}
+
+struct Browser<'a> {
+    groups: Vec<Group<'a>>,
+    pane: Pane,
+    group_index: usize,
+    file_index: usize,
+    status: String,
+    style: StyleOptions,
+    options: BrowseOptions,
+}
+
+///////////////
+// Rendering //
+///////////////
+
+/// Truncates or pads text to exactly the given number of columns.
+fn fit(text: &str, width: usize) -> String {
+    let mut result: String = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(width)
+        .collect();
+    let len = result.chars().count();
+    result.extend(std::iter::repeat_n(' ', width - len));
+    result
+}
+
+/// First index to show, such that the selected index is visible.
+fn scroll_offset(selected: usize, height: usize) -> usize {
+    (selected + 1).saturating_sub(height)
+}
+
+/// Reads the start of a file as lines of text,
+/// or as a hex dump if the file is not text.
+fn preview(path: &Path, max_lines: usize) -> Vec<String> {
+    const PREVIEW_BYTES: u64 = 4 * 1024;
+    let mut buffer = Vec::new();
+    let read = match is_member(path) {
+        true => read_member(path, |reader, _| {
+            Ok(reader.take(PREVIEW_BYTES).read_to_end(&mut buffer)?)
+        }),
+        false => File::open(path)
+            .and_then(|file| file.take(PREVIEW_BYTES).read_to_end(&mut buffer))
+            .map_err(Into::into),
+    };
+    if let Err(error) = read {
+        return vec![format!("(cannot preview: {})", error)];
+    }
+
+    let text = match std::str::from_utf8(&buffer) {
+        Ok(text) => Some(text),
+        // Cut-off in the middle of a character is fine
+        Err(error) if error.error_len().is_none() => {
+            Some(std::str::from_utf8(&buffer[..error.valid_up_to()]).unwrap())
+        },
+        Err(_) => None,
+    };
+    match text {
+        Some(text) if !text.contains('\0') => text
+            .lines()
+            .take(max_lines)
+            .map(|line| line.replace('\t', "    "))
+            .collect(),
+        _ => buffer
+            .chunks(16)
+            .take(max_lines)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut line = format!("{:08X} ", index * 16);
+                for byte in chunk {
+                    let _ = write!(line, " {:02X}", byte);
+                }
+                line
+            })
+            .collect(),
+    }
+}
+
+impl Browser<'_> {
+    fn group(&self) -> Option<&Group<'_>> { self.groups.get(self.group_index) }
+
+    fn entry(&self) -> Option<&Entry<'_>> {
+        self.group()?.files.get(self.file_index)
+    }
+
+    fn render(&self, rows: usize, cols: usize) -> String {
+        let body_height = rows.saturating_sub(2);
+        let left_width = (cols * 2 / 5).max(20).min(cols);
+        let right_width = cols.saturating_sub(left_width + 1);
+
+        // Left pane: groups
+        let mut left = Vec::with_capacity(body_height);
+        let offset = scroll_offset(self.group_index, body_height);
+        for (index, group) in
+            self.groups.iter().enumerate().skip(offset).take(body_height)
+        {
+            let size = group.files.first().map_or(0, |e| e.fingerprint.size);
+            let text = fit(
+                &format!(
+                    " {}× {} ({} wasted)",
+                    group.files.len(),
+                    Bytes(size),
+                    Bytes(group.wasted),
+                ),
+                left_width,
+            );
+            left.push(match (index == self.group_index, self.pane) {
+                (true, Pane::Groups) => Inverted(text).to_string(),
+                (true, Pane::Files) => Bold(text).to_string(),
+                (false, _) => text,
+            });
+        }
+
+        // Right pane: files, metadata and preview
+        let mut right = Vec::with_capacity(body_height);
+        if let Some(group) = self.group() {
+            let list_height = (body_height / 3).max(1).min(group.files.len());
+            let offset = scroll_offset(self.file_index, list_height);
+            for (index, entry) in
+                group.files.iter().enumerate().skip(offset).take(list_height)
+            {
+                let mark = match entry.mark {
+                    Mark::None => "[ ]",
+                    Mark::Delete => "[D]",
+                    Mark::Keep => "[K]",
+                };
+                let path = self.style.path.format(entry.path);
+                let text =
+                    fit(&format!(" {} {}", mark, path.display()), right_width);
+                right.push(match (index == self.file_index, self.pane) {
+                    (true, Pane::Files) => Inverted(text).to_string(),
+                    _ => text,
+                });
+            }
+            right.push(fit(&"─".repeat(right_width), right_width));
+
+            if let Some(entry) = self.entry() {
+                let canonical = canonicalize(entry.path);
+                let canonical = canonical.as_deref().unwrap_or(entry.path);
+                let metadata = [
+                    format!(" Path:     {}", canonical.display()),
+                    format!(" Size:     {}", Bytes(entry.fingerprint.size)),
+                    format!(
+                        " Modified: {}",
+                        Timestamp(entry.fingerprint.modified)
+                    ),
+                    format!(
+                        " Hash:     {}",
+                        self.style.hash.format(group.hash)
+                    ),
+                ];
+                for line in metadata {
+                    right.push(fit(&line, right_width));
+                }
+                right.push(fit(&"─".repeat(right_width), right_width));
+
+                let remaining = body_height.saturating_sub(right.len());
+                for line in preview(entry.path, remaining) {
+                    let line = fit(&format!(" {}", line), right_width);
+                    right.push(Faint(line).to_string());
+                }
+            }
+        }
+
+        // Compose
+        let wasted: u64 = self.groups.iter().map(|g| g.wasted).sum();
+        let title = format!(
+            " {} group(s) of duplicates, {} reclaimable",
+            self.groups.len(),
+            Bytes(wasted),
+        );
+        let status = match self.status.is_empty() {
+            true => {
+                " ↑↓ move  ←→ switch pane  d delete  s keep  u unmark  \
+                     x keep only this  o reveal  q quit"
+            },
+            false => &self.status,
+        };
+
+        let blank_left = fit("", left_width);
+        let blank_right = fit("", right_width);
+        let mut frame = String::new();
+        frame.push_str(CURSOR_HOME);
+        frame.push_str(&Inverted(fit(&title, cols)).to_string());
+        for row in 0..body_height {
+            frame.push_str("\r\n");
+            frame.push_str(left.get(row).unwrap_or(&blank_left));
+            frame.push('│');
+            frame.push_str(right.get(row).unwrap_or(&blank_right));
+        }
+        frame.push_str("\r\n");
+        frame.push_str(&fit(status, cols));
+        frame
+    }
+}
+
+//////////////
+// Handling //
+//////////////
+
+impl Browser<'_> {
+    fn move_selection(&mut self, delta: isize) {
+        let (index, len) = match self.pane {
+            Pane::Groups => (&mut self.group_index, self.groups.len()),
+            Pane::Files => {
+                let len = self.groups[self.group_index].files.len();
+                (&mut self.file_index, len)
+            },
+        };
+        let max = len.saturating_sub(1) as isize;
+        *index = (*index as isize + delta).clamp(0, max) as usize;
+        if self.pane == Pane::Groups {
+            self.file_index = 0;
+        }
+    }
+
+    fn set_mark(&mut self, mark: Mark) {
+        let file_index = self.file_index;
+        let Some(group) = self.groups.get_mut(self.group_index) else {
+            return;
+        };
+        let survivors = group
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(i, e)| *i != file_index && e.mark != Mark::Delete)
+            .count();
+        if mark == Mark::Delete && survivors == 0 {
+            self.status = " Cannot delete every copy of a file".into();
+            return;
+        }
+        group.files[file_index].mark = mark;
+    }
+
+    fn keep_only_selected(&mut self) {
+        let file_index = self.file_index;
+        let Some(group) = self.groups.get_mut(self.group_index) else {
+            return;
+        };
+        for (index, entry) in group.files.iter_mut().enumerate() {
+            entry.mark = match index == file_index {
+                true => Mark::Keep,
+                false => Mark::Delete,
+            };
+        }
+    }
+
+    fn reveal(&mut self) {
+        let Some(entry) = self.entry() else { return };
+        let path = entry.path.to_path_buf();
+        let result = match &self.options.reveal_command {
+            Some(command) => reveal_with(command, &path),
+            None => open_explorer(&path),
+        };
+        if let Err(error) = result {
+            self.status = format!(" Failed to reveal file: {}", error);
+        }
+    }
+
+    fn handle(&mut self, key: Key) -> Flow {
+        if let Key::Resize = key {
+            return Flow::Continue;
+        }
+        self.status.clear();
+        if self.groups.is_empty() {
+            return Flow::Quit;
+        }
+        match (key, self.pane) {
+            (Key::Up | Key::Char('k'), _) => self.move_selection(-1),
+            (Key::Down | Key::Char('j'), _) => self.move_selection(1),
+            (Key::PageUp, _) => self.move_selection(-10),
+            (Key::PageDown, _) => self.move_selection(10),
+            (Key::Right | Key::Char('l') | Key::Enter, Pane::Groups) |
+            (Key::Tab, Pane::Groups) => self.pane = Pane::Files,
+            (Key::Left | Key::Char('h') | Key::Escape, Pane::Files) |
+            (Key::Tab, Pane::Files) => self.pane = Pane::Groups,
+            (Key::Char('d'), Pane::Files) => self.set_mark(Mark::Delete),
+            (Key::Char('s'), Pane::Files) => self.set_mark(Mark::Keep),
+            (Key::Char('u'), Pane::Files) => self.set_mark(Mark::None),
+            (Key::Char('x'), Pane::Files) => self.keep_only_selected(),
+            (Key::Char('o'), _) => self.reveal(),
+            (Key::Char('q'), _) => return Flow::Quit,
+            _ => {},
+        }
+        Flow::Continue
+    }
+
+    /// Deletes all files marked for deletion.
+    /// Returns a message per file.
+    fn delete_marked(&self) -> Vec<String> {
+        let mut messages = Vec::new();
+        for group in &self.groups {
+            let Some(survivor) = group.survivor() else { continue };
+            for entry in &group.files {
+                if entry.mark != Mark::Delete {
+                    continue;
+                }
+                let kind = ActionKind::Delete;
+                messages.push(
+                    match apply_verified(kind, survivor, entry.path, group.hash)
+                    {
+                        Ok(()) => format!("deleted '{}'", entry.path.display()),
+                        Err(error) => format!("skipped: {:#}", error),
+                    },
+                );
+            }
+        }
+        messages
+    }
+
+    fn marked_count(&self) -> usize {
+        self.groups
+            .iter()
+            .flat_map(|g| &g.files)
+            .filter(|e| e.mark == Mark::Delete)
+            .count()
+    }
+}
+
+//////////
+// Main //
+//////////
+
+/// Shows the interface until the user quits.
+///
+/// Files without a known fingerprint are left out,
+/// as are groups with fewer than two files left.
+pub fn browse(
+    duplicates: &[DuplicateGroup],
+    fingerprints: &HashMap<PathBuf, Fingerprint>,
+    style: StyleOptions,
+    options: BrowseOptions,
+) -> crate::Result {
+    let mut groups: Vec<Group> = duplicates
+        .iter()
+        .filter_map(|group| {
+            let files: Vec<Entry> = group
+                .paths
+                .iter()
+                .filter_map(|path| {
+                    Some(Entry {
+                        path,
+                        fingerprint: *fingerprints.get(path)?,
+                        mark: Mark::None,
+                    })
+                })
+                .collect();
+            if files.len() < 2 {
+                return None;
+            }
+            let wasted = group.size * (files.len() as u64 - 1);
+            Some(Group { hash: &group.hash, files, wasted })
+        })
+        .collect();
+    // NB: sort is stable, so ties remain sorted by hash
+    groups.sort_by_key(|group| std::cmp::Reverse(group.wasted));
+
+    let mut browser = Browser {
+        groups,
+        pane: Pane::Groups,
+        group_index: 0,
+        file_index: 0,
+        status: String::new(),
+        style,
+        options,
+    };
+
+    let messages = {
+        let mut terminal = Terminal::enter()?;
+        loop {
+            let (rows, cols) = terminal.size();
+            let mut out = stdout().lock();
+            out.write_all(browser.render(rows, cols).as_bytes())?;
+            out.flush()?;
+            drop(out);
+
+            if let Flow::Quit = browser.handle(terminal.read_key()?) {
+                let count = browser.marked_count();
+                if count == 0 {
+                    break vec![];
+                }
+                browser.status =
+                    format!(" Delete {} marked file(s)? [y/N]", count);
+                let key = loop {
+                    let (rows, cols) = terminal.size();
+                    let mut out = stdout().lock();
+                    out.write_all(browser.render(rows, cols).as_bytes())?;
+                    out.flush()?;
+                    drop(out);
+                    match terminal.read_key()? {
+                        Key::Resize => {},
+                        key => break key,
+                    }
+                };
+                match key {
+                    Key::Char('y' | 'Y') => break browser.delete_marked(),
+                    Key::Char('n' | 'N') => break vec![],
+                    _ => browser.status.clear(),
+                }
+            }
+        }
+    };
+    for message in messages {
+        println!("{}", message);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::hash::HashAlgorithm;
+    use crate::hash::HashStyle;
+    use crate::search::PathStyle;
+
+    fn browser<'a>(hash: &'a FileHash, paths: &'a [PathBuf]) -> Browser<'a> {
+        let fingerprint =
+            Fingerprint { size: 1, modified: SystemTime::UNIX_EPOCH, id: None };
+        let group = |files: &'a [PathBuf]| Group {
+            hash,
+            files: files
+                .iter()
+                .map(|path| Entry { path, fingerprint, mark: Mark::None })
+                .collect(),
+            wasted: files.len() as u64 - 1,
+        };
+        Browser {
+            groups: paths.chunks(3).map(group).collect(),
+            pane: Pane::Groups,
+            group_index: 0,
+            file_index: 0,
+            status: String::new(),
+            style: StyleOptions {
+                hash: HashStyle::Short,
+                path: PathStyle::Absolute,
+            },
+            options: BrowseOptions::default(),
+        }
+    }
+
+    #[test]
+    fn selection_stays_in_bounds_and_in_view() {
+        let hash = FileHash::new(HashAlgorithm::Xxh3, &[0; 16]).unwrap();
+        let paths: Vec<PathBuf> =
+            (0..30).map(|i| PathBuf::from(format!("/r/{}", i))).collect();
+        let mut browser = browser(&hash, &paths);
+
+        browser.move_selection(-1);
+        assert_eq!(browser.group_index, 0);
+        browser.handle(Key::PageDown);
+        assert_eq!(browser.group_index, 9);
+        browser.handle(Key::PageDown);
+        assert_eq!(browser.group_index, 9);
+        assert_eq!(scroll_offset(9, 4), 6);
+        assert_eq!(scroll_offset(2, 4), 0);
+
+        // Moving between groups starts at the first file again
+        browser.handle(Key::Tab);
+        browser.handle(Key::PageDown);
+        assert_eq!(browser.file_index, 2);
+        browser.handle(Key::Tab);
+        browser.handle(Key::Up);
+        assert_eq!((browser.group_index, browser.file_index), (8, 0));
+
+        // Resizing is not a key; it keeps the status
+        browser.status = "status".into();
+        browser.handle(Key::Resize);
+        assert_eq!(browser.status, "status");
+
+        for (rows, cols) in [(1, 1), (3, 10), (24, 80)] {
+            let frame = browser.render(rows, cols);
+            assert_eq!(frame.split("\r\n").count(), rows.max(2));
+        }
+    }
+
+    #[test]
+    fn marks_never_delete_every_copy() {
+        let hash = FileHash::new(HashAlgorithm::Xxh3, &[0; 16]).unwrap();
+        let paths: Vec<PathBuf> =
+            ["/r/a", "/r/b", "/r/c"].map(PathBuf::from).into();
+        let mut browser = browser(&hash, &paths);
+        browser.handle(Key::Tab);
+
+        browser.handle(Key::Char('d'));
+        browser.handle(Key::Down);
+        browser.handle(Key::Char('d'));
+        browser.handle(Key::Down);
+        browser.handle(Key::Char('d'));
+        assert!(!browser.status.is_empty());
+        assert_eq!(browser.marked_count(), 2);
+        assert_eq!(browser.groups[0].survivor(), Some(Path::new("/r/c")));
+
+        browser.handle(Key::Up);
+        browser.handle(Key::Char('x'));
+        assert_eq!(browser.marked_count(), 2);
+        assert_eq!(browser.groups[0].survivor(), Some(Path::new("/r/b")));
+
+        // An explicitly kept file survives before unmarked ones
+        browser.handle(Key::Char('u'));
+        browser.handle(Key::Up);
+        browser.handle(Key::Char('s'));
+        assert_eq!(browser.groups[0].survivor(), Some(Path::new("/r/a")));
+    }
+}
@@ -0,0 +1,235 @@
+//! Items to detect silent corruption ("bit rot") of indexed files.
+//!
+//! A file whose size and modification time did not change since it was
+//! hashed should still have the same contents. If hashing it again gives
+//! a different hash, the contents changed without anything writing to it.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::path::absolute;
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::archive::is_member;
+use crate::connection::Connection;
+use crate::connection::ConnectionKind;
+use crate::db::Change;
+use crate::db::Database;
+use crate::fingerprint::Fingerprint;
+use crate::hash::FileHash;
+use crate::hash::HashExtent;
+use crate::hash_concurrent::HashFilesOptions;
+use crate::hash_concurrent::parallel_hash_files;
+use crate::progress::ProgressSink;
+use crate::skip::Skipped;
+
+/// Options for verifying the files in the index.
+pub struct VerifyOptions {
+    /// Which indexed files to verify; all of them if empty.
+    pub directories: Vec<PathBuf>,
+    /// Options for hashing. The algorithm of each record is used instead.
+    pub config: HashFilesOptions,
+    /// The index to verify.
+    pub cache: ConnectionKind,
+    /// The percentage of files to verify, from 1 to 100.
+    /// Files verified least recently (or never) go first,
+    /// so repeated runs eventually verify every file.
+    pub sample: u8,
+}
+
+#[derive(Debug)]
+/// A file whose contents changed, though its metadata did not.
+pub struct Corruption {
+    /// The path to the file.
+    pub path: PathBuf,
+    /// The hash in the index.
+    pub expected: FileHash,
+    /// The hash of the contents now.
+    pub actual: FileHash,
+}
+
+#[derive(Debug, Default)]
+/// The outcome of verifying the index.
+pub struct Verification {
+    /// Files whose contents no longer match their hash, sorted by path.
+    pub corrupt: Vec<Corruption>,
+    /// The number of files hashed again, including corrupt files.
+    pub verified: usize,
+    /// The number of bytes hashed again.
+    pub bytes: u64,
+    /// The number of files modified since they were hashed,
+    /// which cannot be verified.
+    pub changed: usize,
+    /// The number of files left for later runs, by sampling.
+    pub pending: usize,
+    /// Files that could not be read.
+    pub skipped: Vec<Skipped>,
+    /// Other problems that did not stop the verification.
+    pub errors: Vec<crate::Error>,
+}
+
+/// Whether the file still has the size and modification time it had
+/// when it was hashed. Its identity may differ, e.g. after a restore.
+fn is_unchanged(recorded: &Fingerprint, current: &Fingerprint) -> bool {
+    recorded.size == current.size && recorded.modified == current.modified
+}
+
+/// Hashes (a sample of) the unchanged files in the index again,
+/// and reports those whose hash changed.
+///
+/// Files inside archives are not verified; verify the archive instead.
+pub fn verify(
+    VerifyOptions { directories, config, cache, sample }: VerifyOptions,
+    progress: &mut dyn ProgressSink,
+) -> crate::Result<Verification> {
+    let mut index = Connection::<Database>::open(cache)
+        .context("failed to open cache; use --clean-cache to rebuild it")?;
+    let directories: Vec<PathBuf> = directories
+        .iter()
+        .map(absolute)
+        .collect::<Result<_, _>>()
+        .context("failed to resolve directories")?;
+    let mut verification = Verification::default();
+
+    // Only unchanged files can be verified
+    let mut unchanged = Vec::new();
+    for (path, record) in index.records() {
+        let is_selected = directories.is_empty() ||
+            directories.iter().any(|dir| path.starts_with(dir));
+        if !is_selected || is_member(path) {
+            continue;
+        }
+        match Fingerprint::from_path(path) {
+            Ok(current) if is_unchanged(&record.fingerprint, &current) => {
+                unchanged.push((path, record, index.last_verified(path)));
+            },
+            Ok(_) => verification.changed += 1,
+            Err(error) => verification.skipped.push(Skipped::new(path, error)),
+        }
+    }
+
+    // Least recently verified first; never verified sorts before any time
+    unchanged.sort_by_key(|&(path, _, verified)| (verified, path));
+    let count = (unchanged.len() * sample as usize).div_ceil(100);
+    verification.pending = unchanged.len() - count;
+    unchanged.truncate(count);
+
+    let mut by_algorithm: HashMap<_, Vec<(&Path, u64)>> = HashMap::new();
+    for &(path, record, _) in &unchanged {
+        let files = by_algorithm.entry(record.hash.algorithm()).or_default();
+        files.push((path, record.fingerprint.size));
+    }
+    let mut hashes = Vec::new();
+    for (algorithm, files) in by_algorithm {
+        let config = HashFilesOptions { algorithm, ..config };
+        let hashed =
+            parallel_hash_files(&files, HashExtent::Full, config, progress);
+        hashes.extend(hashed.hashes);
+        verification.skipped.extend(hashed.skipped);
+    }
+
+    let verified_at = SystemTime::now();
+    let mut changes = Vec::new();
+    for (path, actual) in hashes {
+        let record = index.get(path).expect("hashed files are indexed");
+        // A file written to while it was hashed is not corrupt
+        let current = Fingerprint::from_path(path);
+        if !current.is_ok_and(|now| is_unchanged(&record.fingerprint, &now)) {
+            verification.changed += 1;
+            continue;
+        }
+        verification.verified += 1;
+        verification.bytes += record.fingerprint.size;
+        match record.hash == actual {
+            true => {
+                changes.push(Change::Verify(path.to_path_buf(), verified_at))
+            },
+            false => verification.corrupt.push(Corruption {
+                path: path.to_path_buf(),
+                expected: record.hash,
+                actual,
+            }),
+        }
+    }
+    verification.corrupt.sort_by(|a, b| a.path.cmp(&b.path));
+
+    // Corrupt files keep their record, so they are flagged again
+    for change in changes {
+        index.apply(change)?;
+    }
+    if let Err(e) = index.save() {
+        verification.errors.push(e.context("failed to save index"));
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+
+    use super::*;
+    use crate::db::Record;
+    use crate::hash::FileHasher;
+    use crate::hash::HashAlgorithm;
+    use crate::progress::NoProgress;
+
+    #[test]
+    fn flags_changed_contents_with_unchanged_metadata() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-verify", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache = dir.join("index.dat");
+        let (intact, rotten) = (dir.join("intact"), dir.join("rotten"));
+        fs::write(&intact, "intact").unwrap();
+        fs::write(&rotten, "rotten").unwrap();
+
+        let mut index =
+            Connection::<Database>::open(ConnectionKind::Disk(cache.clone()))
+                .unwrap();
+        let mut hasher = FileHasher::new(HashAlgorithm::Xxh3);
+        for path in [&intact, &rotten] {
+            let fingerprint = Fingerprint::from_path(path).unwrap();
+            let hash = hasher.from_contents(path).unwrap();
+            index
+                .apply(Change::Add(path.clone(), Record { fingerprint, hash }))
+                .unwrap();
+        }
+        index.save().unwrap();
+        drop(index);
+
+        // Flip the contents, but keep the size and modification time
+        let modified = fs::metadata(&rotten).unwrap().modified().unwrap();
+        fs::write(&rotten, "r0tten").unwrap();
+        File::options()
+            .write(true)
+            .open(&rotten)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let options = |sample| VerifyOptions {
+            directories: vec![dir.clone()],
+            config: HashFilesOptions {
+                algorithm: HashAlgorithm::Sha256,
+                ..HashFilesOptions::for_tests()
+            },
+            cache: ConnectionKind::Disk(cache.clone()),
+            sample,
+        };
+        let first = verify(options(50), &mut NoProgress).unwrap();
+        let second = verify(options(50), &mut NoProgress).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Sampling half verifies the other file on the next run
+        assert_eq!((first.verified, first.pending), (1, 1));
+        assert_eq!((second.verified, second.pending), (1, 1));
+        let corrupt: Vec<&Path> = [&first, &second]
+            .iter()
+            .flat_map(|v| v.corrupt.iter().map(|c| c.path.as_path()))
+            .collect();
+        assert_eq!(corrupt, [rotten.as_path()]);
+    }
+}
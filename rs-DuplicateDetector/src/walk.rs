@@ -0,0 +1,502 @@
+//! Items to find all files in a set of directories.
+//!
+//! Unlike a naive recursive walk, this:
+//! - visits every directory at most once, so symlink cycles are harmless;
+//! - reports each file (i.e. inode) once, and its other names as hard links;
+//! - prefers real names of a file over symbolic links to it;
+//! - skips files rejected by a [`Filter`] or a `.dupignore` file;
+//! - optionally lists the files inside archives, by their virtual paths.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::archive::Listing;
+use crate::archive::is_archive;
+use crate::archive::list_members;
+use crate::archive::member_path;
+use crate::filter::Filter;
+use crate::filter::IgnoreStack;
+use crate::fingerprint::FileId;
+use crate::fingerprint::Fingerprint;
+use crate::skip::Skipped;
+
+/////////////
+// Options //
+/////////////
+
+#[derive(Debug, Default, Clone, Copy)]
+/// Options for walking directories.
+pub struct WalkOptions {
+    /// Whether to follow symbolic links to files and directories.
+    pub follow_symlinks: bool,
+    /// Whether to stay on the file system of each root directory.
+    /// Only supported on Unix.
+    pub one_file_system: bool,
+    /// Whether to list the files inside zip and tar archives too.
+    pub archives: bool,
+}
+
+////////////
+// Result //
+////////////
+
+#[derive(Debug)]
+/// A file found by walking.
+pub struct WalkEntry {
+    /// The path to the file, starting with one of the root directories.
+    pub path: PathBuf,
+    /// The fingerprint of the file at the moment it was found.
+    pub fingerprint: Fingerprint,
+    /// Whether the file was found through a symbolic link,
+    /// as no real name of it was found.
+    pub is_symlink: bool,
+}
+
+#[derive(Debug, Default)]
+/// All files found by walking.
+pub struct Walk {
+    /// Every distinct file, listed under the first name it was found by.
+    pub files: Vec<WalkEntry>,
+    /// Maps the path of a file in [`Walk::files`] to its other names.
+    /// These are already the same file, so they are not duplicates.
+    pub hard_links: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Maps the path of a file in [`Walk::files`] to symbolic links to it,
+    /// if symbolic links are followed. These are not hard links either.
+    pub symlinks: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Every directory read, including the roots.
+    pub dirs: Vec<PathBuf>,
+    /// Directories, files and archives that could not be read.
+    pub skipped: Vec<Skipped>,
+    /// The archives listed anew, rather than known beforehand.
+    pub listings: Vec<(PathBuf, Listing)>,
+}
+
+/// Looks up the listing of an archive made before, if any.
+/// Listings of archives that changed since are ignored.
+pub type KnownListings<'a> = &'a dyn Fn(&Path) -> Option<Listing>;
+
+////////////
+// Walker //
+////////////
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+/// Identifies a directory, to ensure it is visited once.
+enum DirKey {
+    Id(FileId),
+    /// Fallback for platforms without [`FileId`]s.
+    Canonical(PathBuf),
+}
+
+impl DirKey {
+    fn new(path: &Path, stat: &Metadata) -> io::Result<Self> {
+        Ok(match FileId::from_metadata(stat) {
+            Some(id) => DirKey::Id(id),
+            None => DirKey::Canonical(fs::canonicalize(path)?),
+        })
+    }
+}
+
+/// Returns the device a file resides on, if known.
+fn device_of(stat: &Metadata) -> Option<u64> {
+    FileId::from_metadata(stat).map(|id| id.device)
+}
+
+/// Walks directories, remembering what it saw across starting points.
+struct Walker<'a> {
+    options: WalkOptions,
+    filter: &'a Filter,
+    known: KnownListings<'a>,
+    result: Walk,
+    visited_dirs: HashSet<DirKey>,
+    seen_files: HashMap<FileId, usize>,
+    /// Files found through a symbolic link, with the root they were found in.
+    /// These are added last, so any real name of the file is found first.
+    linked_files: Vec<(PathBuf, PathBuf, Metadata)>,
+}
+
+/// Directories yet to read, with the `.dupignore` files that apply to them.
+type Frontier = VecDeque<(PathBuf, IgnoreStack)>;
+
+impl<'a> Walker<'a> {
+    fn new(
+        options: WalkOptions,
+        filter: &'a Filter,
+        known: KnownListings<'a>,
+    ) -> Self {
+        Walker {
+            options,
+            filter,
+            known,
+            result: Walk::default(),
+            visited_dirs: HashSet::new(),
+            seen_files: HashMap::new(),
+            linked_files: Vec::new(),
+        }
+    }
+
+    /// Visits a file or directory inside the root, possibly through a link,
+    /// adding it to the result or to the frontier if accepted.
+    ///
+    /// Paths that cannot be read are skipped.
+    #[allow(clippy::too_many_arguments)]
+    fn visit(
+        &mut self,
+        root: &Path,
+        root_device: Option<u64>,
+        path: PathBuf,
+        stat: &Metadata,
+        is_symlink: bool,
+        ignores: &IgnoreStack,
+        frontier: &mut Frontier,
+    ) {
+        let Walker { options, filter, .. } = self;
+        if ignores.is_ignored(&path, stat.is_dir()) {
+            return;
+        }
+
+        if stat.is_dir() {
+            if options.one_file_system && device_of(stat) != root_device {
+                return;
+            }
+            if !filter.accepts_dir(root, &path) {
+                return;
+            }
+            let key = match DirKey::new(&path, stat) {
+                Ok(key) => key,
+                Err(error) => {
+                    self.result.skipped.push(Skipped::new(path, error));
+                    return;
+                },
+            };
+            if self.visited_dirs.insert(key) {
+                match ignores.enter(&path) {
+                    Ok(ignores) => frontier.push_back((path, ignores)),
+                    Err(error) => {
+                        self.result.skipped.push(Skipped::new(path, error));
+                    },
+                }
+            }
+        } else if stat.is_file() {
+            if !filter.accepts_file(root, &path, stat.len()) {
+                return;
+            }
+            match is_symlink {
+                true => {
+                    let entry = (root.to_path_buf(), path, stat.clone());
+                    self.linked_files.push(entry);
+                },
+                false => self.add_file(root, path, stat, false),
+            }
+        } else {
+            // sockets, fifos, devices, etc.
+        }
+    }
+
+    /// Adds an accepted file to the result,
+    /// as another name if the file was found before.
+    fn add_file(
+        &mut self,
+        root: &Path,
+        path: PathBuf,
+        stat: &Metadata,
+        is_symlink: bool,
+    ) {
+        let Walker { options, filter, known, result, .. } = self;
+        let fingerprint = match Fingerprint::from_metadata(stat) {
+            Ok(fingerprint) => fingerprint,
+            Err(error) => {
+                result.skipped.push(Skipped::new(path, error));
+                return;
+            },
+        };
+        let first_name = fingerprint
+            .id
+            .and_then(|id| self.seen_files.get(&id))
+            .map(|&index| &result.files[index].path);
+        if let Some(first_name) = first_name {
+            let names = match is_symlink {
+                true => &mut result.symlinks,
+                false => &mut result.hard_links,
+            };
+            names.entry(first_name.clone()).or_default().push(path);
+            return;
+        }
+        if let Some(id) = fingerprint.id {
+            self.seen_files.insert(id, result.files.len());
+        }
+        let listing = (options.archives && is_archive(&path)).then(|| {
+            known(&path).filter(|listing| listing.fingerprint == fingerprint)
+        });
+        let members = match listing {
+            Some(Some(listing)) => listing.members,
+            Some(None) => {
+                // Damaged archives are listed again next time
+                let (members, error) = list_members(&path);
+                match error {
+                    Some(error) => {
+                        result.skipped.push(Skipped::new(&path, error));
+                    },
+                    None => {
+                        let members = members.clone();
+                        let listing = Listing { fingerprint, members };
+                        result.listings.push((path.clone(), listing));
+                    },
+                }
+                members
+            },
+            None => vec![],
+        };
+        let members: Vec<WalkEntry> = members
+            .into_iter()
+            .map(|member| WalkEntry {
+                path: member_path(&path, &member.name),
+                fingerprint: member.fingerprint(&fingerprint),
+                is_symlink,
+            })
+            .filter(|entry| {
+                let size = entry.fingerprint.size;
+                filter.accepts_file(root, &entry.path, size)
+            })
+            .collect();
+        result.files.push(WalkEntry { path, fingerprint, is_symlink });
+        result.files.extend(members);
+    }
+
+    /// Adds the files found through symbolic links,
+    /// and returns everything found.
+    fn finish(mut self) -> Walk {
+        for (root, path, stat) in std::mem::take(&mut self.linked_files) {
+            self.add_file(&root, path, &stat, true);
+        }
+        self.result
+    }
+
+    /// Reads the directories in the frontier, and those found inside.
+    fn drain(
+        &mut self,
+        root: &Path,
+        root_device: Option<u64>,
+        mut frontier: Frontier,
+    ) {
+        while let Some((dir, ignores)) = frontier.pop_front() {
+            let items = match fs::read_dir(&dir) {
+                Ok(items) => {
+                    self.result.dirs.push(dir.clone());
+                    items
+                },
+                Err(error) => {
+                    self.result.skipped.push(Skipped::new(dir, error));
+                    continue;
+                },
+            };
+            for item in items {
+                let item = match item {
+                    Ok(item) => item,
+                    Err(error) => {
+                        // The rest of the listing is lost with it
+                        self.result.skipped.push(Skipped::new(&dir, error));
+                        break;
+                    },
+                };
+                let path = item.path();
+                let is_symlink = match item.file_type() {
+                    Ok(file_type) => file_type.is_symlink(),
+                    Err(error) => {
+                        self.result.skipped.push(Skipped::new(path, error));
+                        continue;
+                    },
+                };
+                let stat = if is_symlink {
+                    if !self.options.follow_symlinks {
+                        continue;
+                    }
+                    match fs::metadata(&path) {
+                        Ok(stat) => stat,
+                        Err(_) => continue, // dangling link
+                    }
+                } else {
+                    match item.metadata() {
+                        Ok(stat) => stat,
+                        Err(error) => {
+                            self.result.skipped.push(Skipped::new(path, error));
+                            continue;
+                        },
+                    }
+                };
+                self.visit(
+                    root,
+                    root_device,
+                    path,
+                    &stat,
+                    is_symlink,
+                    &ignores,
+                    &mut frontier,
+                );
+            }
+        }
+    }
+}
+
+/// Recursively reads the given directories and returns all accepted files.
+/// Returned paths start with the directory they were found in.
+///
+/// Archives are only listed if no listing of them is known.
+pub fn walk(
+    roots: &[PathBuf],
+    options: WalkOptions,
+    filter: &Filter,
+    known: KnownListings,
+) -> crate::Result<Walk> {
+    let mut walker = Walker::new(options, filter, known);
+    for root in roots {
+        let root_stat = fs::metadata(root)?;
+        let root_device = device_of(&root_stat);
+        if !walker.visited_dirs.insert(DirKey::new(root, &root_stat)?) {
+            continue; // root overlaps an earlier root
+        }
+
+        let ignores = match IgnoreStack::default().enter(root) {
+            Ok(ignores) => ignores,
+            Err(error) => {
+                walker.result.skipped.push(Skipped::new(root, error));
+                continue;
+            },
+        };
+        let frontier = VecDeque::from([(root.clone(), ignores)]);
+        walker.drain(root, root_device, frontier);
+    }
+    Ok(walker.finish())
+}
+
+/// Like [`walk`], but only reads the given paths inside the root,
+/// e.g. those that changed since the last walk.
+///
+/// Paths may be files or directories, and are accepted or rejected
+/// just as they would be when walking the entire root.
+/// Paths that no longer exist are left out.
+pub fn walk_within(
+    root: &Path,
+    paths: &[PathBuf],
+    options: WalkOptions,
+    filter: &Filter,
+    known: KnownListings,
+) -> crate::Result<Walk> {
+    let mut walker = Walker::new(options, filter, known);
+    let root_device = device_of(&fs::metadata(root)?);
+    'paths: for path in paths {
+        let Ok(relative) = path.strip_prefix(root) else { continue };
+        let Ok(link_stat) = fs::symlink_metadata(path) else { continue };
+        let is_symlink = link_stat.is_symlink();
+        let stat = match options.follow_symlinks && is_symlink {
+            true => fs::metadata(path),
+            false => Ok(link_stat),
+        };
+        let Ok(stat) = stat else { continue }; // gone again, or dangling
+
+        // Directories on the way down must be accepted too
+        let mut dir = root.to_path_buf();
+        let mut ignores = IgnoreStack::default().enter(&dir);
+        let parents = relative.parent().into_iter().flat_map(Path::components);
+        for component in parents {
+            let Ok(accepted) = &ignores else { break };
+            dir.push(component);
+            if accepted.is_ignored(&dir, true) ||
+                !filter.accepts_dir(root, &dir)
+            {
+                continue 'paths;
+            }
+            ignores = accepted.enter(&dir);
+        }
+        let ignores = match ignores {
+            Ok(ignores) => ignores,
+            Err(error) => {
+                walker.result.skipped.push(Skipped::new(dir, error));
+                continue;
+            },
+        };
+
+        let mut frontier = VecDeque::new();
+        let path = path.clone();
+        walker.visit(
+            root,
+            root_device,
+            path,
+            &stat,
+            is_symlink,
+            &ignores,
+            &mut frontier,
+        );
+        walker.drain(root, root_device, frontier);
+    }
+    Ok(walker.finish())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::iter::once;
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// Returns every name of each file found, sorted, by kind of name.
+    fn names(walk: &Walk, dir: &Path) -> [Vec<Vec<String>>; 2] {
+        let relative = |path: &PathBuf| {
+            path.strip_prefix(dir).unwrap().display().to_string()
+        };
+        [&walk.hard_links, &walk.symlinks].map(|links| {
+            let mut sets: Vec<Vec<String>> = walk
+                .files
+                .iter()
+                .map(|entry| {
+                    let others = links.get(&entry.path).into_iter().flatten();
+                    let mut names: Vec<String> =
+                        once(&entry.path).chain(others).map(relative).collect();
+                    names.sort();
+                    names
+                })
+                .collect();
+            sets.sort();
+            sets
+        })
+    }
+
+    #[test]
+    fn follows_cycles_and_links_once() {
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-walk", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("sub/hard")).unwrap();
+        symlink(dir.join("a"), dir.join("soft")).unwrap();
+        symlink(&dir, dir.join("sub/cycle")).unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+
+        let filter = Filter::default();
+        let options = |follow_symlinks| WalkOptions {
+            follow_symlinks,
+            ..WalkOptions::default()
+        };
+        let roots = [dir.clone()];
+        let followed = walk(&roots, options(true), &filter, &|_| None).unwrap();
+        let ignored = walk(&roots, options(false), &filter, &|_| None).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The link to a file is not a hard link, nor is the file found twice
+        assert_eq!(names(&followed, &dir), [
+            [vec!["a", "sub/hard"], vec!["b"]],
+            [vec!["a", "soft"], vec!["b"]],
+        ]);
+        assert_eq!(followed.dirs.len(), 2);
+        assert_eq!(names(&ignored, &dir), [
+            [vec!["a", "sub/hard"], vec!["b"]],
+            [vec!["a"], vec!["b"]],
+        ]);
+        assert!(followed.skipped.is_empty() && ignored.skipped.is_empty());
+    }
+}
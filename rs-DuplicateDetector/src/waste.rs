@@ -0,0 +1,409 @@
+//! Items to summarize how much space duplicates waste, and where.
+//!
+//! Of each group of duplicates, one file is kept;
+//! every other copy counts as waste. Copies are kept in order of path,
+//! so waste is attributed to every file but the first.
+//! Files inside archives cannot be removed, so they neither count
+//! as waste nor are kept.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::StyleOptions;
+use crate::archive::is_member;
+use crate::archive::split_member;
+use crate::core::ansi::Bold;
+use crate::core::units::Bytes;
+use crate::output::OutputFormat;
+use crate::report::DuplicateGroup;
+
+/////////////
+// Options //
+/////////////
+
+#[derive(Debug, Clone, Copy)]
+/// Options for summarizing wasted space.
+pub struct SummaryOptions {
+    /// How many of the groups wasting the most space to list.
+    pub top: usize,
+}
+
+///////////
+// Model //
+///////////
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// The space wasted by the redundant copies in a category.
+pub struct Waste {
+    /// Names the category, e.g. the extension.
+    pub key: String,
+    /// The number of redundant copies.
+    pub files: usize,
+    /// The number of bytes they take up.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// A group of duplicates, and the space it wastes.
+pub struct WastefulGroup {
+    /// The hash, formatted according to the [`StyleOptions`].
+    pub hash: String,
+    /// The size of each file, in bytes.
+    pub size: u64,
+    /// The number of copies that could be removed.
+    pub redundant: usize,
+    /// The number of bytes freed by removing them.
+    pub wasted: u64,
+    /// The files, formatted according to the [`StyleOptions`].
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// How much space duplicates waste, broken down in several ways.
+pub struct WasteSummary {
+    /// The number of groups of duplicates wasting space.
+    pub groups: usize,
+    /// The number of redundant copies.
+    pub files: usize,
+    /// The number of bytes freed by keeping one file of each group.
+    pub reclaimable: u64,
+    /// Waste by lowercase extension, most first.
+    pub by_extension: Vec<Waste>,
+    /// Waste by directory directly inside the directories searched,
+    /// most first. Files directly inside count towards the directory itself.
+    pub by_directory: Vec<Waste>,
+    /// Waste by size of the files, smallest band first.
+    pub by_size: Vec<Waste>,
+    /// The groups wasting the most space, most first.
+    pub top_groups: Vec<WastefulGroup>,
+}
+
+/// Upper bounds (exclusive) of each size band, and its name.
+const SIZE_BANDS: [(u64, &str); 5] = [
+    (1 << 10, "< 1 KiB"),
+    (1 << 20, "1 KiB - 1 MiB"),
+    (100 << 20, "1 MiB - 100 MiB"),
+    (1 << 30, "100 MiB - 1 GiB"),
+    (u64::MAX, ">= 1 GiB"),
+];
+
+/// Names the extension of the file, for grouping.
+fn extension_of(path: &Path) -> String {
+    match path.extension() {
+        Some(extension) => extension.to_string_lossy().to_lowercase(),
+        None => "(none)".to_string(),
+    }
+}
+
+/// Returns the directory directly inside the innermost root containing
+/// the file, or the root itself if the file is directly inside it.
+/// Files inside archives count towards the archive.
+fn top_level_dir(path: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    let path = split_member(path).map_or(path, |(archive, _)| archive);
+    let root = roots
+        .iter()
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.as_os_str().len())?;
+    let relative = path.strip_prefix(root).ok()?;
+    let mut components = relative.components();
+    let first = components.next()?;
+    Some(match components.next() {
+        Some(_) => root.join(first),
+        None => root.clone(),
+    })
+}
+
+/// Returns the copies in the group that could be removed,
+/// i.e. every file outside archives but the first.
+fn redundant(group: &DuplicateGroup) -> impl Iterator<Item = &PathBuf> {
+    group.paths.iter().filter(|path| !is_member(path)).skip(1)
+}
+
+/// Returns the number of bytes freed by removing the redundant copies.
+fn wasted_bytes(group: &DuplicateGroup) -> u64 {
+    group.size * redundant(group).count() as u64
+}
+
+/// Adds a copy to the waste of the category.
+fn add(waste: &mut HashMap<String, Waste>, key: String, bytes: u64) {
+    let entry =
+        waste.entry(key.clone()).or_insert(Waste { key, files: 0, bytes: 0 });
+    entry.files += 1;
+    entry.bytes += bytes;
+}
+
+/// Sorts the categories by waste, most first, then by name.
+fn most_first(waste: HashMap<String, Waste>) -> Vec<Waste> {
+    let mut waste: Vec<Waste> = waste.into_values().collect();
+    waste.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.key.cmp(&b.key)));
+    waste
+}
+
+impl WasteSummary {
+    /// Summarizes the waste of the groups, found in the given roots.
+    ///
+    /// Paths in each group must be sorted, as they are in a [`Report`].
+    ///
+    /// [`Report`]: crate::report::Report
+    pub fn new(
+        groups: &[DuplicateGroup],
+        roots: &[PathBuf],
+        SummaryOptions { top }: SummaryOptions,
+        style: StyleOptions,
+    ) -> Self {
+        let mut by_extension = HashMap::new();
+        let mut by_directory = HashMap::new();
+        let mut by_size: Vec<Waste> = SIZE_BANDS
+            .iter()
+            .map(|(_, name)| Waste {
+                key: name.to_string(),
+                files: 0,
+                bytes: 0,
+            })
+            .collect();
+        for group in groups {
+            let band = SIZE_BANDS
+                .iter()
+                .position(|&(bound, _)| group.size < bound)
+                .unwrap_or(SIZE_BANDS.len() - 1);
+            for path in redundant(group) {
+                add(&mut by_extension, extension_of(path), group.size);
+                if let Some(dir) = top_level_dir(path, roots) {
+                    let dir = style.path.format(&dir).display().to_string();
+                    add(&mut by_directory, dir, group.size);
+                }
+                by_size[band].files += 1;
+                by_size[band].bytes += group.size;
+            }
+        }
+        by_size.retain(|band| band.files > 0);
+
+        let groups: Vec<&DuplicateGroup> =
+            groups.iter().filter(|group| wasted_bytes(group) > 0).collect();
+        let mut top_groups = groups.clone();
+        top_groups.sort_by_key(|group| Reverse(wasted_bytes(group)));
+        top_groups.truncate(top);
+        let top_groups = top_groups
+            .into_iter()
+            .map(|group| WastefulGroup {
+                hash: style.hash.format(&group.hash),
+                size: group.size,
+                redundant: redundant(group).count(),
+                wasted: wasted_bytes(group),
+                paths: group
+                    .paths
+                    .iter()
+                    .map(|path| style.path.format(path).display().to_string())
+                    .collect(),
+            })
+            .collect();
+
+        WasteSummary {
+            groups: groups.len(),
+            files: groups.iter().map(|group| redundant(group).count()).sum(),
+            reclaimable: groups.iter().map(|group| wasted_bytes(group)).sum(),
+            by_extension: most_first(by_extension),
+            by_directory: most_first(by_directory),
+            by_size,
+            top_groups,
+        }
+    }
+}
+
+/////////////
+// Writers //
+/////////////
+
+#[derive(Serialize)]
+/// A row in CSV output.
+struct CsvRow<'a> {
+    breakdown: &'a str,
+    key: &'a str,
+    files: usize,
+    bytes: u64,
+}
+
+/// Writes the summary in the format.
+///
+/// As text, each breakdown is a table. As CSV, there is one row
+/// per category, by breakdown, and one row per group, keyed by hash,
+/// counting the redundant copies.
+pub fn write_summary(
+    mut out: impl Write,
+    summary: &WasteSummary,
+    format: OutputFormat,
+) -> crate::Result {
+    match format {
+        OutputFormat::Text => write_text(out, summary)?,
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, summary)?;
+            writeln!(out)?;
+        },
+        OutputFormat::Ndjson => {
+            serde_json::to_writer(&mut out, summary)?;
+            writeln!(out)?;
+        },
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.serialize(CsvRow {
+                breakdown: "total",
+                key: "",
+                files: summary.files,
+                bytes: summary.reclaimable,
+            })?;
+            let breakdowns = [
+                ("extension", &summary.by_extension),
+                ("directory", &summary.by_directory),
+                ("size", &summary.by_size),
+            ];
+            for (breakdown, waste) in breakdowns {
+                for Waste { key, files, bytes } in waste {
+                    let (files, bytes) = (*files, *bytes);
+                    writer.serialize(CsvRow {
+                        breakdown,
+                        key,
+                        files,
+                        bytes,
+                    })?;
+                }
+            }
+            for group in &summary.top_groups {
+                writer.serialize(CsvRow {
+                    breakdown: "group",
+                    key: &group.hash,
+                    files: group.redundant,
+                    bytes: group.wasted,
+                })?;
+            }
+            writer.flush()?;
+        },
+    }
+    Ok(())
+}
+
+/// Writes the summary as a table per breakdown, for people to read.
+fn write_text(mut out: impl Write, summary: &WasteSummary) -> crate::Result {
+    let WasteSummary { groups, files, reclaimable, .. } = summary;
+    writeln!(
+        out,
+        "{}",
+        Bold(format!(
+            "{} reclaimable from {} redundant file(s) in {} group(s)",
+            Bytes(*reclaimable),
+            files,
+            groups,
+        )),
+    )?;
+    let breakdowns = [
+        ("By extension", &summary.by_extension),
+        ("By top-level directory", &summary.by_directory),
+        ("By size", &summary.by_size),
+    ];
+    for (title, waste) in breakdowns {
+        if waste.is_empty() {
+            continue;
+        }
+        let width = waste.iter().map(|w| w.key.chars().count()).max();
+        let width = width.unwrap_or_default();
+        writeln!(out, "\n{}:", Bold(title))?;
+        for Waste { key, files, bytes } in waste {
+            let bytes = Bytes(*bytes).to_string();
+            writeln!(
+                out,
+                "  {:<width$}  {:>10}  {:>7} file(s)",
+                key, bytes, files,
+            )?;
+        }
+    }
+    if !summary.top_groups.is_empty() {
+        let header = format!("Top {} group(s)", summary.top_groups.len());
+        writeln!(out, "\n{}:", Bold(header))?;
+        for group in &summary.top_groups {
+            let WastefulGroup { hash, size, wasted, paths, .. } = group;
+            writeln!(
+                out,
+                "  {} wasted by {} files of {} with hash {}:",
+                Bytes(*wasted),
+                paths.len(),
+                Bytes(*size),
+                hash,
+            )?;
+            for path in paths {
+                writeln!(out, "    {}", path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::FileHash;
+    use crate::hash::HashAlgorithm;
+    use crate::hash::HashStyle;
+    use crate::search::PathStyle;
+
+    fn group(byte: u8, size: u64, paths: &[&str]) -> DuplicateGroup {
+        DuplicateGroup {
+            hash: FileHash::new(HashAlgorithm::Xxh3, &[byte; 16]).unwrap(),
+            size,
+            paths: paths.iter().map(PathBuf::from).collect(),
+            covered: false,
+        }
+    }
+
+    #[test]
+    fn attributes_every_copy_but_the_first() {
+        let groups = [
+            group(1, 10, &["/r/a/x.JPG", "/r/b/x.jpg", "/r/b/y.jpg"]),
+            group(2, 2048, &["/r/a/z", "/r/top"]),
+            // Only copies outside archives can be removed
+            group(3, 10, &["/r/a.zip!/x.txt", "/r/c/x.txt", "/r/d/x.txt"]),
+            group(4, 99, &["/r/e.tar!/y", "/r/f/y"]),
+        ];
+        let summary = WasteSummary::new(
+            &groups,
+            &[PathBuf::from("/r")],
+            SummaryOptions { top: 1 },
+            StyleOptions { hash: HashStyle::Short, path: PathStyle::Relative },
+        );
+        assert_eq!(summary.groups, 3);
+        assert_eq!(summary.reclaimable, 3 * 10 + 2048);
+        assert_eq!(summary.files, 4);
+        let waste = |key: &str, files, bytes| Waste {
+            key: key.to_string(),
+            files,
+            bytes,
+        };
+        assert_eq!(summary.by_extension, [
+            waste("(none)", 1, 2048),
+            waste("jpg", 2, 20),
+            waste("txt", 1, 10),
+        ]);
+        assert_eq!(summary.by_directory, [
+            waste("/r", 1, 2048),
+            waste("/r/b", 2, 20),
+            waste("/r/d", 1, 10),
+        ]);
+        assert_eq!(summary.by_size, [
+            waste("< 1 KiB", 3, 30),
+            waste("1 KiB - 1 MiB", 1, 2048),
+        ]);
+        assert_eq!(summary.top_groups.len(), 1);
+        assert_eq!(summary.top_groups[0].paths, ["/r/a/z", "/r/top"]);
+    }
+
+    #[test]
+    fn counts_members_towards_the_archive() {
+        let roots = [PathBuf::from("/r")];
+        let dir = |path: &str| top_level_dir(Path::new(path), &roots);
+        assert_eq!(dir("/r/x.zip!/a/b"), Some(PathBuf::from("/r")));
+        assert_eq!(dir("/r/a/x.zip!/b"), Some(PathBuf::from("/r/a")));
+    }
+}
@@ -0,0 +1,470 @@
+//! Items to keep the index up to date while files change.
+//!
+//! After a scan, every directory read is watched for changes.
+//! Changes are collected until things settle, then only the changed paths
+//! are read again and the index is brought up to date, as in a scan.
+//! Files that were merely renamed or moved keep their hash.
+//!
+//! Only supported on Linux, using inotify.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::archive::is_member;
+use crate::archive::lies_in;
+use crate::db::Change;
+use crate::db::Record;
+use crate::fingerprint::FileId;
+use crate::fingerprint::Fingerprint;
+use crate::progress::ProgressSink;
+use crate::report::CacheStats;
+use crate::report::DuplicateGroup;
+use crate::scan::Scan;
+use crate::skip::Skipped;
+use crate::subtree::DirectoryFindings;
+use crate::walk::Walk;
+use crate::walk::walk_within;
+
+/// How long no changes must arrive before a batch of changes is applied.
+const SETTLE_PERIOD: Duration = Duration::from_millis(500);
+
+/////////////
+// Backend //
+/////////////
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Something that happened inside a watched directory.
+enum Event {
+    /// The path was created, written, moved or deleted.
+    Changed(PathBuf),
+    /// Events were lost, so anything may have changed.
+    Overflow,
+}
+
+#[cfg(target_os = "linux")]
+mod backend {
+    use std::collections::HashMap;
+    use std::io;
+    use std::io::ErrorKind;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use inotify::EventMask;
+    use inotify::Inotify;
+    use inotify::WatchMask;
+
+    use super::Event;
+
+    pub struct Watcher {
+        inotify: Inotify,
+        /// Maps watch descriptors to the directory watched.
+        dirs: HashMap<i32, PathBuf>,
+        buffer: Vec<u8>,
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Self> {
+            Ok(Watcher {
+                inotify: Inotify::init()?,
+                dirs: HashMap::new(),
+                buffer: vec![0; 1 << 16],
+            })
+        }
+
+        /// Watches the directory itself, not its subdirectories.
+        /// Watching a directory again, e.g. after it moved, updates its path.
+        pub fn add(&mut self, dir: &Path) -> io::Result<()> {
+            let mask = WatchMask::CREATE |
+                WatchMask::CLOSE_WRITE |
+                WatchMask::MOVED_FROM |
+                WatchMask::MOVED_TO |
+                WatchMask::DELETE |
+                WatchMask::ONLYDIR;
+            let wd = self.inotify.watches().add(dir, mask)?;
+            self.dirs.insert(wd.get_watch_descriptor_id(), dir.to_path_buf());
+            Ok(())
+        }
+
+        fn read(&mut self, block: bool) -> io::Result<Vec<Event>> {
+            let events = match block {
+                true => self.inotify.read_events_blocking(&mut self.buffer),
+                false => self.inotify.read_events(&mut self.buffer),
+            };
+            let events = match events {
+                Ok(events) => events,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    return Ok(vec![]);
+                },
+                Err(error) => return Err(error),
+            };
+            let mut result = Vec::new();
+            for event in events {
+                let id = event.wd.get_watch_descriptor_id();
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    result.push(Event::Overflow);
+                } else if event.mask.contains(EventMask::IGNORED) {
+                    // The directory is gone; its parent reports that
+                    self.dirs.remove(&id);
+                } else if let (Some(dir), Some(name)) =
+                    (self.dirs.get(&id), event.name)
+                {
+                    result.push(Event::Changed(dir.join(name)));
+                }
+            }
+            Ok(result)
+        }
+
+        /// Waits for at least one event.
+        pub fn wait(&mut self) -> io::Result<Vec<Event>> {
+            loop {
+                let events = self.read(true)?;
+                if !events.is_empty() {
+                    return Ok(events);
+                }
+            }
+        }
+
+        /// Returns the events that already happened, if any.
+        pub fn poll(&mut self) -> io::Result<Vec<Event>> { self.read(false) }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod backend {
+    use std::io;
+    use std::path::Path;
+
+    use super::Event;
+
+    pub struct Watcher;
+
+    impl Watcher {
+        pub fn new() -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "watching directories is only supported on Linux",
+            ))
+        }
+
+        pub fn add(&mut self, _dir: &Path) -> io::Result<()> { Ok(()) }
+
+        pub fn wait(&mut self) -> io::Result<Vec<Event>> { Ok(vec![]) }
+
+        pub fn poll(&mut self) -> io::Result<Vec<Event>> { Ok(vec![]) }
+    }
+}
+
+use backend::Watcher;
+
+/// Waits for changes, and collects them until things settle.
+fn next_batch(watcher: &mut Watcher) -> io::Result<Vec<Event>> {
+    let mut events = watcher.wait()?;
+    loop {
+        thread::sleep(SETTLE_PERIOD);
+        let more = watcher.poll()?;
+        if more.is_empty() {
+            return Ok(events);
+        }
+        events.extend(more);
+    }
+}
+
+/// Reduces events to the paths to read again, leaving out those
+/// inside another such path.
+fn changed_paths(events: &[Event], roots: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match events.contains(&Event::Overflow) {
+        true => roots.to_vec(),
+        false => events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Changed(path) => Some(path.clone()),
+                Event::Overflow => None,
+            })
+            .collect(),
+    };
+    // NB: sorted, so a directory comes right before the paths inside
+    paths.sort();
+    paths.dedup();
+    let mut result: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if !result.last().is_some_and(|last| path.starts_with(last)) {
+            result.push(path);
+        }
+    }
+    result
+}
+
+///////////
+// Watch //
+///////////
+
+#[derive(Debug, Default)]
+/// What changed in one batch of changes.
+pub struct Update {
+    /// Groups of identical files with a file that was added or changed,
+    /// or every group for the first update.
+    pub duplicates: Vec<DuplicateGroup>,
+    /// The fingerprint of every file in the groups.
+    pub files: HashMap<PathBuf, Fingerprint>,
+    /// Number of paths that changed; 0 for the first update.
+    pub changed: usize,
+    /// How the cache was used to bring it up to date.
+    pub cache: CacheStats,
+    /// Files that could not be read, and were left out.
+    pub skipped: Vec<Skipped>,
+    /// Other problems that did not stop watching.
+    pub errors: Vec<crate::Error>,
+}
+
+impl Scan {
+    /// Watches the directories for changes, keeping the index up to date,
+    /// and reports the duplicates found as they appear.
+    ///
+    /// The first update reports every duplicate found by the scan.
+    /// Only returns if watching fails, or the callback fails.
+    pub fn watch(
+        mut self,
+        mut on_update: impl FnMut(Update) -> crate::Result,
+        progress: &mut dyn ProgressSink,
+    ) -> crate::Result {
+        let mut watcher = Watcher::new().context("failed to watch")?;
+        for dir in &self.dirs {
+            watcher.add(dir).map_err(|error| match error.kind() {
+                io::ErrorKind::StorageFull => crate::Error::new(error).context(
+                    "too many directories to watch; raise the limit with \
+                     sysctl fs.inotify.max_user_watches",
+                ),
+                _ => crate::Error::new(error)
+                    .context(format!("failed to watch '{}'", dir.display())),
+            })?;
+        }
+
+        let duplicates = self.duplicate_groups(&DirectoryFindings::default());
+        on_update(Update {
+            files: self.fingerprints(&duplicates),
+            duplicates,
+            changed: 0,
+            cache: self.cache,
+            skipped: std::mem::take(&mut self.skipped),
+            errors: std::mem::take(&mut self.errors),
+        })?;
+        loop {
+            let events = next_batch(&mut watcher)?;
+            let paths = changed_paths(&events, &self.directories);
+            let update = self.apply(&paths, &mut watcher, progress)?;
+            on_update(update)?;
+        }
+    }
+
+    /// Returns the fingerprint of every file in the groups that was found.
+    fn fingerprints(
+        &self,
+        groups: &[DuplicateGroup],
+    ) -> HashMap<PathBuf, Fingerprint> {
+        let paths = groups.iter().flat_map(|group| &group.paths);
+        paths
+            .filter_map(|path| Some((path.clone(), *self.files.get(path)?)))
+            .collect()
+    }
+
+    /// Reads the changed paths again, and brings the index up to date.
+    fn apply(
+        &mut self,
+        paths: &[PathBuf],
+        watcher: &mut Watcher,
+        progress: &mut dyn ProgressSink,
+    ) -> crate::Result<Update> {
+        // Forget what was there, but remember the hashes by file identity
+        let mut moved: HashMap<FileId, Record> = HashMap::new();
+        let Scan { index, files, hard_links, .. } = self;
+        files.retain(|file, _| {
+            let is_changed = paths.iter().any(|path| lies_in(file, path));
+            if is_changed &&
+                !is_member(file) &&
+                let Some(record) = index.get(file) &&
+                let Some(id) = record.fingerprint.id
+            {
+                moved.insert(id, *record);
+            }
+            !is_changed
+        });
+        // Other names of a file whose first name changed are read again,
+        // so one of them takes its place
+        let is_changed =
+            |file: &Path| paths.iter().any(|p| file.starts_with(p));
+        let mut to_read = paths.to_vec();
+        hard_links.retain(|file, names| {
+            if !is_changed(file) {
+                return true;
+            }
+            to_read.extend(names.drain(..).filter(|name| !is_changed(name)));
+            false
+        });
+        for names in hard_links.values_mut() {
+            names.retain(|name| !is_changed(name));
+        }
+
+        // Read what is there now
+        let mut ids: HashMap<FileId, PathBuf> = files
+            .iter()
+            .filter(|(path, _)| !is_member(path))
+            .filter_map(|(path, fingerprint)| Some((fingerprint.id?, path)))
+            .map(|(id, path)| (id, path.clone()))
+            .collect();
+        let mut added: HashSet<PathBuf> = HashSet::new();
+        let mut listings = Vec::new();
+        for root in &self.directories {
+            // Each path is read from the outermost root containing it
+            let inside: Vec<PathBuf> = to_read
+                .iter()
+                .filter(|path| {
+                    let mut roots = self.directories.iter();
+                    let outermost = roots.find(|dir| path.starts_with(dir));
+                    outermost == Some(root)
+                })
+                .cloned()
+                .collect();
+            if inside.is_empty() {
+                continue;
+            }
+            let known = |path: &Path| self.index.get_listing(path).cloned();
+            let Walk {
+                files: found,
+                hard_links: links,
+                dirs,
+                skipped,
+                listings: listed,
+                ..
+            } = walk_within(root, &inside, self.walk, &self.filter, &known)?;
+            self.skipped.extend(skipped);
+            listings.extend(listed);
+            for dir in dirs {
+                if let Err(error) = watcher.add(&dir) {
+                    self.skipped.push(Skipped::new(dir, error));
+                }
+            }
+            for (first_name, names) in links {
+                self.hard_links.entry(first_name).or_default().extend(names);
+            }
+            for entry in found {
+                let (path, fingerprint) = (entry.path, entry.fingerprint);
+                let id = fingerprint.id.filter(|_| !is_member(&path));
+                if let Some(id) = id {
+                    // A link to a file that did not change is not a name
+                    if entry.is_symlink && ids.contains_key(&id) {
+                        continue;
+                    }
+                    // Another name for a file that did not change
+                    if let Some(first_name) = ids.get(&id) {
+                        let names = self.hard_links.entry(first_name.clone());
+                        names.or_default().push(path);
+                        continue;
+                    }
+                    ids.insert(id, path.clone());
+                }
+                let reused = id
+                    .and_then(|id| moved.get(&id))
+                    .filter(|record| record.fingerprint == fingerprint);
+                if let Some(&record) = reused {
+                    self.index.apply(Change::Add(path.clone(), record))?;
+                }
+                self.files.insert(path.clone(), fingerprint);
+                added.insert(path);
+            }
+        }
+
+        self.sync(Some(&to_read), progress)?;
+        for (path, listing) in listings {
+            self.index.apply(Change::AddListing(path, listing))?;
+        }
+        let mut errors = Vec::new();
+        if let Err(error) = self.index.save() {
+            errors.push(error.context("failed to save index"));
+        }
+        let mut duplicates =
+            self.duplicate_groups(&DirectoryFindings::default());
+        duplicates
+            .retain(|group| group.paths.iter().any(|p| added.contains(p)));
+        Ok(Update {
+            files: self.fingerprints(&duplicates),
+            duplicates,
+            changed: paths.len(),
+            cache: self.cache,
+            skipped: std::mem::take(&mut self.skipped),
+            errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reports_duplicates_as_they_appear() {
+        use std::fs;
+
+        use crate::connection::ConnectionKind;
+        use crate::progress::NoProgress;
+        use crate::scan::ScanOptions;
+        use crate::scan::scan;
+
+        let dir = std::env::temp_dir()
+            .join(format!("duplicate-detector-{}-watch", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let (a, b, link) = (dir.join("a"), dir.join("b"), dir.join("sub/link"));
+        fs::write(&a, "same").unwrap();
+        // NB: Found after the file, as subdirectories are read later
+        fs::hard_link(&a, &link).unwrap();
+
+        let options =
+            ScanOptions::for_tests(vec![dir.clone()], ConnectionKind::Memory);
+        let mut groups = Vec::new();
+        let on_update = |update: Update| {
+            groups.push(update.duplicates.into_iter().map(|g| g.paths));
+            match groups.len() {
+                1 => fs::write(&b, "same")?,
+                2 => fs::remove_file(&a)?,
+                _ => anyhow::bail!("done"),
+            }
+            Ok(())
+        };
+        let scan = scan(options, &mut NoProgress).unwrap();
+        let stopped = scan.watch(on_update, &mut NoProgress);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(stopped.unwrap_err().to_string(), "done");
+        let groups: Vec<Vec<Vec<PathBuf>>> =
+            groups.into_iter().map(|groups| groups.collect()).collect();
+        assert_eq!(groups, [
+            vec![],
+            vec![vec![a.clone(), b.clone()]],
+            // The other name of the file takes the place of the first
+            vec![vec![b.clone(), link.clone()]],
+        ]);
+    }
+
+    #[test]
+    fn changes_inside_changed_directories_are_merged() {
+        let roots = [PathBuf::from("/r")];
+        let changed = |path: &str| Event::Changed(PathBuf::from(path));
+        let events = [changed("/r/b/x"), changed("/r/a"), changed("/r/b")];
+        assert_eq!(changed_paths(&events, &roots), vec![
+            PathBuf::from("/r/a"),
+            PathBuf::from("/r/b"),
+        ]);
+        let events = [changed("/r/a"), Event::Overflow];
+        assert_eq!(changed_paths(&events, &roots), roots.to_vec());
+
+        assert!(lies_in(Path::new("/r/b/c.zip!/x"), Path::new("/r/b/c.zip")));
+        assert!(!lies_in(Path::new("/r/bc"), Path::new("/r/b")));
+    }
+}